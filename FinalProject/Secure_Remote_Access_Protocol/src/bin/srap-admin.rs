@@ -0,0 +1,242 @@
+//! Offline administration of Google's data directory: user records, the server's keys and
+//! its certificates. Run it while Google is stopped, Google rewrites the records after
+//! every request and would undo the changes.
+
+use srap::crypto::dh_group::{DhGroup, Group};
+use srap::crypto::participant::{DatabaseContent, CA};
+use srap::crypto::secret::Secret;
+use srap::server::ake_keys::DEFAULT_OVERLAP;
+use srap::server::rate_limit::{AttemptCounter, RateLimitPolicy};
+use srap::server::store::{self, Store, DATA_DIR};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "Usage: srap-admin [--dir DIR] COMMAND
+
+Commands:
+  users                    List the registered users
+  show USER                Show the metadata of USER's record
+  delete USER              Delete USER's record
+  lock USER [SECONDS]      Lock USER's account, for the default lockout if no duration is given
+  unlock USER              Unlock USER's account and clear its failed attempts
+  keys                     List the server's AKE keys
+  rotate-key [SECONDS]     Make a new AKE key current, accepting the old one for SECONDS
+                           (30 days if not given) while users log in and re-wrap
+  certs                    Show the server certificate and the revoked ones
+  issue-cert               Issue a new server key pair and certificate
+  revoke-cert FINGERPRINT  Revoke the server certificate with this fingerprint
+  export FILE              Write all records to FILE
+  import FILE              Add the records in FILE, keeping existing users";
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut dir = DATA_DIR.to_string();
+    if args.first().is_some_and(|arg| arg == "--dir") {
+        if args.len() < 2 {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+        dir = args.remove(1);
+        args.remove(0);
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let store = Store::new(dir);
+
+    let result = match args.as_slice() {
+        ["users"] => users(&store),
+        ["show", user] => show(&store, user),
+        ["delete", user] => update(&store, user, |_| None),
+        ["lock", user] => lock(&store, user, RateLimitPolicy::default().lockout),
+        ["lock", user, seconds] => match seconds.parse() {
+            Ok(seconds) => lock(&store, user, Duration::from_secs(seconds)),
+            Err(_) => Err(format!("Invalid duration: {seconds}")),
+        },
+        ["unlock", user] => update(&store, user, |mut record| {
+            record.attempts = AttemptCounter::default();
+            Some(record)
+        }),
+        ["keys"] => keys(&store),
+        ["rotate-key"] => rotate_key(&store, DEFAULT_OVERLAP),
+        ["rotate-key", seconds] => match seconds.parse() {
+            Ok(seconds) => rotate_key(&store, Duration::from_secs(seconds)),
+            Err(_) => Err(format!("Invalid duration: {seconds}")),
+        },
+        ["certs"] => certs(&store),
+        ["issue-cert"] => issue_cert(&store),
+        ["revoke-cert", fingerprint] => revoke_cert(&store, fingerprint),
+        ["export", file] => export(&store, file),
+        ["import", file] => import(&store, file),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn load(store: &Store) -> Result<HashMap<Vec<u8>, DatabaseContent>, String> {
+    store.load_users().map_err(|e| io_error(store, e))
+}
+
+fn save(store: &Store, database: &HashMap<Vec<u8>, DatabaseContent>) -> Result<(), String> {
+    store.save_users(database).map_err(|e| io_error(store, e))
+}
+
+fn io_error(store: &Store, e: io::Error) -> String {
+    format!("{}: {e}", store.dir().display())
+}
+
+fn users(store: &Store) -> Result<(), String> {
+    let database = load(store)?;
+    let current = store.ake_keys().map_err(|e| io_error(store, e))?.current().id;
+    let mut names: Vec<&Vec<u8>> = database.keys().collect();
+    names.sort();
+    let now = SystemTime::now();
+    for name in names {
+        let record = &database[name];
+        let mut flags = Vec::new();
+        if record.totp_secret.is_some() {
+            flags.push("totp");
+        }
+        if record.attempts.is_locked(now) {
+            flags.push("locked");
+        }
+        if record.key_id != current {
+            flags.push("old-key");
+        }
+        println!("{}\t{}", String::from_utf8_lossy(name), flags.join(","));
+    }
+    Ok(())
+}
+
+fn show(store: &Store, user: &str) -> Result<(), String> {
+    let database = load(store)?;
+    let record = database.get(user.as_bytes()).ok_or_else(|| unknown_user(user))?;
+    let attempts = &record.attempts;
+    println!("user:            {user}");
+    println!("client key:      {}", store::to_hex(&Group::encode(&record.lpk_c)));
+    println!("AKE key:         {}", record.key_id);
+    println!("key stretching:  {:?}", record.ksf);
+    println!("second factor:   {}", if record.totp_secret.is_some() { "TOTP" } else { "none" });
+    println!("failed attempts: {}", attempts.failures);
+    println!("last failure:    {}", time(attempts.last_failure));
+    let locked_until = attempts.locked_until.filter(|_| attempts.is_locked(SystemTime::now()));
+    println!("locked until:    {}", time(locked_until));
+    Ok(())
+}
+
+/// Replaces USER's record with `change(record)`, or deletes it on `None`.
+fn update(store: &Store, user: &str, change: impl FnOnce(DatabaseContent) -> Option<DatabaseContent>) -> Result<(), String> {
+    let mut database = load(store)?;
+    let record = database.remove(user.as_bytes()).ok_or_else(|| unknown_user(user))?;
+    if let Some(record) = change(record) {
+        database.insert(user.as_bytes().to_vec(), record);
+    }
+    save(store, &database)
+}
+
+fn lock(store: &Store, user: &str, duration: Duration) -> Result<(), String> {
+    update(store, user, |mut record| {
+        record.attempts.locked_until = Some(SystemTime::now() + duration);
+        Some(record)
+    })
+}
+
+fn keys(store: &Store) -> Result<(), String> {
+    let keys = store.ake_keys().map_err(|e| io_error(store, e))?;
+    let database = load(store)?;
+    for key in keys.keys() {
+        let users = database.values().filter(|record| record.key_id == key.id).count();
+        match key.retires_at {
+            Some(retires_at) => println!("{}\tretires {}\t{users} users", key.id, time(Some(retires_at))),
+            None => println!("{}\tcurrent\t{users} users", key.id),
+        }
+    }
+    Ok(())
+}
+
+/// Users whose envelope is for the old key get it re-wrapped on their next login.
+fn rotate_key(store: &Store, overlap: Duration) -> Result<(), String> {
+    let mut keys = store.ake_keys().map_err(|e| io_error(store, e))?;
+    let id = keys.rotate(overlap, SystemTime::now()).id;
+    store.save_ake_keys(&keys).map_err(|e| io_error(store, e))?;
+    println!("AKE key {id} is current");
+    Ok(())
+}
+
+fn certs(store: &Store) -> Result<(), String> {
+    let ca = store.ca().map_err(|e| io_error(store, e))?;
+    let identity = store.identity(&ca).map_err(|e| io_error(store, e))?;
+    let verifying_key = identity.key_pair().verifying_key().encode();
+    println!("current: {}", store::to_hex(&CA::fingerprint(verifying_key.as_slice())));
+    for fingerprint in store.revoked().map_err(|e| io_error(store, e))? {
+        println!("revoked: {}", store::to_hex(&fingerprint));
+    }
+    Ok(())
+}
+
+/// The old certificate stays valid until it is revoked.
+fn issue_cert(store: &Store) -> Result<(), String> {
+    let ca = store.ca().map_err(|e| io_error(store, e))?;
+    let identity = store.issue_identity(&ca).map_err(|e| io_error(store, e))?;
+    let verifying_key = identity.key_pair().verifying_key().encode();
+    println!("Issued {}", store::to_hex(&CA::fingerprint(verifying_key.as_slice())));
+    Ok(())
+}
+
+fn revoke_cert(store: &Store, fingerprint: &str) -> Result<(), String> {
+    let fingerprint = store::parse_fingerprint(fingerprint).ok_or("Invalid fingerprint, expected 64 hex digits")?;
+    store.revoke(fingerprint).map_err(|e| io_error(store, e))
+}
+
+/// The records only work with the OPRF seed and CA of this directory.
+fn export(store: &Store, file: &str) -> Result<(), String> {
+    let database = load(store)?;
+    let encoded = Secret::new(store::encode_users(&database));
+    store::write_private(file.as_ref(), &encoded).map_err(|e| format!("{file}: {e}"))?;
+    println!("Exported {} users", database.len());
+    Ok(())
+}
+
+fn import(store: &Store, file: &str) -> Result<(), String> {
+    let bytes = Secret::new(std::fs::read(file).map_err(|e| format!("{file}: {e}"))?);
+    let imported = store::decode_users(&bytes).map_err(|e| format!("{file}: {e}"))?;
+    let mut database = load(store)?;
+    let (mut added, mut skipped) = (0, 0);
+    for (name, record) in imported {
+        match database.entry(name) {
+            Entry::Occupied(entry) => {
+                eprintln!("Skipping {}, already registered", String::from_utf8_lossy(entry.key()));
+                skipped += 1;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(record);
+                added += 1;
+            }
+        }
+    }
+    save(store, &database)?;
+    println!("Imported {added} users, skipped {skipped}");
+    Ok(())
+}
+
+fn unknown_user(user: &str) -> String {
+    format!("Unknown user: {user}")
+}
+
+/// Seconds since the Unix epoch, or `-`.
+fn time(time: Option<SystemTime>) -> String {
+    match time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        Some(since_epoch) => since_epoch.as_secs().to_string(),
+        None => "-".to_string(),
+    }
+}
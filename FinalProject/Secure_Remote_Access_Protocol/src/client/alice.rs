@@ -1,56 +1,80 @@
+use crate::client::password_policy::{self, PasswordPolicy};
 use crate::crypto;
-use crate::crypto::hash2curve::hash2curve_demo;
-use crate::crypto::hmac::{compute_hmac, verify_hmac};
-use crate::crypto::key_schedule::{key_schedule_1, key_schedule_2, key_schedule_3};
-use crate::crypto::participant::{Message, User, CA};
+use crate::crypto::secret::{Secret, SecretKey};
+use crate::crypto::participant::{RequestError, User, CA, STATUS_OK, STATUS_REWRAP, STATUS_TICKET, STATUS_TOTP_REQUIRED, STATUS_USER_EXISTS};
+use crate::crypto::totp;
+use crate::handshake::client::HandshakeInfo;
+use crate::handshake::{AlertDescription, ClientHandshake, Event, HandshakeError};
 use aes_gcm::aead::OsRng;
-use elliptic_curve::group::GroupEncoding;
-use elliptic_curve::hash2curve::ExpandMsgXmd;
-use elliptic_curve::{Field, PrimeField};
-use image::EncodableLayout;
-use k256::{ProjectivePoint, Scalar};
-use kem::Decapsulate;
-use ml_dsa::signature::Verifier;
-use ml_dsa::{EncodedVerifyingKey, VerifyingKey};
-use ml_dsa::{MlDsa65, Signature};
-use ml_kem::{Ciphertext, EncodedSizeUser, KemCore, MlKem768};
 use rand_core::RngCore;
-use sha2::{Digest, Sha256};
-use sha3::Sha3_256;
-use std::net::TcpStream;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::{io, panic};
-use hmac::digest::Output;
-use inquire::Select;
-
-static RECEIVED_RESET: AtomicBool = AtomicBool::new(false);
-
-pub fn alice(ca: &mut CA, group_element: &mut ProjectivePoint) {
-    let mut stream = TcpStream::connect("127.0.0.1:9000").unwrap();
+use crate::transport::{Transport, TransportError};
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::{task, time};
+use inquire::{Confirm, Password, PasswordDisplayMode, Select};
+
+/// Address Google listens on.
+pub(crate) const SERVER_ADDR: &str = "127.0.0.1:9000";
+
+/// Pause after which an idle session sends a heartbeat, well below Google's idle timeout.
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+pub async fn alice(ca: &mut CA) {
+    let mut stream = TcpStream::connect(SERVER_ADDR).await.unwrap();
+
+    // The logged-in session outlives its connection, so it can be resumed on the next one
+    let mut session = None;
     loop {
-        panic::set_hook(Box::new(|_| {
-        }));
-        match panic::catch_unwind(panic::AssertUnwindSafe(|| {
-            match alice_inner(ca, group_element, &mut stream) {
-                _ => panic!("Alice: Error in alice_inner"),
+        let result = match &mut session {
+            Some(active) => match chat(active, &mut stream).await {
+                Ok(()) => session.take().unwrap().close(&mut stream).await,
+                Err(e) => Err(e),
+            },
+            None => alice_inner(ca, &mut stream, &mut session).await,
+        };
+
+        // Errors were alerted to Google where they were found
+        match result {
+            Ok(()) => continue,
+            Err(RequestError::Failed) => session = None,
+            Err(RequestError::Transport(TransportError::Alert(description))) => {
+                eprintln!("Alice: Google ended the session: {description}");
+                session = None;
             }
-        })) {
-            _ => {
-                if !RECEIVED_RESET.load(Ordering::Relaxed) {
-                    println!("Alice: An error occurred, resetting connection");
-                    User::send_bytes(&mut stream, &Message::Reset {});
+            Err(RequestError::Transport(_)) => {
+                // Google drops connections that stay silent too long, so connect again
+                eprintln!("Alice: Connection to Google lost, reconnecting");
+                stream = match TcpStream::connect(SERVER_ADDR).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Alice: Cannot reconnect to Google: {e}");
+                        return;
+                    }
                 };
-                RECEIVED_RESET.store(false, Ordering::Relaxed);
+
+                // Resume the session with its last ticket, or log in again from the menu
+                if let Some(active) = &mut session {
+                    println!("Alice: Resuming the session");
+                    if active.resume(&mut stream).await.is_err() {
+                        eprintln!("Alice: Cannot resume the session, log in again");
+                        session = None;
+                    }
+                }
             }
         }
     }
 }
 
-pub fn alice_inner(ca: &mut CA, group_element: &mut ProjectivePoint, mut stream: &mut TcpStream) {
-    let mut aead_nonce: [u8; 12] = [0u8; 12];
+/// Serves one choice of the menu. A successful login leaves its session in `session`.
+pub(crate) async fn alice_inner(
+    ca: &mut CA,
+    mut stream: &mut impl Transport,
+    session: &mut Option<RatchetSession>,
+) -> Result<(), RequestError> {
     let ad = b"Alice,Google,";
-    let g = group_element.clone();
-    let options = vec!["Login", "Register"];
+    let options = vec!["Login", "Register", "Change password", "Delete account"];
+    let policy = PasswordPolicy::default();
 
     loop {
         println!("\n------------------------------------------------------------------\n");
@@ -61,25 +85,81 @@ pub fn alice_inner(ca: &mut CA, group_element: &mut ProjectivePoint, mut stream:
         io::stdin()
             .read_line(&mut username)
             .expect("Error reading username");
-        println!("Enter password: ");
-        let mut pw = String::new();
-        io::stdin()
-            .read_line(&mut pw)
-            .expect("Error reading password");
+        let username = username.trim();
+
+        // A new password is typed twice, existing ones once
+        let pw = match read_password("Enter password:", matches!(selection, Ok("Register"))) {
+            Some(pw) => pw,
+            None => {
+                eprintln!("Alice: Error reading password");
+                return Ok(());
+            }
+        };
 
         match selection {
             Ok(choice) => {
                 match choice {
                     "Login" => {
-                        if login(ca, &mut stream, &mut aead_nonce, &ad, g, &username, &pw) {
-                            eprintln!("Alice: Login error");
-                            return;
+                        match open_session(ca, &mut stream, ad, username, &pw, None).await {
+                            Ok(active) => {
+                                *session = Some(active);
+                                return Ok(());
+                            }
+                            Err(e) => {
+                                eprintln!("Alice: Login error");
+                                return Err(e);
+                            }
                         }
                     },
                     "Register" => {
-                        if register(ca, &mut stream, &mut aead_nonce, &ad, &username, &pw) {
-                            eprintln!("Alice: Register error");
-                            return;
+                        if let Err(e) = policy.check(&pw) {
+                            eprintln!("Alice: Register error: {e}");
+                            continue;
+                        }
+
+                        let enroll_totp = Confirm::new("Enable two-factor authentication (TOTP)?")
+                            .with_default(false)
+                            .prompt()
+                            .unwrap_or(false);
+
+                        // A rejected registration leaves the connection usable
+                        let result = if enroll_totp {
+                            register_with_totp(ca, &mut stream, ad, username, &pw).await.map(|secret| {
+                                println!("Alice: TOTP secret (keep it safe): {}", to_hex(&secret));
+                            })
+                        } else {
+                            register(ca, &mut stream, ad, username, &pw).await
+                        };
+                        match result {
+                            Ok(()) => (),
+                            Err(RequestError::Failed) => eprintln!("Alice: Register error"),
+                            Err(e) => {
+                                eprintln!("Alice: Register error");
+                                return Err(e);
+                            }
+                        }
+                    },
+                    "Change password" => {
+                        let new_pw = match read_password("Enter new password:", true) {
+                            Some(pw) => pw,
+                            None => {
+                                eprintln!("Alice: Error reading new password");
+                                return Ok(());
+                            }
+                        };
+                        if let Err(e) = policy.check(&new_pw) {
+                            eprintln!("Alice: ChangePassword error: {e}");
+                            continue;
+                        }
+                        if let Err(e) = change_password(ca, &mut stream, ad, username, &pw, &new_pw, None).await {
+                            eprintln!("Alice: ChangePassword error");
+                            return Err(e);
+                        }
+                    },
+                    "Delete account" => {
+                        if let Err(e) = delete_account(ca, &mut stream, ad, username, &pw, None).await {
+                            eprintln!("Alice: DeleteAccount error");
+                            return Err(e);
                         }
                     },
                     _ => unreachable!(),
@@ -87,217 +167,250 @@ pub fn alice_inner(ca: &mut CA, group_element: &mut ProjectivePoint, mut stream:
             },
             Err(_) => {
                 eprintln!("Alice: Error reading selection");
-                return;
+                return Ok(());
             }
         }
     }
 }
 
-pub fn login(
-    ca: &mut CA,
-    mut stream: &mut TcpStream,
-    aead_nonce: &mut [u8; 12],
-    ad: &&[u8; 13],
-    g: ProjectivePoint,
-    username: &str,
-    pw: &str,
-) -> bool {
-    let username = username.as_bytes();
-    let pw = pw.as_bytes();
+/// Lowercase hex encoding of `bytes`.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
 
-    // ----------- OPRF stage -----------
-    println!("Alice: OPRF stage");
+/// Reads a password without echoing it, asking for confirmation if `confirm` is set,
+/// and normalizes it. The raw input is wiped once it has been normalized.
+fn read_password(message: &str, confirm: bool) -> Option<Secret<String>> {
+    let prompt = Password::new(message).with_display_mode(PasswordDisplayMode::Hidden);
+    let prompt = if confirm {
+        prompt.with_custom_confirmation_message("Confirm password:")
+    } else {
+        prompt.without_confirmation()
+    };
+    let pw = Secret::new(prompt.prompt().ok()?);
+    Some(Secret::new(password_policy::normalize(&pw)))
+}
+
+/// Sends what `handshake` has queued and waits for its next event. Errors are reported
+/// as `Alice: <context> error` and alerted to Google.
+async fn next_event(handshake: &mut ClientHandshake, stream: &mut impl Transport, context: &str) -> Result<Event, RequestError> {
+    match User::drive(stream, handshake).await? {
+        Ok(event) => Ok(event),
+        Err(e) => {
+            eprintln!("Alice: {context} error: {e}");
+            Err(RequestError::Failed)
+        }
+    }
+}
+
+/// Reports `e` as `Alice: <context> error` and alerts Google about it.
+async fn fail(handshake: &mut ClientHandshake, stream: &mut impl Transport, context: &str, e: HandshakeError) -> RequestError {
+    eprintln!("Alice: {context} error: {e}");
+    match User::fail(stream, handshake, e).await {
+        Ok(_) => RequestError::Failed,
+        Err(e) => e.into(),
+    }
+}
+
+/// Reports an unexpected event and alerts Google about it.
+async fn unexpected(handshake: &mut ClientHandshake, stream: &mut impl Transport) -> RequestError {
+    eprintln!("Alice: Unexpected message");
+    match User::send_alert(stream, handshake, AlertDescription::UnexpectedMessage).await {
+        Ok(()) => RequestError::Failed,
+        Err(e) => e.into(),
+    }
+}
+
+/// Runs pq_tls and returns the established handshake, whose k3 keys protect every
+/// later message of the request.
+pub(crate) async fn pq_tls(
+    stream: &mut impl Transport,
+    ca: &CA,
+    ad: &[u8; 13]
+) -> Result<ClientHandshake, RequestError> {
+    let mut handshake = ClientHandshake::new(ca, ad);
+    match next_event(&mut handshake, stream, "Handshake").await? {
+        Event::Established => Ok(handshake),
+        _ => Err(unexpected(&mut handshake, stream).await),
+    }
+}
 
+/// Runs pq_tls, the OPRF stage and the 3DH AKE with key confirmation for `action`
+/// (e.g. `Login`, `ChangePassword`, `DeleteAccount`).
+pub(crate) async fn authenticate(
+    ca: &CA,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
+    username: &[u8],
+    pw: &[u8],
+    action: &[u8],
+) -> Result<ClientHandshake, RequestError> {
     // Establish TLS connection
     println!("Alice: Establishing TLS connection");
-    let (_k1_c, _k1_s, _k2_c, _k2_s, k3_c, k3_s) = pq_tls(&mut stream, ca, ad);
+    let mut handshake = pq_tls(stream, ca, ad).await?;
     println!("Alice: TLS connection established");
 
-    // Login request
-    println!("Alice: Sending login request");
-    let a = Scalar::random(&mut OsRng);
-    let h_pw: ProjectivePoint =
-        hash2curve_demo::<k256::Secp256k1, ExpandMsgXmd<Sha3_256>>(pw)
-            .expect("hash2curve_demo (k256 + SHA3-256) failed");
-
-    let mut msg = Vec::new();
-    msg.extend_from_slice(b"Login;");
-    msg.extend_from_slice(username);
-    msg.extend_from_slice(b";");
-    msg.extend_from_slice((h_pw * a).to_bytes().as_bytes());
-    OsRng.fill_bytes(aead_nonce);
-    let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k3_c, &aead_nonce, msg.as_bytes(), &ad.to_vec()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Alice: Encrypt error: {e}");
-            return true;
-        }
-    };
-    let msg = Message::AeadCiphertext {
-        nonce: *aead_nonce,
-        aead_payload: cypher_text,
-    };
-    User::send_bytes(&mut stream, &msg);
-
-    // Receive AEAD(k3_s, {{h_pw^as, enc_client_keys}}) message from Google
-    println!("Alice: Waiting for login response");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce, aead_payload) = match msg {
-        Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-        _ => {
-            match msg {
-                Message::Reset {} => (),
-                _ => {
-                    eprintln!("Alice: Unexpected message");
-                    return true;
-                }
-            }
-            RECEIVED_RESET.store(true, Ordering::Relaxed);
-            panic!("Alice: Unexpected message")
-        },
-    };
-    let decrypted_msg: Vec<u8> = match crypto::aead::decrypt(&k3_s, &nonce, &aead_payload, &ad.as_ref()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Alice: Decrypt error: {e}");
-            return true;
+    // Login request, then the OPRF stage, 3DH and key confirmation
+    println!("Alice: Sending {} request", String::from_utf8_lossy(action));
+    if let Err(e) = handshake.start_login(action, username, pw) {
+        return Err(fail(&mut handshake, stream, "Login", e).await);
+    }
+    match next_event(&mut handshake, stream, "Login").await? {
+        Event::LoginSucceeded => {
+            println!("Alice: Valid MACs received.\n\n");
+            Ok(handshake)
         }
-    };
-    if decrypted_msg.len() < 114 {
-        eprintln!("Alice: Decrypt error: received malformed ratchet payload (len={})", decrypted_msg.len());
-        return true;
+        _ => Err(unexpected(&mut handshake, stream).await),
     }
-    let (h_pw_as_bytes, rest_bytes) = decrypted_msg.split_at(33);
-    let h_pw_as = ProjectivePoint::from_bytes(h_pw_as_bytes.into()).unwrap();
-    let (enc_client_keys, enc_client_keys_nonce) = rest_bytes.split_at(114);
-
-    // Decrypt enc_client_keys and verify correctness
-    println!("Alice: Decrypting client keys");
-    let h_pw_s = h_pw_as * a.invert().unwrap();
-    let rw = Sha3_256::digest([pw.as_bytes(), h_pw_s.to_bytes().as_bytes()].concat());
-    let (rw_key, _) = crypto::key_schedule::extract(None, rw.as_bytes());
-    let client_key_info = match crypto::aead::decrypt(rw_key.as_ref(), &enc_client_keys_nonce.try_into().unwrap(), enc_client_keys.as_bytes(), ad.as_ref()) {
-        Ok(c) => c,
-        Err(_) => {
-            eprintln!("Alice: Login error: Incorrect password or corrupted data");
-            return true;
+}
+
+pub async fn login(
+    ca: &CA,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
+    username: &str,
+    pw: &str,
+    totp_code: Option<&str>,
+) -> Result<(), RequestError> {
+    #[cfg_attr(test, allow(unused_mut))]
+    let mut session = open_session(ca, stream, ad, username, pw, totp_code).await?;
+    #[cfg(not(test))]
+    chat(&mut session, stream).await?;
+    session.close(stream).await
+}
+
+/// Sends the user's messages over `session` until they enter /quit.
+async fn chat(session: &mut RatchetSession, stream: &mut impl Transport) -> Result<(), RequestError> {
+    loop {
+        // Keep the session alive while waiting for the user
+        println!("Enter a message to send to Google (or /quit to log out): ");
+        let mut line = task::spawn_blocking(|| {
+            let mut message_from_user = String::new();
+            io::stdin().read_line(&mut message_from_user).map(|_| message_from_user)
+        });
+        let message_from_user = loop {
+            tokio::select! {
+                line = &mut line => break line,
+                _ = time::sleep(HEARTBEAT_INTERVAL) => session.heartbeat(stream).await?,
+            }
+        };
+        let message_from_user = message_from_user
+            .expect("Error reading message_from_user")
+            .expect("Error reading message_from_user");
+        let message_from_user = message_from_user.trim();
+        if message_from_user == "/quit" {
+            return Ok(());
         }
-    };
 
-    let alice_lsk_c_bytes: [u8; 32] = client_key_info[33..65].try_into().unwrap();
-    let lsk_c: Scalar = Scalar::from_repr(alice_lsk_c_bytes.into()).expect("invalid Scalar encoding for alice_lsk_c");
-    let _lpk_c: ProjectivePoint = ProjectivePoint::from_bytes(client_key_info[..33].try_into().unwrap()).unwrap();
-    let lpk_s: ProjectivePoint = ProjectivePoint::from_bytes(client_key_info[65..98].try_into().unwrap()).unwrap();
+        let answer = session.send(stream, message_from_user).await?;
+        println!("Alice: Received message from Google: {answer}");
+    }
+}
 
-    // ----------- AKE stage: 3DH -----------
-    println!("Alice: AKE stage");
+/// A ticket from Google and the resumption secret of the session it was issued for.
+struct Resumption {
+    ticket: Vec<u8>,
+    secret: SecretKey,
+}
 
-    let x = Scalar::random(&mut OsRng);
+/// A logged-in session, carrying messages over the double ratchet. Once Google ends it
+/// because it expired, it is resumed with the last ticket.
+pub(crate) struct RatchetSession {
+    handshake: ClientHandshake,
+    resumption: Option<Resumption>,
+    ca: CA,
+    ad: [u8; 13],
+    username: Vec<u8>,
+}
 
-    // Send ephemeral_pk to Google
-    println!("Alice: Sending ephemeral_pk");
-    let mut msg = Vec::new();
-    msg.extend_from_slice((g * x).to_bytes().as_bytes());
-    OsRng.fill_bytes(aead_nonce);
-    let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k3_c, &aead_nonce, msg.as_bytes(), &ad.to_vec()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Alice: Encrypt error: {e}");
-            return true;
-        }
-    };
-    let msg = Message::AeadCiphertext {
-        nonce: *aead_nonce,
-        aead_payload: cypher_text
-    };
-    User::send_bytes(&mut stream, &msg);
-
-    // Receive ephemeral_pk from Google
-    println!("Alice: Waiting for ephemeral_pk");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce, aead_payload) = match msg {
-        Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-        _ => panic!("Alice: Unexpected message"),
-    };
-    let decrypted_msg: Vec<u8> = match crypto::aead::decrypt(&k3_s, &nonce, &aead_payload, &ad.as_ref()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Decrypt error: {e}");
-            return true;
+impl RatchetSession {
+    /// Sends `message` over the double ratchet and returns Google's answer.
+    pub(crate) async fn send(&mut self, stream: &mut impl Transport, message: &str) -> Result<String, RequestError> {
+        match self.request(stream, |handshake| handshake.send_app_data(message)).await? {
+            Event::AppData(answer) => Ok(answer),
+            _ => Err(unexpected(&mut self.handshake, stream).await),
         }
-    };
-    let large_y: ProjectivePoint = ProjectivePoint::from_bytes(decrypted_msg.as_slice().try_into().unwrap()).unwrap();
-
-    // 3DH-KClient(𝑎, 𝑥, 𝐵, 𝑌)
-    println!("Alice: Calculating SK");
-    let mut key_input = Vec::new();
-    key_input.extend_from_slice((lpk_s * x).to_bytes().as_bytes());
-    key_input.extend_from_slice((large_y * x).to_bytes().as_bytes());
-    key_input.extend_from_slice((large_y * lsk_c).to_bytes().as_bytes());
-    let (sk, _) = crypto::key_schedule::extract(None, key_input.as_bytes());
-
-    // ----------- Key Confirmation -----------
-    println!("Alice: Key Confirmation stage");
-
-    // Calculate mac_c
-    println!("Alice: Calculating mac_c");
-    let (_, hk) = crypto::key_schedule::extract(None, sk.as_bytes());
-    let combined_key = crypto::key_schedule::expand::<64>(&hk, b"Key Confirmation").unwrap();
-    let (kc, ks) = combined_key.split_at(32);
-
-    let mac_c = compute_hmac(kc.as_bytes(), b"Client KC");
-    let expected_mac_s = compute_hmac(ks.as_bytes(), b"Server KC");
-
-    // Send mac_c to Google
-    println!("Alice: Sending mac_c");
-    OsRng.fill_bytes(aead_nonce);
-    let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k3_c, &aead_nonce, mac_c.as_bytes(), &ad.to_vec()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Alice: Encrypt error: {e}");
-            return true;
+    }
+
+    /// Sends a heartbeat and waits for Google's answer, so Google keeps the connection.
+    pub(crate) async fn heartbeat(&mut self, stream: &mut impl Transport) -> Result<(), RequestError> {
+        match self.request(stream, ClientHandshake::send_heartbeat).await? {
+            Event::Heartbeat => Ok(()),
+            _ => Err(unexpected(&mut self.handshake, stream).await),
         }
-    };
-    let msg = Message::AeadCiphertext {
-        nonce: *aead_nonce,
-        aead_payload: cypher_text
-    };
-    User::send_bytes(&mut stream, &msg);
-
-    // Receive mac_s from Google
-    println!("Alice: Waiting for mac_s");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce, aead_payload) = match msg {
-        Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-        _ => {
-            match msg {
-                Message::Reset {} => (),
-                _ => {
-                    eprintln!("Alice: Unexpected message");
-                    return true;
+    }
+
+    /// Queues a message with `queue` and waits for Google's answer. If Google closed the
+    /// session instead, the session is resumed once and the message sent again.
+    async fn request(
+        &mut self,
+        stream: &mut impl Transport,
+        queue: impl Fn(&mut ClientHandshake) -> Result<(), HandshakeError>,
+    ) -> Result<Event, RequestError> {
+        let mut resumed = false;
+        loop {
+            if let Err(e) = queue(&mut self.handshake) {
+                return Err(fail(&mut self.handshake, stream, "Encrypt", e).await);
+            }
+            match User::drive(stream, &mut self.handshake).await? {
+                Ok(event) => return Ok(event),
+                Err(HandshakeError::Alert(AlertDescription::CloseNotify)) if !resumed => {
+                    println!("Alice: Session ended by Google, resuming");
+                    self.resume(stream).await?;
+                    resumed = true;
+                }
+                Err(e) => {
+                    eprintln!("Alice: Ratchet error: {e}");
+                    return Err(RequestError::Failed);
                 }
             }
-            RECEIVED_RESET.store(true, Ordering::Relaxed);
-            panic!("Alice: Unexpected message")
-        },
-    };
-    let mac_s: Vec<u8> = match crypto::aead::decrypt(&k3_s, &nonce, &aead_payload, &ad.as_ref()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Alice: Decrypt error: {e}");
-            return true;
         }
-    };
+    }
+
+    /// Resumes the session on `stream` without the password: pq_tls, then key
+    /// confirmation under the resumption secret of the last ticket. A ticket is only
+    /// used once, Google issues the next one for the resumed session.
+    pub(crate) async fn resume(&mut self, stream: &mut impl Transport) -> Result<(), RequestError> {
+        let Some(Resumption { ticket, secret }) = self.resumption.take() else {
+            eprintln!("Alice: Resume error: no ticket, log in again");
+            return Err(RequestError::Failed);
+        };
+        let mut handshake = pq_tls(stream, &self.ca, &self.ad).await?;
+        if let Err(e) = handshake.start_resume(&self.username, &ticket, &secret) {
+            return Err(fail(&mut handshake, stream, "Resume", e).await);
+        }
+        match next_event(&mut handshake, stream, "Resume").await? {
+            Event::LoginSucceeded => (),
+            _ => return Err(unexpected(&mut handshake, stream).await),
+        }
+        self.resumption = Some(recv_ticket(&mut handshake, stream).await?);
+        self.handshake = handshake;
+        Ok(())
+    }
+
+    /// Ends the session with close_notify and waits for Google's, after which the
+    /// connection is ready for the next handshake.
+    pub(crate) async fn close(mut self, stream: &mut impl Transport) -> Result<(), RequestError> {
+        Ok(User::close(stream, &mut self.handshake).await?)
+    }
 
-    // Verify MAC
-    println!("Alice: Verifying mac_s");
-    assert!(verify_hmac(
-        ks.as_bytes(),
-        b"Server KC",
-        mac_s.as_bytes()
-    ));
-    assert_eq!(mac_s.as_bytes(), expected_mac_s.as_bytes());
-    println!("Alice: Valid MACs received.\n\n");
+    /// What Alice learned about Google during pq_tls.
+    pub(crate) fn info(&self) -> &HandshakeInfo {
+        self.handshake.info()
+    }
+}
+
+/// Logs in and passes the second factor, asking for the TOTP code on stdin if Google
+/// requires one and `totp_code` is `None`.
+pub(crate) async fn open_session(
+    ca: &CA,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
+    username: &str,
+    pw: &str,
+    totp_code: Option<&str>,
+) -> Result<RatchetSession, RequestError> {
+    let mut handshake = authenticate(ca, stream, ad, username.as_bytes(), pw.as_bytes(), b"Login").await?;
 
     // End of login -----------------------------------------------------------------------------------------------------------
     // Start communication -----------------------------------------------------------------------------------------------------------
@@ -305,347 +418,246 @@ pub fn login(
     // ----------- Double Ratchet -----------
     println!("Alice: Double Ratchet stage");
 
-    let mut rk_i = sk;
-    let mut large_y_i = large_y;
-    let mut _x_i = x;
-
-    #[cfg(not(test))]
-    loop {
-        println!("Enter a message to send to Google: ");
-        let mut message_from_user = String::new();
-        io::stdin()
-            .read_line(&mut message_from_user)
-            .expect("Error reading message_from_user");
-        let message_from_user = message_from_user.trim();
-
-        let (x_i_plus_1, large_y_plus_one, rk_i_plus_2, _) = match inner_double_ratchet(&mut stream, aead_nonce, &ad, g, &k3_c, &k3_s, rk_i, large_y_i, message_from_user) {
-            Ok(value) => value,
-            Err(value) => return value,
-        };
+    let mut status = recv_status(&mut handshake, stream).await?;
 
-        rk_i = rk_i_plus_2.into();
-        large_y_i = large_y_plus_one;
-        _x_i = x_i_plus_1;
+    // Google rotated its AKE key since the envelope was created
+    if let Some(request) = status.strip_prefix(STATUS_REWRAP) {
+        println!("Alice: Re-wrapping the envelope for Google's new key");
+        if let Err(e) = handshake.rewrap_envelope(request) {
+            return Err(fail(&mut handshake, stream, "Re-wrap", e).await);
+        }
+        User::flush(stream, &mut handshake).await?;
+        status = recv_status(&mut handshake, stream).await?;
     }
+    second_factor(&mut handshake, stream, &status, totp_code, "Login").await?;
+
+    let mut session = RatchetSession {
+        handshake,
+        resumption: None,
+        ca: ca.clone(),
+        ad: *ad,
+        username: username.as_bytes().to_vec(),
+    };
 
-    #[cfg(test)]
-    {
-        return false
-    }
+    // Ticket for resuming the session once Google ends it
+    session.resumption = Some(recv_ticket(&mut session.handshake, stream).await?);
+    Ok(session)
 }
 
-pub(crate) fn inner_double_ratchet(
-    mut stream: &mut &mut TcpStream,
-    aead_nonce: &mut [u8; 12],
-    ad: &&&[u8; 13],
-    g: ProjectivePoint,
-    k3_c: &[u8; 32],
-    k3_s: &[u8; 32],
-    mut rk_i: Output<Sha256>,
-    mut large_y_i: ProjectivePoint,
-    message_from_user: &str
-) -> Result<(Scalar, ProjectivePoint, [u8; 32], String), bool> {
-    // Calculate the new ratchet keys and encrypt the message using mk_1
-    println!("Alice: Calculating new ratchet keys");
-    let x_i_plus_1 = Scalar::random(&mut OsRng);
-    let (rk_i_plus_1, ck_0) = kdf_rk(rk_i.as_bytes(), (large_y_i * x_i_plus_1).to_bytes().as_bytes());
-    let (ck_1, mk_1) = kdf_ck(ck_0.as_bytes());
-    let c1: Vec<u8> = match crypto::aead::encrypt(&mk_1.try_into().unwrap(), &aead_nonce, message_from_user.as_bytes(), &ad.to_vec()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Alice: Encrypt error: {e}");
-            return Err(true);
+/// Second factor stage after `authenticate`. If Google answered with `status`
+/// STATUS_TOTP_REQUIRED, sends the TOTP code as the first ratchet message, asking for it
+/// on stdin if `totp_code` is `None`.
+async fn second_factor(
+    handshake: &mut ClientHandshake,
+    stream: &mut impl Transport,
+    status: &[u8],
+    totp_code: Option<&str>,
+    context: &str,
+) -> Result<(), RequestError> {
+    if status == STATUS_OK {
+        return Ok(());
+    }
+    if status != STATUS_TOTP_REQUIRED {
+        eprintln!("Alice: {context} error: rejected by Google");
+        return Err(unexpected(handshake, stream).await);
+    }
+    let code = match totp_code {
+        Some(code) => code.to_string(),
+        None => {
+            println!("Enter TOTP code: ");
+            let mut code = String::new();
+            io::stdin()
+                .read_line(&mut code)
+                .expect("Error reading TOTP code");
+            code
         }
     };
 
-    // Send large_x_plus_one and c1 to Google
-    println!("Alice: Sending X_i+1 and c1 to Google");
-    let mut msg = Vec::new();
-    msg.extend_from_slice(aead_nonce.as_bytes());
-    msg.extend_from_slice((g * x_i_plus_1).to_bytes().as_bytes());
-    msg.extend_from_slice(c1.as_bytes());
-    OsRng.fill_bytes(aead_nonce);
-    let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k3_c, &aead_nonce, msg.as_bytes(), &ad.to_vec()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Alice: Encrypt error: {e}");
-            return Err(true);
-        }
-    };
-    let msg = Message::AeadCiphertext {
-        nonce: *aead_nonce,
-        aead_payload: cypher_text
-    };
-    User::send_bytes(&mut stream, &msg);
-
-    // Can be used for multiple messages
-    let (_ck_2, _mk_2) = kdf_ck(ck_1.as_bytes());
-
-
-    // Receive large_y_plus_one and c1 from Alice
-    println!("Alice: Waiting for Y_i+1 and c1 from Google");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce, aead_payload) = match msg {
-        Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-        _ => {
-            match msg {
-                Message::Reset {} => (),
-                _ => {
-                    eprintln!("Alice: Unexpected message");
-                    return Err(true);
-                }
-            }
-            RECEIVED_RESET.store(true, Ordering::Relaxed);
-            panic!("Alice: Unexpected message")
-        },
-    };
-    let decrypted_msg: Vec<u8> = match crypto::aead::decrypt(&k3_s, &nonce, &aead_payload, &ad.as_ref()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Alice: Decrypt error: {e}");
-            return Err(true);
+    println!("Alice: Sending TOTP code to Google");
+    if let Err(e) = handshake.send_app_data(code.trim()) {
+        return Err(fail(handshake, stream, "Encrypt", e).await);
+    }
+    match next_event(handshake, stream, context).await? {
+        Event::AppData(verdict) if verdict.as_bytes() == STATUS_OK => Ok(()),
+        Event::AppData(_) => {
+            eprintln!("Alice: {context} error: invalid TOTP code");
+            Err(RequestError::Failed)
         }
-    };
-    if decrypted_msg.len() < 45 {
-        eprintln!("Alice: Decrypt error: received malformed ratchet payload (len={})", decrypted_msg.len());
-        return Err(true);
+        _ => Err(unexpected(handshake, stream).await),
     }
-    let (nonce_and_large_y_plus_one_as_bytes, c1) = decrypted_msg.split_at(45);
-    let (nonce, large_y_plus_one_as_bytes) = nonce_and_large_y_plus_one_as_bytes.split_at(12);
-    let large_y_plus_one = ProjectivePoint::from_bytes(large_y_plus_one_as_bytes.into()).unwrap();
+}
 
-    // Recover the chains
-    println!("Alice: Recovering chains");
-    let (rk_i_plus_2, ck_0) = kdf_rk(rk_i_plus_1.as_bytes(), (large_y_plus_one * x_i_plus_1).to_bytes().as_bytes());
-    let (ck_1, mk_1) = kdf_ck(ck_0.as_bytes());
+pub(crate) async fn register(
+    ca: &CA,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
+    username: &str,
+    pw: &str,
+) -> Result<(), RequestError> {
+    registration_request(ca, stream, ad, username, pw, b"Register").await.map(|_| ())
+}
 
-    let message_from_server: Vec<u8> = match crypto::aead::decrypt(&mk_1.try_into().unwrap(), &nonce.try_into().unwrap(), &c1, &ad.as_ref()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Alice: Decrypt error: {e}");
-            return Err(true);
+/// Registers `username` with a TOTP second factor and returns the enrolled secret.
+pub(crate) async fn register_with_totp(
+    ca: &CA,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
+    username: &str,
+    pw: &str,
+) -> Result<[u8; totp::SECRET_LEN], RequestError> {
+    let status = registration_request(ca, stream, ad, username, pw, b"RegisterTotp").await?;
+    match status.strip_prefix(b"OK;").and_then(|secret| <[u8; totp::SECRET_LEN]>::try_from(secret).ok()) {
+        Some(secret) => Ok(secret),
+        None => {
+            eprintln!("Alice: Register error: no TOTP secret received");
+            Err(RequestError::Failed)
         }
-    };
-
-    let message_text = String::from_utf8_lossy(&message_from_server);
-    println!("Alice: Received message from Google: {}", message_text);
-
-    // Can be used for multiple messages
-    let (_ck_2, _mk_2) = kdf_ck(ck_1.as_bytes());
-    Ok((x_i_plus_1, large_y_plus_one, rk_i_plus_2, format!("{}", message_text)))
+    }
 }
 
-pub(crate) fn register(
-    ca: &mut CA,
-    mut stream: &mut TcpStream,
-    aead_nonce: &mut [u8; 12],
-    ad: &&[u8; 13],
+/// Sends `action;username;password` and returns the accepted registration status.
+async fn registration_request(
+    ca: &CA,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
     username: &str,
     pw: &str,
-) -> bool {
-    {
-        let username = username.as_bytes();
-        let pw = pw.as_bytes();
-
-        // Establish TLS connection
-        println!("Alice: Establishing TLS connection");
-        let (_k1_c, _k1_s, _k2_c, _k2_s, k3_c, _k3_s) = pq_tls(&mut stream, ca, ad);
-        println!("Alice: TLS connection established.");
-
-        // Send username and password to Google
-        println!("Alice: Sending username and password to Google");
-        let mut msg = Vec::new();
-        msg.extend_from_slice(b"Register;");
-        msg.extend_from_slice(username);
-        msg.extend_from_slice(b";");
-        msg.extend_from_slice(pw);
-        OsRng.fill_bytes(aead_nonce);
-        let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k3_c, &aead_nonce, msg.as_bytes(), &ad.to_vec()) {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Alice: Encrypt error: {e}");
-                return true;
-            }
-        };
-        let msg = Message::AeadCiphertext {
-            nonce: *aead_nonce,
-            aead_payload: cypher_text,
-        };
-        User::send_bytes(&mut stream, &msg);
+    action: &[u8],
+) -> Result<Vec<u8>, RequestError> {
+    // Establish TLS connection
+    println!("Alice: Establishing TLS connection");
+    let mut handshake = pq_tls(stream, ca, ad).await?;
+    println!("Alice: TLS connection established.");
+
+    // Send username and password to Google
+    println!("Alice: Sending username and password to Google");
+    let request = Secret::new([action, b";", username.as_bytes(), b";", pw.as_bytes()].concat());
+    let msg = match handshake.seal(&request) {
+        Ok(msg) => msg,
+        Err(e) => return Err(fail(&mut handshake, stream, "Encrypt", e).await),
     };
-    false
+    User::send_bytes(stream, &handshake, &msg).await?;
+
+    // Receive the registration status
+    println!("Alice: Waiting for registration status");
+    let status = recv_status(&mut handshake, stream).await?;
+    if status.starts_with(STATUS_OK) {
+        println!("Alice: Registration successful.");
+        Ok(status)
+    } else if status == STATUS_USER_EXISTS {
+        eprintln!("Alice: Register error: username already taken");
+        Err(RequestError::Failed)
+    } else {
+        eprintln!("Alice: Register error: rejected by Google");
+        Err(RequestError::Failed)
+    }
 }
 
-fn kdf_ck(ck_i: &[u8]) -> (Vec<u8>, Vec<u8>) {
-    let ck_i_plus_1 = compute_hmac(ck_i.as_bytes(), b"ChainKey");
-    let mk_i = compute_hmac(ck_i.as_bytes(), b"MessageKey");
-
-    (ck_i_plus_1, mk_i)
+/// Changes the password of `username`: proves the old password with a full OPAQUE
+/// login and passes the second factor, then sends the new password under the session's
+/// account key.
+pub(crate) async fn change_password(
+    ca: &CA,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
+    username: &str,
+    old_pw: &str,
+    new_pw: &str,
+    totp_code: Option<&str>,
+) -> Result<(), RequestError> {
+    let mut handshake = authenticate(ca, stream, ad, username.as_bytes(), old_pw.as_bytes(), b"ChangePassword").await?;
+    let status = recv_status(&mut handshake, stream).await?;
+    second_factor(&mut handshake, stream, &status, totp_code, "ChangePassword").await?;
+
+    // Send the new password to Google
+    println!("Alice: Sending new password to Google");
+    send_account_request(&mut handshake, stream, ad, new_pw.as_bytes()).await?;
+
+    if recv_status(&mut handshake, stream).await? == STATUS_OK {
+        println!("Alice: Password changed.");
+        Ok(())
+    } else {
+        eprintln!("Alice: ChangePassword error: rejected by Google");
+        Err(RequestError::Failed)
+    }
 }
 
-fn kdf_rk(rk_i: &[u8], dh: &[u8]) -> ([u8; 32], [u8; 32]) {
-    let (_, hk) = crypto::key_schedule::extract(Some(rk_i), dh);
-    let rk_i_plus_1 = crypto::key_schedule::expand::<32>(&hk, b"RootKey").unwrap();
-    let ck_i = crypto::key_schedule::expand::<32>(&hk, b"ChainKey").unwrap();
-
-    (rk_i_plus_1, ck_i)
+/// Deletes the account of `username` after proving its password and passing the
+/// second factor.
+pub(crate) async fn delete_account(
+    ca: &CA,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
+    username: &str,
+    pw: &str,
+    totp_code: Option<&str>,
+) -> Result<(), RequestError> {
+    let mut handshake = authenticate(ca, stream, ad, username.as_bytes(), pw.as_bytes(), b"DeleteAccount").await?;
+    let status = recv_status(&mut handshake, stream).await?;
+    second_factor(&mut handshake, stream, &status, totp_code, "DeleteAccount").await?;
+
+    // Confirm the deletion, bound to the username
+    println!("Alice: Sending deletion confirmation to Google");
+    let confirmation = [b"DeleteAccount;".as_slice(), username.as_bytes()].concat();
+    send_account_request(&mut handshake, stream, ad, &confirmation).await?;
+
+    if recv_status(&mut handshake, stream).await? == STATUS_OK {
+        println!("Alice: Account deleted.");
+        Ok(())
+    } else {
+        eprintln!("Alice: DeleteAccount error: rejected by Google");
+        Err(RequestError::Failed)
+    }
 }
 
-pub(crate) fn pq_tls(
-    mut stream: &mut TcpStream,
-    ca: &mut CA,
-    ad: &[u8; 13]
-) -> ([u8; 32], [u8; 32], [u8; 32], [u8; 32], [u8; 32], [u8; 32]) {
-
-    let mut nonce_c: [u8; 8] = [0u8; 8];
-    OsRng.fill_bytes(&mut nonce_c);
-    let mut aead_nonce: [u8; 12] = [0u8; 12];
-    let (dk, ek) = MlKem768::generate(&mut OsRng);
-
-    // Send nonce_c, ek to Google
-    println!("Alice: Sending nonce_c and ek to Google");
-    let msg = Message::PqtlsClientHello {
-        nonce_c: nonce_c.to_vec(),
-        ek: ek.as_bytes().to_vec(),
-    };
-    User::send_bytes(&mut stream, &msg);
-
-    // Receive PqtlsServerHello from Alive
-    println!("Alice: Waiting for PqtlsServerHello from Google");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce_s, ct_bytes, verifying_key_bytes) = match msg {
-        Message::PqtlsServerHello { nonce_s, ct, verifying_key } => (nonce_s, ct, verifying_key),
-        _ => {
-            match msg {
-                Message::Reset {} => (),
-                _ => {
-                    eprintln!("Alice: Unexpected message");
-                    return ([0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32]);
-                }
-            }
-            RECEIVED_RESET.store(true, Ordering::Relaxed);
-            panic!("Alice: Unexpected message")
-        },
-    };
-    let verifying_key = VerifyingKey::<MlDsa65>::decode(
-        &EncodedVerifyingKey::<MlDsa65>::try_from(verifying_key_bytes.as_slice())
-            .expect("Invalid verifying key bytes"),
-    );
-    let ct = Ciphertext::<MlKem768>::try_from(ct_bytes.as_slice())
-        .expect("ungültiger Ciphertext");
-
-    // Calculate shared key and K1_c, K1_s, K2_c, K2_s
-    println!("Alice: Calculating shared key and K1_c, K1_s, K2_c, K2_s");
-    let shared_key = dk.decapsulate(&ct).unwrap();
-    let (k1_c, k1_s) = key_schedule_1(shared_key.as_bytes());
-    let (k2_c, k2_s) = key_schedule_2(
-        nonce_c.clone().as_bytes(),
-        ek.as_bytes().as_bytes(),
-        nonce_s.clone().as_bytes(),
-        verifying_key.encode().as_bytes(),
-        shared_key.as_bytes(),
-    );
-
-    // Receive and decrypt the AEAD message
-    println!("Alice: Waiting for AEAD message from Google");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce, aead_payload) = match msg {
-        Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-        _ => {
-            match msg {
-                Message::Reset {} => (),
-                _ => {
-                    eprintln!("Alice: Unexpected message");
-                    return ([0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32]);
-                }
-            }
-            RECEIVED_RESET.store(true, Ordering::Relaxed);
-            panic!("Alice: Unexpected message")
-        },
+/// Sends AEAD(k3_c, {{nonce, AEAD(account_key, request)}}) to Google.
+pub(crate) async fn send_account_request(
+    handshake: &mut ClientHandshake,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
+    request: &[u8],
+) -> Result<(), RequestError> {
+    let Some(sk) = handshake.session_key() else {
+        eprintln!("Alice: Not logged in");
+        User::send_alert(stream, handshake, AlertDescription::InternalError).await?;
+        return Err(RequestError::Failed);
     };
-    let decrypted_msg: Vec<u8> = match crypto::aead::decrypt(&k1_s, &nonce, &aead_payload, &ad.to_vec()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Alice: Decrypt error: {e}");
-            return ([0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32]);
-        }
-    };
-
-    let mac_len = 32;
-    let mac_start = decrypted_msg.len() - mac_len;
-    let google_mac: Vec<u8> = decrypted_msg[mac_start..].to_vec();
-
-    let rest = &decrypted_msg[..mac_start];
-    let half = rest.len() / 2;
-    let cert_from_google_bytes: Vec<u8> = rest[..half].to_vec();
-    let sign_from_google_bytes: Vec<u8> = rest[half..].to_vec();
-    let cert: Signature<MlDsa65> = Signature::try_from(cert_from_google_bytes.as_slice()).unwrap();
-    let google_sign: Signature<MlDsa65> = Signature::try_from(sign_from_google_bytes.as_slice()).unwrap();
-
-    // Calculate K3_c, K3_s
-    println!("Alice: Calculating K3_c, K3_s");
-    let (k3_c, k3_s) = key_schedule_3(
-        nonce_c.clone().as_bytes(),
-        ek.as_bytes().as_bytes(),
-        nonce_s.as_bytes(),
-        verifying_key.encode().as_bytes(),
-        shared_key.as_bytes(),
-        google_sign.encode().as_bytes(),
-        cert.encode().as_bytes(),
-        google_mac.as_bytes(),
-    );
-
-    // Verify the signature, certificate and MAC tag from google
-    println!("Alice: Verifying the signature, certificate and MAC tag from google");
-    let mut expected_sign_msg = Vec::new();
-    expected_sign_msg.extend_from_slice(nonce_c.clone().as_bytes());
-    expected_sign_msg.extend_from_slice(ek.as_bytes().as_bytes());
-    expected_sign_msg.extend_from_slice(nonce_s.as_bytes());
-    expected_sign_msg.extend_from_slice(verifying_key.encode().as_bytes());
-    expected_sign_msg.extend_from_slice(cert.encode().as_bytes());
-    let mut expected_mac_s_input = Vec::new();
-    expected_mac_s_input.extend_from_slice(nonce_c.clone().as_bytes());
-    expected_mac_s_input.extend_from_slice(ek.as_bytes().as_bytes());
-    expected_mac_s_input.extend_from_slice(nonce_s.as_bytes());
-    expected_mac_s_input.extend_from_slice(verifying_key.encode().as_bytes());
-    expected_mac_s_input.extend_from_slice(google_sign.encode().as_bytes());
-    expected_mac_s_input.extend_from_slice(cert.encode().as_bytes());
-    expected_mac_s_input.extend_from_slice(b"ServerMAC");
-
-    assert!(verifying_key.verify(&Sha256::digest(&expected_sign_msg), &google_sign).is_ok());
-    assert!(ca.verifying_key().verify(verifying_key.encode().as_bytes(), &cert).is_ok());
-    assert!(verify_hmac(&k2_s, &Sha256::digest(&expected_mac_s_input), google_mac.as_bytes()));
-
-    // Calculate alice's MAC tag
-    println!("Alice: Calculating alice's MAC tag");
-    let mut mac_c_input = Vec::new();
-    mac_c_input.extend_from_slice(nonce_c.clone().as_bytes());
-    mac_c_input.extend_from_slice(ek.as_bytes().as_bytes());
-    mac_c_input.extend_from_slice(nonce_s.as_bytes());
-    mac_c_input.extend_from_slice(verifying_key.encode().as_bytes());
-    mac_c_input.extend_from_slice(google_sign.encode().as_bytes());
-    mac_c_input.extend_from_slice(cert.encode().as_bytes());
-    mac_c_input.extend_from_slice(b"ClientMAC");
-
-    let mac_c = compute_hmac(&k2_c, &Sha256::digest(&mac_c_input));
-
-    // Send AEAD(k1_c, {{alice_mac_c}}) message from Alice to Google
-    println!("Alice: Sending AEAD(k1_c, {{alice_mac_c}}) message from Alice to Google");
-    OsRng.fill_bytes(&mut aead_nonce);
-    let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k1_c, &aead_nonce, mac_c.as_bytes(), &ad.to_vec()) {
+    let account_key = crypto::key_schedule::account_key(sk.as_slice());
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let c1: Vec<u8> = match crypto::aead::encrypt(&account_key, &nonce, request, ad) {
         Ok(c) => c,
         Err(e) => {
             eprintln!("Alice: Encrypt error: {e}");
-            return ([0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32]);
+            User::send_alert(stream, handshake, AlertDescription::InternalError).await?;
+            return Err(RequestError::Failed);
         }
     };
 
-    let msg = Message::AeadCiphertext {
-        nonce: aead_nonce,
-        aead_payload: cypher_text,
+    let msg = match handshake.seal(&[nonce.as_slice(), &c1].concat()) {
+        Ok(msg) => msg,
+        Err(e) => return Err(fail(handshake, stream, "Encrypt", e).await),
     };
-    User::send_bytes(&mut stream, &msg);
+    Ok(User::send_bytes(stream, handshake, &msg).await?)
+}
 
+/// Receives AEAD(k3_s, status) from Google.
+async fn recv_status(handshake: &mut ClientHandshake, stream: &mut impl Transport) -> Result<Vec<u8>, RequestError> {
+    match User::recv_sealed(stream, handshake).await? {
+        Ok(c) => Ok(c),
+        Err(e) => {
+            eprintln!("Alice: Decrypt error: {e}");
+            Err(RequestError::Failed)
+        }
+    }
+}
 
-    (k1_c, k1_s, k2_c, k2_s, k3_c, k3_s)
+/// Receives the resumption ticket Google issues once the login succeeded.
+async fn recv_ticket(handshake: &mut ClientHandshake, stream: &mut impl Transport) -> Result<Resumption, RequestError> {
+    let status = recv_status(handshake, stream).await?;
+    let (Some(ticket), Some(sk)) = (status.strip_prefix(STATUS_TICKET), handshake.session_key()) else {
+        return Err(unexpected(handshake, stream).await);
+    };
+    let secret = crypto::key_schedule::resumption_secret(sk.as_slice());
+    Ok(Resumption { ticket: ticket.to_vec(), secret })
 }
\ No newline at end of file
@@ -0,0 +1,10 @@
+//! Synchronous entry point to the async client in `alice`.
+
+use crate::client::alice;
+use crate::crypto::participant::CA;
+use crate::transport::blocking::runtime;
+
+/// Runs the terminal client until Alice quits or the connection is lost.
+pub fn alice(ca: &mut CA) {
+    runtime().block_on(Box::pin(alice::alice(ca)));
+}
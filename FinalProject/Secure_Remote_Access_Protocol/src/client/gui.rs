@@ -0,0 +1,427 @@
+//! Desktop client for Alice built on eframe (`cargo run -- --gui`).
+//!
+//! The window never blocks on the network: a worker thread owns the connection to
+//! Google and a single-threaded tokio runtime, and runs the same
+//! `register`/`open_session`/ratchet code as the terminal client. The UI hands it
+//! `Command`s and renders the `Event`s it answers with.
+
+use crate::client::alice::{self, RatchetSession, SERVER_ADDR};
+use crate::handshake::client::HandshakeInfo;
+use crate::client::password_policy::{self, PasswordPolicy};
+use crate::crypto::participant::{RequestError, CA};
+use crate::crypto::secret::Secret;
+use crate::transport;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+/// Requests from the UI to the worker.
+enum Command {
+    Register { username: String, password: Secret<String>, enroll_totp: bool },
+    Login { username: String, password: Secret<String>, totp_code: String },
+    Send(String),
+    Logout,
+}
+
+/// Outcomes reported by the worker to the UI.
+enum Event {
+    Connected,
+    Registered { username: String, totp_secret: Option<String> },
+    RegisterFailed,
+    LoggedIn { username: String, handshake: HandshakeInfo },
+    LoginFailed,
+    Reply(String),
+    SendFailed,
+    LoggedOut,
+    Disconnected(String),
+}
+
+#[derive(PartialEq)]
+enum Status {
+    Connecting,
+    Connected,
+    LoggedIn(String),
+    Disconnected(String),
+}
+
+enum ChatEntry {
+    Sent(String),
+    Received(String),
+}
+
+pub fn run(ca: CA) -> eframe::Result {
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([760.0, 480.0]),
+        ..Default::default()
+    };
+    eframe::run_native(
+        "Secure Remote Access",
+        options,
+        Box::new(move |cc| {
+            let (commands, command_rx) = mpsc::channel();
+            let (event_tx, events) = mpsc::channel();
+            let ctx = cc.egui_ctx.clone();
+            thread::spawn(move || worker(ca, command_rx, event_tx, ctx));
+            Ok(Box::new(AliceApp::new(commands, events)))
+        }),
+    )
+}
+
+/// Runs the commands of the UI one after the other on a single connection to Google,
+/// and sends heartbeats while a session waits for the next command.
+fn worker(ca: CA, commands: Receiver<Command>, events: Sender<Event>, ctx: egui::Context) {
+    let notify = |event| {
+        let _ = events.send(event);
+        ctx.request_repaint();
+    };
+
+    let runtime = transport::blocking::runtime();
+    let mut stream = match runtime.block_on(TcpStream::connect(SERVER_ADDR)) {
+        Ok(stream) => stream,
+        Err(e) => {
+            notify(Event::Disconnected(format!("cannot connect to {SERVER_ADDR}: {e}")));
+            return;
+        }
+    };
+    notify(Event::Connected);
+
+    let ad = b"Alice,Google,";
+    let mut session: Option<RatchetSession> = None;
+
+    loop {
+        let command = match commands.recv_timeout(alice::HEARTBEAT_INTERVAL) {
+            Ok(command) => command,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(active) = session.as_mut() {
+                    let result = runtime.block_on(Box::pin(active.heartbeat(&mut stream)));
+                    if result.is_err() && !reconnect(&runtime, &mut stream, active) {
+                        session = None;
+                        notify(Event::SendFailed);
+                    }
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        let event = match command {
+            Command::Register { username, password, enroll_totp } => {
+                // A rejected registration leaves the connection usable
+                let result = runtime.block_on(Box::pin(async {
+                    if enroll_totp {
+                        alice::register_with_totp(&ca, &mut stream, ad, &username, &password).await
+                            .map(|secret| Some(alice::to_hex(&secret)))
+                    } else {
+                        alice::register(&ca, &mut stream, ad, &username, &password).await.map(|()| None)
+                    }
+                }));
+                match result {
+                    Ok(totp_secret) => Event::Registered { username, totp_secret },
+                    Err(_) => Event::RegisterFailed,
+                }
+            }
+            Command::Login { username, password, totp_code } => {
+                let result = runtime.block_on(Box::pin(alice::open_session(&ca, &mut stream, ad, &username, &password, Some(&totp_code))));
+                match result {
+                    Ok(new_session) => {
+                        let handshake = new_session.info().clone();
+                        session = Some(new_session);
+                        Event::LoggedIn { username, handshake }
+                    }
+                    Err(_) => Event::LoginFailed,
+                }
+            }
+            Command::Send(message) => {
+                let result = match session.as_mut() {
+                    Some(active) => {
+                        let mut result = runtime.block_on(Box::pin(active.send(&mut stream, &message)));
+                        // The connection may be gone, so resume on a new one and send again
+                        if result.is_err() && reconnect(&runtime, &mut stream, active) {
+                            result = runtime.block_on(Box::pin(active.send(&mut stream, &message)));
+                        }
+                        result
+                    }
+                    None => Err(RequestError::Failed),
+                };
+                match result {
+                    Ok(answer) => Event::Reply(answer),
+                    Err(_) => {
+                        session = None;
+                        Event::SendFailed
+                    }
+                }
+            }
+            Command::Logout => {
+                // Both sides drop the ratchet session and Google waits for a new handshake
+                if let Some(active) = session.take() {
+                    let _ = runtime.block_on(Box::pin(active.close(&mut stream)));
+                }
+                Event::LoggedOut
+            }
+        };
+        notify(event);
+    }
+}
+
+/// Connects to Google again and resumes `session` on the new connection, after the old
+/// one was lost, e.g. to Google's idle timeout.
+fn reconnect(runtime: &Runtime, stream: &mut TcpStream, session: &mut RatchetSession) -> bool {
+    match runtime.block_on(TcpStream::connect(SERVER_ADDR)) {
+        Ok(new_stream) => *stream = new_stream,
+        Err(_) => return false,
+    }
+    runtime.block_on(Box::pin(session.resume(stream))).is_ok()
+}
+
+pub struct AliceApp {
+    commands: Sender<Command>,
+    events: Receiver<Event>,
+    policy: PasswordPolicy,
+    status: Status,
+    /// A command is running on the worker.
+    busy: bool,
+    /// Result of the last command, shown below the forms.
+    notice: Option<String>,
+    username: String,
+    password: Secret<String>,
+    confirm_password: Secret<String>,
+    totp_code: String,
+    enroll_totp: bool,
+    handshake: Option<HandshakeInfo>,
+    chat: Vec<ChatEntry>,
+    message: String,
+}
+
+impl AliceApp {
+    fn new(commands: Sender<Command>, events: Receiver<Event>) -> Self {
+        AliceApp {
+            commands,
+            events,
+            policy: PasswordPolicy::default(),
+            status: Status::Connecting,
+            busy: false,
+            notice: None,
+            username: String::new(),
+            password: Secret::default(),
+            confirm_password: Secret::default(),
+            totp_code: String::new(),
+            enroll_totp: false,
+            handshake: None,
+            chat: Vec::new(),
+            message: String::new(),
+        }
+    }
+
+    fn submit(&mut self, command: Command) {
+        if self.commands.send(command).is_ok() {
+            self.busy = true;
+            self.notice = None;
+        } else {
+            self.status = Status::Disconnected("the connection worker stopped".to_string());
+        }
+    }
+
+    fn poll_events(&mut self) {
+        loop {
+            match self.events.try_recv() {
+                Ok(event) => self.apply(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if !matches!(self.status, Status::Disconnected(_)) {
+                        self.status = Status::Disconnected("the connection worker stopped".to_string());
+                    }
+                    self.busy = false;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Event) {
+        self.busy = false;
+        match event {
+            Event::Connected => self.status = Status::Connected,
+            Event::Registered { username, totp_secret } => {
+                self.notice = Some(match totp_secret {
+                    Some(secret) => format!("Registered {username}. TOTP secret (keep it safe): {secret}"),
+                    None => format!("Registered {username}."),
+                });
+            }
+            Event::RegisterFailed => {
+                self.notice = Some("Registration failed: the username is taken or Google rejected it.".to_string());
+            }
+            Event::LoggedIn { username, handshake } => {
+                self.status = Status::LoggedIn(username);
+                self.handshake = Some(handshake);
+                self.chat.clear();
+                self.totp_code.clear();
+            }
+            Event::LoginFailed => {
+                self.notice = Some("Login failed: wrong credentials or TOTP code, or too many attempts.".to_string());
+            }
+            Event::Reply(answer) => self.chat.push(ChatEntry::Received(answer)),
+            Event::SendFailed => {
+                self.status = Status::Connected;
+                self.notice = Some("The session was closed after an error, please log in again.".to_string());
+            }
+            Event::LoggedOut => self.status = Status::Connected,
+            Event::Disconnected(reason) => self.status = Status::Disconnected(reason),
+        }
+    }
+
+    /// Takes the typed password, normalized as in the terminal client, and clears the field.
+    fn take_password(&mut self) -> Secret<String> {
+        let password = Secret::new(password_policy::normalize(&self.password));
+        self.password = Secret::default();
+        password
+    }
+
+    fn login(&mut self) {
+        let username = self.username.trim().to_string();
+        let password = self.take_password();
+        let totp_code = self.totp_code.trim().to_string();
+        self.submit(Command::Login { username, password, totp_code });
+    }
+
+    fn register(&mut self) {
+        let confirmation = Secret::new(password_policy::normalize(&self.confirm_password));
+        self.confirm_password = Secret::default();
+        let password = self.take_password();
+        if password != confirmation {
+            self.notice = Some("The passwords do not match.".to_string());
+            return;
+        }
+        if let Err(e) = self.policy.check(&password) {
+            self.notice = Some(format!("Weak password: {e}."));
+            return;
+        }
+        let username = self.username.trim().to_string();
+        let enroll_totp = self.enroll_totp;
+        self.submit(Command::Register { username, password, enroll_totp });
+    }
+
+    fn status_bar(&self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            let (color, text) = match &self.status {
+                Status::Connecting => (egui::Color32::YELLOW, format!("Connecting to {SERVER_ADDR}...")),
+                Status::Connected => (egui::Color32::LIGHT_GREEN, format!("Connected to {SERVER_ADDR}")),
+                Status::LoggedIn(username) => (egui::Color32::LIGHT_GREEN, format!("Logged in as {username}")),
+                Status::Disconnected(reason) => (egui::Color32::LIGHT_RED, format!("Disconnected: {reason}")),
+            };
+            ui.colored_label(color, "●");
+            ui.label(text);
+            if self.busy {
+                ui.spinner();
+            }
+        });
+    }
+
+    fn handshake_panel(&self, ui: &mut egui::Ui) {
+        ui.heading("Handshake");
+        match &self.handshake {
+            Some(handshake) => {
+                ui.label("Protocol version");
+                ui.add(egui::Label::new(egui::RichText::new(handshake.version.to_string()).monospace()));
+                ui.add_space(8.0);
+                ui.label("Suite");
+                ui.add(egui::Label::new(egui::RichText::new(handshake.suite).monospace()).wrap());
+                ui.add_space(8.0);
+                ui.label("Server key fingerprint (SHA-256)");
+                ui.add(egui::Label::new(egui::RichText::new(&handshake.server_key_fingerprint).monospace()).wrap());
+            }
+            None => {
+                ui.label("No session yet.");
+            }
+        }
+    }
+
+    fn account_forms(&mut self, ui: &mut egui::Ui) {
+        let enabled = self.status == Status::Connected && !self.busy;
+        ui.add_enabled_ui(enabled, |ui| {
+            ui.heading("Log in");
+            egui::Grid::new("credentials").num_columns(2).show(ui, |ui| {
+                ui.label("Username");
+                ui.text_edit_singleline(&mut self.username);
+                ui.end_row();
+                ui.label("Password");
+                ui.add(egui::TextEdit::singleline(&mut *self.password).password(true));
+                ui.end_row();
+                ui.label("TOTP code");
+                ui.add(egui::TextEdit::singleline(&mut self.totp_code).hint_text("only if enabled"));
+                ui.end_row();
+            });
+            if ui.button("Log in").clicked() {
+                self.login();
+            }
+
+            ui.separator();
+            ui.heading("Register");
+            egui::Grid::new("registration").num_columns(2).show(ui, |ui| {
+                ui.label("Confirm password");
+                ui.add(egui::TextEdit::singleline(&mut *self.confirm_password).password(true));
+                ui.end_row();
+            });
+            ui.checkbox(&mut self.enroll_totp, "Enable two-factor authentication (TOTP)");
+            if ui.button("Register").clicked() {
+                self.register();
+            }
+        });
+    }
+
+    fn chat_view(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.heading("Messages");
+            if ui.add_enabled(!self.busy, egui::Button::new("Log out")).clicked() {
+                self.submit(Command::Logout);
+            }
+        });
+        ui.separator();
+
+        egui::ScrollArea::vertical()
+            .max_height(ui.available_height() - 36.0)
+            .auto_shrink(false)
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for entry in &self.chat {
+                    match entry {
+                        ChatEntry::Sent(text) => ui.label(egui::RichText::new(format!("Alice: {text}")).strong()),
+                        ChatEntry::Received(text) => ui.label(format!("Google: {text}")),
+                    };
+                }
+            });
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            let input = ui.add_enabled(!self.busy, egui::TextEdit::singleline(&mut self.message).hint_text("Message to Google"));
+            let entered = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            let clicked = ui.add_enabled(!self.busy, egui::Button::new("Send")).clicked();
+            if (entered || clicked) && !self.message.trim().is_empty() {
+                let message = self.message.trim().to_string();
+                self.message.clear();
+                self.chat.push(ChatEntry::Sent(message.clone()));
+                self.submit(Command::Send(message));
+                input.request_focus();
+            }
+        });
+    }
+}
+
+impl eframe::App for AliceApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.poll_events();
+
+        egui::TopBottomPanel::top("status").show(ctx, |ui| self.status_bar(ui));
+        egui::SidePanel::right("handshake").min_width(220.0).show(ctx, |ui| self.handshake_panel(ui));
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if matches!(self.status, Status::LoggedIn(_)) {
+                self.chat_view(ui);
+            } else {
+                self.account_forms(ui);
+            }
+            if let Some(notice) = &self.notice {
+                ui.separator();
+                ui.label(notice);
+            }
+        });
+    }
+}
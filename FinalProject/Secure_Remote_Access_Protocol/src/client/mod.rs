@@ -1 +1,4 @@
-pub mod alice;
\ No newline at end of file
+pub mod alice;
+pub mod blocking;
+pub mod gui;
+pub mod password_policy;
\ No newline at end of file
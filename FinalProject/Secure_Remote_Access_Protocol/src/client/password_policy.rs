@@ -0,0 +1,133 @@
+//! Password policy checked by Alice before a password is registered.
+//!
+//! Every password typed by the user goes through `normalize` (also at login, so the
+//! same password always yields the same bytes); new passwords are then checked with
+//! `PasswordPolicy::check`.
+
+use std::collections::HashSet;
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+
+/// Breached-password list shipped with the offline-attack homework.
+const BREACHED_PASSWORDS: &str = include_str!("../../../../Homework_3/offline-attack/Dictionary.txt");
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PolicyError {
+    TooShort { min_len: usize },
+    TooLong { max_len: usize },
+    TooFewCharacterClasses { min_classes: usize },
+    Breached,
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::TooShort { min_len } => write!(f, "password must be at least {min_len} characters long"),
+            PolicyError::TooLong { max_len } => write!(f, "password must be at most {max_len} characters long"),
+            PolicyError::TooFewCharacterClasses { min_classes } => write!(
+                f,
+                "password must contain at least {min_classes} of: lowercase letters, uppercase letters, digits, symbols"
+            ),
+            PolicyError::Breached => write!(f, "password appears in a list of breached passwords"),
+        }
+    }
+}
+
+/// Trims surrounding whitespace (including the newline left by `read_line`) and applies
+/// Unicode NFKC normalization.
+pub fn normalize(password: &str) -> String {
+    password.trim().nfkc().collect()
+}
+
+pub struct PasswordPolicy {
+    /// Minimum length in characters.
+    pub min_len: usize,
+    /// Maximum length in characters.
+    pub max_len: usize,
+    /// Minimum number of character classes (lowercase, uppercase, digit, other).
+    pub min_classes: usize,
+    /// Lowercased breached passwords.
+    breached: HashSet<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_len: 10,
+            max_len: 128,
+            min_classes: 3,
+            breached: HashSet::new(),
+        }
+        .with_breached_list(BREACHED_PASSWORDS.lines())
+    }
+}
+
+impl PasswordPolicy {
+    /// Adds the passwords of `list` (one per item) to the breached passwords.
+    pub fn with_breached_list<'a>(mut self, list: impl IntoIterator<Item = &'a str>) -> Self {
+        self.breached.extend(
+            list.into_iter()
+                .map(|line| normalize(line).to_lowercase())
+                .filter(|line| !line.is_empty()),
+        );
+        self
+    }
+
+    /// Checks a password that was already passed through `normalize`.
+    pub fn check(&self, password: &str) -> Result<(), PolicyError> {
+        let len = password.chars().count();
+        if len < self.min_len {
+            return Err(PolicyError::TooShort { min_len: self.min_len });
+        }
+        if len > self.max_len {
+            return Err(PolicyError::TooLong { max_len: self.max_len });
+        }
+
+        let classes = [
+            password.chars().any(char::is_lowercase),
+            password.chars().any(char::is_uppercase),
+            password.chars().any(char::is_numeric),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|&&present| present).count() < self.min_classes {
+            return Err(PolicyError::TooFewCharacterClasses { min_classes: self.min_classes });
+        }
+
+        if self.breached.contains(&password.to_lowercase()) {
+            return Err(PolicyError::Breached);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_trims_and_applies_nfkc() {
+        assert_eq!(normalize("  hunter2\n"), "hunter2");
+        // Fullwidth letters and the "ﬁ" ligature are compatibility characters
+        assert_eq!(normalize("ｐａｓｓ\u{FB01}"), "passfi");
+        // Composed and decomposed "é" normalize to the same string
+        assert_eq!(normalize("caf\u{E9}"), normalize("cafe\u{301}"));
+    }
+
+    #[test]
+    fn check_enforces_length_classes_and_breached_list() {
+        let policy = PasswordPolicy::default().with_breached_list(["Correct-Horse-42"]);
+
+        assert_eq!(policy.check(""), Err(PolicyError::TooShort { min_len: 10 }));
+        assert_eq!(policy.check(&"aA1!".repeat(40)), Err(PolicyError::TooLong { max_len: 128 }));
+        assert_eq!(policy.check("alllowercase"), Err(PolicyError::TooFewCharacterClasses { min_classes: 3 }));
+        assert_eq!(policy.check("correct-horse-42"), Err(PolicyError::Breached));
+        assert!(policy.check("Tr0ub4dor&3-staple").is_ok());
+    }
+
+    #[test]
+    fn default_policy_rejects_dictionary_passwords() {
+        let policy = PasswordPolicy { min_len: 0, min_classes: 0, ..PasswordPolicy::default() };
+        assert_eq!(policy.check("password"), Err(PolicyError::Breached));
+        assert_eq!(policy.check("QWERTY"), Err(PolicyError::Breached));
+    }
+}
@@ -1,8 +1,9 @@
 use aes_gcm::{Aes256Gcm, KeyInit, aead::{Aead, Payload, Error}};
 use aes_gcm::aead::generic_array::GenericArray;
+use crate::crypto::secret::SecretKey;
 
 /// 32-byte AES-256 key
-pub type Key = [u8; 32];
+pub type Key = SecretKey;
 /// 96-bit (12-byte) AES-GCM nonce (a.k.a. IV)
 pub type Nonce = [u8; 12];
 
@@ -10,7 +11,7 @@ pub type Nonce = [u8; 12];
 /// authenticating `ad` as associated data.
 /// Returns: ciphertext || tag (the tag is appended by the library).
 pub fn encrypt(key: &Key, nonce: &Nonce, plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>, Error> {
-    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(key.as_slice()));
     let n = GenericArray::from_slice(nonce);
     cipher.encrypt(n, Payload { msg: plaintext, aad: ad }) // returns Vec<u8>
 }
@@ -18,7 +19,7 @@ pub fn encrypt(key: &Key, nonce: &Nonce, plaintext: &[u8], ad: &[u8]) -> Result<
 /// Decrypts AES-256-GCM using `key`, `nonce`, and `ad`.
 /// Returns plaintext on success; on any tampering / mismatch it returns an error.
 pub fn decrypt(key: &Key, nonce: &Nonce, ciphertext: &[u8], ad: &[u8]) -> Result<Vec<u8>, Error> {
-    let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
+    let cipher = Aes256Gcm::new(GenericArray::from_slice(key.as_slice()));
     let nonce_ga = GenericArray::from_slice(nonce);
     cipher.decrypt(nonce_ga, Payload { msg: ciphertext, aad: ad })
 }
\ No newline at end of file
@@ -0,0 +1,230 @@
+//! The Diffie-Hellman group of the 3DH key exchange, the envelope's client key pair and
+//! the double ratchet.
+//!
+//! The group is picked at build time: secp256k1 by default, X25519 with the `x25519`
+//! feature or ristretto255 with the `ristretto255` feature. Alice and Google have to be
+//! built with the same group, and Google's stored records and AKE keys only load with
+//! the group they were created with. The OPRF stays on secp256k1 either way.
+
+use crate::crypto::secret::Secret;
+use std::fmt::Debug;
+use zeroize::Zeroize;
+
+#[cfg(all(feature = "x25519", feature = "ristretto255"))]
+compile_error!("the `x25519` and `ristretto255` features select different DH groups, enable at most one");
+
+/// A prime-order group (or X25519) with its encodings.
+pub trait DhGroup {
+    type Scalar: Zeroize + Clone;
+    type Element: Copy + PartialEq + Debug;
+
+    /// Name recorded in Google's data directory.
+    const NAME: &'static str;
+    /// Length of an encoded element, fixed for every element.
+    const ELEMENT_LEN: usize;
+
+    fn random_scalar() -> Self::Scalar;
+    /// Derives a private key from `seed` with the domain separation tag `dst`
+    /// (`DeriveDiffieHellmanKeyPair`), `None` for the rare seeds without one.
+    fn derive_scalar(seed: &[u8], dst: &[u8]) -> Option<Self::Scalar>;
+    fn scalar_to_bytes(scalar: &Self::Scalar) -> Secret<[u8; 32]>;
+    fn scalar_from_bytes(bytes: &[u8; 32]) -> Option<Self::Scalar>;
+
+    fn public_key(scalar: &Self::Scalar) -> Self::Element;
+    /// The encoded shared element, key material for the KDFs.
+    fn dh(scalar: &Self::Scalar, element: &Self::Element) -> Secret<Vec<u8>>;
+
+    fn encode(element: &Self::Element) -> Vec<u8>;
+    /// Decodes an element sent by the peer, refusing the identity and small-order elements.
+    fn decode(bytes: &[u8]) -> Option<Self::Element>;
+}
+
+#[cfg(not(any(feature = "x25519", feature = "ristretto255")))]
+pub type Group = Secp256k1;
+#[cfg(feature = "x25519")]
+pub type Group = X25519;
+#[cfg(all(feature = "ristretto255", not(feature = "x25519")))]
+pub type Group = Ristretto255;
+
+pub type DhScalar = <Group as DhGroup>::Scalar;
+pub type DhElement = <Group as DhGroup>::Element;
+/// Length of an encoded element of the selected group, see `DhGroup::ELEMENT_LEN`.
+pub const ELEMENT_LEN: usize = <Group as DhGroup>::ELEMENT_LEN;
+
+/// secp256k1 with compressed SEC1 encodings.
+pub struct Secp256k1;
+
+impl DhGroup for Secp256k1 {
+    type Scalar = k256::Scalar;
+    type Element = k256::ProjectivePoint;
+
+    const NAME: &'static str = "secp256k1";
+    const ELEMENT_LEN: usize = 33;
+
+    fn random_scalar() -> Self::Scalar {
+        <k256::Scalar as elliptic_curve::Field>::random(&mut rand_core::OsRng)
+    }
+
+    fn derive_scalar(seed: &[u8], dst: &[u8]) -> Option<Self::Scalar> {
+        use elliptic_curve::hash2curve::{ExpandMsgXmd, GroupDigest};
+        let scalar = k256::Secp256k1::hash_to_scalar::<ExpandMsgXmd<sha3::Sha3_256>>(&[seed], &[dst]).ok()?;
+        Some(scalar).filter(|scalar| !bool::from(scalar.is_zero()))
+    }
+
+    fn scalar_to_bytes(scalar: &Self::Scalar) -> Secret<[u8; 32]> {
+        Secret::new(scalar.to_bytes().into())
+    }
+
+    fn scalar_from_bytes(bytes: &[u8; 32]) -> Option<Self::Scalar> {
+        use elliptic_curve::PrimeField;
+        k256::Scalar::from_repr((*bytes).into()).into()
+    }
+
+    fn public_key(scalar: &Self::Scalar) -> Self::Element {
+        k256::ProjectivePoint::GENERATOR * scalar
+    }
+
+    fn dh(scalar: &Self::Scalar, element: &Self::Element) -> Secret<Vec<u8>> {
+        Secret::new(Self::encode(&(*element * scalar)))
+    }
+
+    fn encode(element: &Self::Element) -> Vec<u8> {
+        use elliptic_curve::group::GroupEncoding;
+        element.to_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self::Element> {
+        use elliptic_curve::group::{Group as _, GroupEncoding};
+        if bytes.len() != Self::ELEMENT_LEN {
+            return None;
+        }
+        Option::<k256::ProjectivePoint>::from(k256::ProjectivePoint::from_bytes(bytes.into()))
+            .filter(|element| !bool::from(element.is_identity()))
+    }
+}
+
+/// X25519 (RFC 7748) on Montgomery u-coordinates, as in the DHKE and DHIES demos.
+#[cfg(feature = "x25519")]
+pub struct X25519;
+
+#[cfg(feature = "x25519")]
+impl DhGroup for X25519 {
+    type Scalar = x25519_dalek::StaticSecret;
+    type Element = x25519_dalek::PublicKey;
+
+    const NAME: &'static str = "x25519";
+    const ELEMENT_LEN: usize = 32;
+
+    fn random_scalar() -> Self::Scalar {
+        x25519_dalek::StaticSecret::random_from_rng(rand_core::OsRng)
+    }
+
+    /// Every 32-byte string is a private key, so the seed is only hashed with the tag.
+    fn derive_scalar(seed: &[u8], dst: &[u8]) -> Option<Self::Scalar> {
+        use sha2::Digest;
+        let bytes = Secret::new(<[u8; 32]>::from(sha2::Sha256::new().chain_update(dst).chain_update(seed).finalize()));
+        Some(x25519_dalek::StaticSecret::from(*bytes))
+    }
+
+    fn scalar_to_bytes(scalar: &Self::Scalar) -> Secret<[u8; 32]> {
+        Secret::new(scalar.to_bytes())
+    }
+
+    fn scalar_from_bytes(bytes: &[u8; 32]) -> Option<Self::Scalar> {
+        Some(x25519_dalek::StaticSecret::from(*bytes))
+    }
+
+    fn public_key(scalar: &Self::Scalar) -> Self::Element {
+        x25519_dalek::PublicKey::from(scalar)
+    }
+
+    fn dh(scalar: &Self::Scalar, element: &Self::Element) -> Secret<Vec<u8>> {
+        Secret::new(scalar.diffie_hellman(element).as_bytes().to_vec())
+    }
+
+    fn encode(element: &Self::Element) -> Vec<u8> {
+        element.as_bytes().to_vec()
+    }
+
+    /// Clamped scalars are multiples of the cofactor, so any of them maps exactly the
+    /// small-order u-coordinates to zero.
+    fn decode(bytes: &[u8]) -> Option<Self::Element> {
+        let bytes = <[u8; 32]>::try_from(bytes).ok()?;
+        if x25519_dalek::x25519([1u8; 32], bytes) == [0u8; 32] {
+            return None;
+        }
+        Some(x25519_dalek::PublicKey::from(bytes))
+    }
+}
+
+/// ristretto255 (RFC 9496), the prime-order group built on Curve25519.
+#[cfg(feature = "ristretto255")]
+pub struct Ristretto255;
+
+#[cfg(feature = "ristretto255")]
+impl DhGroup for Ristretto255 {
+    type Scalar = curve25519_dalek::Scalar;
+    type Element = curve25519_dalek::RistrettoPoint;
+
+    const NAME: &'static str = "ristretto255";
+    const ELEMENT_LEN: usize = 32;
+
+    fn random_scalar() -> Self::Scalar {
+        curve25519_dalek::Scalar::random(&mut rand_core::OsRng)
+    }
+
+    fn derive_scalar(seed: &[u8], dst: &[u8]) -> Option<Self::Scalar> {
+        use sha2::Digest;
+        let wide = Secret::new(<[u8; 64]>::from(sha2::Sha512::new().chain_update(dst).chain_update(seed).finalize()));
+        Some(curve25519_dalek::Scalar::from_bytes_mod_order_wide(&wide)).filter(|scalar| *scalar != curve25519_dalek::Scalar::ZERO)
+    }
+
+    fn scalar_to_bytes(scalar: &Self::Scalar) -> Secret<[u8; 32]> {
+        Secret::new(scalar.to_bytes())
+    }
+
+    fn scalar_from_bytes(bytes: &[u8; 32]) -> Option<Self::Scalar> {
+        curve25519_dalek::Scalar::from_canonical_bytes(*bytes).into()
+    }
+
+    fn public_key(scalar: &Self::Scalar) -> Self::Element {
+        curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT * scalar
+    }
+
+    fn dh(scalar: &Self::Scalar, element: &Self::Element) -> Secret<Vec<u8>> {
+        Secret::new(Self::encode(&(element * scalar)))
+    }
+
+    fn encode(element: &Self::Element) -> Vec<u8> {
+        element.compress().to_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self::Element> {
+        use curve25519_dalek::traits::Identity;
+        let element = curve25519_dalek::ristretto::CompressedRistretto::from_slice(bytes).ok()?.decompress()?;
+        Some(element).filter(|element| *element != curve25519_dalek::RistrettoPoint::identity())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides_agree_and_encodings_round_trip() {
+        let (a, b) = (Group::random_scalar(), Group::random_scalar());
+        let (large_a, large_b) = (Group::public_key(&a), Group::public_key(&b));
+        assert!(Group::dh(&a, &large_b) == Group::dh(&b, &large_a));
+
+        let encoded = Group::encode(&large_a);
+        assert_eq!(encoded.len(), ELEMENT_LEN);
+        assert_eq!(Group::decode(&encoded), Some(large_a));
+        assert_eq!(Group::decode(&encoded[1..]), None);
+        assert_eq!(Group::decode(&[0u8; ELEMENT_LEN]), None);
+
+        let restored = Group::scalar_from_bytes(&Group::scalar_to_bytes(&a)).unwrap();
+        assert_eq!(Group::public_key(&restored), large_a);
+        let derived = Group::derive_scalar(b"seed", b"DST").unwrap();
+        assert_eq!(Group::public_key(&derived), Group::public_key(&Group::derive_scalar(b"seed", b"DST").unwrap()));
+    }
+}
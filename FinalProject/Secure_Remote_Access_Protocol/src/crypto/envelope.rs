@@ -0,0 +1,238 @@
+//! OPAQUE envelope and credential response masking (RFC 9807, Sections 4 and 6.1).
+//!
+//! The envelope only carries a nonce and an authentication tag. The client key pair is
+//! re-derived from the randomized password, and the server public key is sent masked
+//! under a key that only the holder of the password can compute.
+
+use crate::crypto::dh_group::{self, DhElement, DhGroup, DhScalar, Group};
+use crate::crypto::hmac::{compute_hmac, verify_hmac};
+use crate::crypto::key_schedule::{expand, Hkdfsha256};
+use crate::crypto::secret::{Secret, SecretKey};
+
+/// Length of the envelope and masking nonces.
+pub const NONCE_LEN: usize = 32;
+/// Length of the envelope authentication tag (HMAC-SHA3-256).
+pub const MAC_LEN: usize = 32;
+/// Length of an encoded envelope: nonce || auth_tag.
+pub const ENVELOPE_LEN: usize = NONCE_LEN + MAC_LEN;
+/// Length of the masked part of the credential response: server_public_key || envelope.
+pub const MASKED_RESPONSE_LEN: usize = dh_group::ELEMENT_LEN + ENVELOPE_LEN;
+
+/// Domain separation tag for deriving the client key pair from the envelope seed.
+const DERIVE_KEY_PAIR_DST: &[u8] = b"CRYPTOGRAPHY_ENGINEERING-OPAQUE-DeriveDiffieHellmanKeyPair";
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+    InvalidEncoding,
+    KeyDerivationError,
+    EnvelopeRecoveryError,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    pub nonce: [u8; NONCE_LEN],
+    pub auth_tag: [u8; MAC_LEN],
+}
+
+impl Envelope {
+    pub fn to_bytes(self) -> [u8; ENVELOPE_LEN] {
+        let mut out = [0u8; ENVELOPE_LEN];
+        out[..NONCE_LEN].copy_from_slice(&self.nonce);
+        out[NONCE_LEN..].copy_from_slice(&self.auth_tag);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        if bytes.len() != ENVELOPE_LEN {
+            return Err(EnvelopeError::InvalidEncoding);
+        }
+        Ok(Self {
+            nonce: bytes[..NONCE_LEN].try_into().unwrap(),
+            auth_tag: bytes[NONCE_LEN..].try_into().unwrap(),
+        })
+    }
+}
+
+/// Output of `store`, i.e. everything the server keeps for a registered user.
+pub struct StoredEnvelope {
+    pub envelope: Envelope,
+    pub client_public_key: DhElement,
+    pub masking_key: SecretKey,
+}
+
+/// Output of `recover`.
+pub struct RecoveredCredentials {
+    pub client_private_key: Secret<DhScalar>,
+    pub client_public_key: DhElement,
+}
+
+fn randomized_password_hk(randomized_password: &[u8]) -> Result<Hkdfsha256, EnvelopeError> {
+    Hkdfsha256::from_prk(randomized_password).map_err(|_| EnvelopeError::KeyDerivationError)
+}
+
+/// `masking_key = Expand(randomized_password, "MaskingKey", 32)`
+pub fn masking_key(randomized_password: &[u8]) -> Result<SecretKey, EnvelopeError> {
+    let hk = randomized_password_hk(randomized_password)?;
+    expand::<32>(&hk, b"MaskingKey").map_err(|_| EnvelopeError::KeyDerivationError)
+}
+
+/// Derives the client key pair from a 32-byte seed (`DeriveDiffieHellmanKeyPair`).
+fn derive_key_pair(seed: &[u8]) -> Result<(Secret<DhScalar>, DhElement), EnvelopeError> {
+    let sk = Secret::new(Group::derive_scalar(seed, DERIVE_KEY_PAIR_DST).ok_or(EnvelopeError::KeyDerivationError)?);
+    let pk = Group::public_key(&sk);
+    Ok((sk, pk))
+}
+
+/// `CreateCleartextCredentials`: every field is prefixed with its 2-byte length.
+fn cleartext_credentials(
+    server_public_key: &DhElement,
+    client_public_key: &DhElement,
+    server_identity: &[u8],
+    client_identity: &[u8],
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&Group::encode(server_public_key));
+    for identity in [server_identity, client_identity] {
+        out.extend_from_slice(&(identity.len() as u16).to_be_bytes());
+        out.extend_from_slice(identity);
+    }
+    out.extend_from_slice(&Group::encode(client_public_key));
+    out
+}
+
+/// Derives `(auth_key, client key pair)` for a given envelope nonce.
+fn envelope_keys(
+    randomized_password: &[u8],
+    nonce: &[u8; NONCE_LEN],
+) -> Result<(SecretKey, Secret<DhScalar>, DhElement), EnvelopeError> {
+    let hk = randomized_password_hk(randomized_password)?;
+    let auth_key = expand::<32>(&hk, &[nonce.as_slice(), b"AuthKey"].concat())
+        .map_err(|_| EnvelopeError::KeyDerivationError)?;
+    let seed = expand::<32>(&hk, &[nonce.as_slice(), b"PrivateKey"].concat())
+        .map_err(|_| EnvelopeError::KeyDerivationError)?;
+    let (client_private_key, client_public_key) = derive_key_pair(seed.as_slice())?;
+    Ok((auth_key, client_private_key, client_public_key))
+}
+
+/// `Store`: creates a fresh envelope for `randomized_password`.
+pub fn store(
+    randomized_password: &[u8],
+    nonce: [u8; NONCE_LEN],
+    server_public_key: &DhElement,
+    server_identity: &[u8],
+    client_identity: &[u8],
+) -> Result<StoredEnvelope, EnvelopeError> {
+    let (auth_key, _, client_public_key) = envelope_keys(randomized_password, &nonce)?;
+    let cleartext = cleartext_credentials(server_public_key, &client_public_key, server_identity, client_identity);
+    let auth_tag = compute_hmac(auth_key.as_slice(), &[nonce.as_slice(), &cleartext].concat());
+
+    Ok(StoredEnvelope {
+        envelope: Envelope { nonce, auth_tag: auth_tag.try_into().unwrap() },
+        client_public_key,
+        masking_key: masking_key(randomized_password)?,
+    })
+}
+
+/// `Recover`: re-derives the client key pair and checks the envelope's auth tag.
+pub fn recover(
+    randomized_password: &[u8],
+    envelope: &Envelope,
+    server_public_key: &DhElement,
+    server_identity: &[u8],
+    client_identity: &[u8],
+) -> Result<RecoveredCredentials, EnvelopeError> {
+    let (auth_key, client_private_key, client_public_key) =
+        envelope_keys(randomized_password, &envelope.nonce)?;
+    let cleartext = cleartext_credentials(server_public_key, &client_public_key, server_identity, client_identity);
+    if !verify_hmac(auth_key.as_slice(), &[envelope.nonce.as_slice(), &cleartext].concat(), &envelope.auth_tag) {
+        return Err(EnvelopeError::EnvelopeRecoveryError);
+    }
+
+    Ok(RecoveredCredentials { client_private_key, client_public_key })
+}
+
+/// `credential_response_pad = Expand(masking_key, masking_nonce || "CredentialResponsePad", len)`
+fn credential_response_pad(
+    masking_key: &[u8],
+    masking_nonce: &[u8; NONCE_LEN],
+) -> Result<Secret<[u8; MASKED_RESPONSE_LEN]>, EnvelopeError> {
+    let hk = Hkdfsha256::from_prk(masking_key).map_err(|_| EnvelopeError::KeyDerivationError)?;
+    expand::<MASKED_RESPONSE_LEN>(&hk, &[masking_nonce.as_slice(), b"CredentialResponsePad"].concat())
+        .map_err(|_| EnvelopeError::KeyDerivationError)
+}
+
+/// Server side: masks `server_public_key || envelope` for the credential response.
+pub fn mask_response(
+    masking_key: &[u8],
+    masking_nonce: &[u8; NONCE_LEN],
+    server_public_key: &DhElement,
+    envelope: &Envelope,
+) -> Result<[u8; MASKED_RESPONSE_LEN], EnvelopeError> {
+    let mut masked = *credential_response_pad(masking_key, masking_nonce)?;
+    let plain = [Group::encode(server_public_key).as_slice(), &envelope.to_bytes()].concat();
+    for (m, p) in masked.iter_mut().zip(plain) {
+        *m ^= p;
+    }
+    Ok(masked)
+}
+
+/// Client side: recovers `server_public_key` and the envelope from a masked response.
+pub fn unmask_response(
+    masking_key: &[u8],
+    masking_nonce: &[u8; NONCE_LEN],
+    masked_response: &[u8],
+) -> Result<(DhElement, Envelope), EnvelopeError> {
+    if masked_response.len() != MASKED_RESPONSE_LEN {
+        return Err(EnvelopeError::InvalidEncoding);
+    }
+    let mut plain = *credential_response_pad(masking_key, masking_nonce)?;
+    for (p, m) in plain.iter_mut().zip(masked_response) {
+        *p ^= m;
+    }
+    let (server_public_key_bytes, envelope_bytes) = plain.split_at(dh_group::ELEMENT_LEN);
+    let server_public_key = Group::decode(server_public_key_bytes).ok_or(EnvelopeError::EnvelopeRecoveryError)?;
+    Ok((server_public_key, Envelope::from_bytes(envelope_bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::{OsRng, RngCore};
+
+    fn setup() -> ([u8; 32], [u8; NONCE_LEN], DhElement) {
+        let mut randomized_password = [0u8; 32];
+        OsRng.fill_bytes(&mut randomized_password);
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let server_public_key = Group::public_key(&Group::random_scalar());
+        (randomized_password, nonce, server_public_key)
+    }
+
+    #[test]
+    fn store_and_recover_round_trip() {
+        let (rwd, nonce, pk_s) = setup();
+        let stored = store(&rwd, nonce, &pk_s, b"Google", b"alice").unwrap();
+
+        let mut masking_nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut masking_nonce);
+        let masked = mask_response(stored.masking_key.as_slice(), &masking_nonce, &pk_s, &stored.envelope).unwrap();
+        let (unmasked_pk_s, envelope) = unmask_response(masking_key(&rwd).unwrap().as_slice(), &masking_nonce, &masked).unwrap();
+        assert_eq!(unmasked_pk_s, pk_s);
+        assert_eq!(envelope, stored.envelope);
+
+        let recovered = recover(&rwd, &envelope, &pk_s, b"Google", b"alice").unwrap();
+        assert_eq!(recovered.client_public_key, stored.client_public_key);
+        assert_eq!(Group::public_key(&recovered.client_private_key), stored.client_public_key);
+    }
+
+    #[test]
+    fn recover_rejects_wrong_password_and_identity() {
+        let (rwd, nonce, pk_s) = setup();
+        let stored = store(&rwd, nonce, &pk_s, b"Google", b"alice").unwrap();
+
+        let mut wrong_rwd = rwd;
+        wrong_rwd[0] ^= 0x01;
+        assert!(recover(&wrong_rwd, &stored.envelope, &pk_s, b"Google", b"alice").is_err());
+        assert!(recover(&rwd, &stored.envelope, &pk_s, b"Google", b"mallory").is_err());
+    }
+}
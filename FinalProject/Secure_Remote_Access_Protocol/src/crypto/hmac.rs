@@ -1,3 +1,4 @@
+use crate::crypto::secret::SecretKey;
 use hmac::{Hmac, Mac};
 use sha3::Sha3_256;
 
@@ -11,6 +12,14 @@ pub fn compute_hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
     mac.finalize().into_bytes().to_vec()
 }
 
+/// Computes HMAC-SHA3-256 of `label` under `key` for use as key material,
+/// e.g. the chain and message keys of the double ratchet.
+pub fn derive_key(key: &[u8], label: &[u8]) -> SecretKey {
+    let mut mac = HmacSha3_256::new_from_slice(key).unwrap();
+    mac.update(label);
+    SecretKey::new(mac.finalize().into_bytes().into())
+}
+
 /// Verifies a given HMAC tag (constant-time comparison).
 pub fn verify_hmac(key: &[u8], message: &[u8], tag: &[u8]) -> bool {
     if tag.len() != 32 {
@@ -33,6 +42,12 @@ mod tests {
         assert!(verify_hmac(key, msg, &tag));
     }
 
+    #[test]
+    fn derive_key_matches_hmac() {
+        let key = derive_key(b"secretkey", b"ChainKey");
+        assert_eq!(key.as_slice(), compute_hmac(b"secretkey", b"ChainKey").as_slice());
+    }
+
     #[test]
     fn hmac_rejects_wrong_tag() {
         let key = b"secretkey";
@@ -1,5 +1,7 @@
+use crate::crypto::secret::{Secret, SecretKey};
 use hkdf::Hkdf;
 use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
 
 /// Type HKDF-SHA256
 pub type Hkdfsha256 = Hkdf<Sha256>;
@@ -8,27 +10,30 @@ const KEY_LEN: usize = 32;
 /// Extract: returns (PRK bytes, HKDF object primed with PRK)
 /// - `salt`: None uses all-zero salt per RFC 5869.
 /// - return (prk, hk). The hk (equiped with prf) can be used to expand
-pub fn extract(salt: Option<&[u8]>, ikm: &[u8]) -> (hmac::digest::Output<Sha256>, Hkdfsha256) {
-    Hkdf::<Sha256>::extract(salt, ikm)
+pub fn extract(salt: Option<&[u8]>, ikm: &[u8]) -> (SecretKey, Hkdfsha256) {
+    let (mut prk, hk) = Hkdf::<Sha256>::extract(salt, ikm);
+    let secret_prk = SecretKey::new(prk.into());
+    prk.zeroize();
+    (secret_prk, hk)
 }
 
 /// Expand into a fixed-size array (nice for keys/IVs).
-pub fn expand<const N: usize>(hk: &Hkdfsha256, info: &[u8]) -> Result<[u8; N], hkdf::InvalidLength> {
-    let mut out = [0u8; N];
-    hk.expand(info, &mut out)?;
+pub fn expand<const N: usize>(hk: &Hkdfsha256, info: &[u8]) -> Result<Secret<[u8; N]>, hkdf::InvalidLength> {
+    let mut out = Secret::new([0u8; N]);
+    hk.expand(info, out.as_mut_slice())?;
     Ok(out)
 }
 
 
-pub fn derive_hs(shared_key: &[u8]) -> (hmac::digest::Output<Sha256>, Hkdfsha256) {
+pub fn derive_hs(shared_key: &[u8]) -> (SecretKey, Hkdfsha256) {
     let zero = [0u8; KEY_LEN];
     let (_es_prk, es_hk) = extract(Some(&zero), &zero);
     let d_es = expand::<KEY_LEN>(&es_hk, &Sha256::digest(b"DerivedES")).unwrap();
-    let (hs_prk, hs_hk) = extract(Some(&d_es), Sha256::digest(shared_key).as_slice());
+    let (hs_prk, hs_hk) = extract(Some(d_es.as_slice()), Sha256::digest(shared_key).as_slice());
     (hs_prk, hs_hk)
 }
 
-pub fn key_schedule_1(shared_key: &[u8]) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+pub fn key_schedule_1(shared_key: &[u8]) -> (SecretKey, SecretKey) {
     let (_, hs_hk) = derive_hs(shared_key);
     let k_c = expand::<KEY_LEN>(&hs_hk, b"ClientKE").unwrap();
     let k_s = expand::<KEY_LEN>(&hs_hk, b"ServerKE").unwrap();
@@ -41,7 +46,7 @@ pub fn key_schedule_2(
     nonce_s: &[u8],
     pk_s: &[u8],
     shared_key: &[u8],
-) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+) -> (SecretKey, SecretKey) {
     let (_, hs_hk) = derive_hs(shared_key);
 
     let mut buf = Vec::new();
@@ -72,11 +77,11 @@ pub fn key_schedule_3(
     sign: &[u8],
     cert_pk_s: &[u8],
     mac_s: &[u8],
-) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+) -> (SecretKey, SecretKey) {
     let (_, hs_hk) = derive_hs(shared_key);
     let d_hs = expand::<KEY_LEN>(&hs_hk, &Sha256::digest(b"DerivedHS")).unwrap();
     let zero = [0u8; KEY_LEN];
-    let (_, ms_hk) = extract(Some(&d_hs), &zero);
+    let (_, ms_hk) = extract(Some(d_hs.as_slice()), &zero);
 
     let mut buf = Vec::new();
     buf.extend_from_slice(nonce_c);
@@ -99,4 +104,18 @@ pub fn key_schedule_3(
     let k_c = expand::<KEY_LEN>(&ms_hk, &client_skh).unwrap();
     let k_s = expand::<KEY_LEN>(&ms_hk, &server_skh).unwrap();
     (k_c, k_s)
+}
+
+/// Key protecting account management requests (ChangePassword, DeleteAccount),
+/// derived from the 3DH session key `sk` so only the authenticated client can use it.
+pub fn account_key(sk: &[u8]) -> SecretKey {
+    let (_, hk) = extract(None, sk);
+    expand::<KEY_LEN>(&hk, b"AccountManagement").unwrap()
+}
+
+/// Secret for resuming a session without the password, derived from the 3DH session key
+/// `sk`. Google seals it into the ticket it issues after the login.
+pub fn resumption_secret(sk: &[u8]) -> SecretKey {
+    let (_, hk) = extract(None, sk);
+    expand::<KEY_LEN>(&hk, b"Resumption").unwrap()
 }
\ No newline at end of file
@@ -0,0 +1,174 @@
+//! Key-stretching functions (KSF) applied to the OPRF output before the envelope key
+//! is derived, so that a stolen user database cannot be attacked cheaply offline.
+//!
+//! The parameters are chosen by the server at registration, stored per user and sent
+//! to the client in the login response (see `to_bytes` / `from_bytes`).
+
+use crate::crypto::secret::Secret;
+use argon2::{Algorithm, Argon2, Params, Version};
+
+/// Output length of the stretched password.
+const OUTPUT_LEN: usize = 32;
+/// The OPRF output is already unique per user and password, so a fixed salt is used.
+const SALT: [u8; 16] = [0u8; 16];
+
+/// Length of the encoded parameters: tag (1) || three u32 parameters (3 * 4).
+pub const PARAMS_LEN: usize = 13;
+
+/// Upper bounds on the parameters a client accepts, so a server cannot make it allocate
+/// or compute without limit. Memory is capped at 1 GiB for both functions.
+pub const MAX_MEMORY: u64 = 1 << 30;
+pub const MAX_ARGON2_T_COST: u32 = 10;
+pub const MAX_ARGON2_P_COST: u32 = 16;
+pub const MAX_SCRYPT_LOG_N: u8 = 20;
+pub const MAX_SCRYPT_R: u32 = 32;
+pub const MAX_SCRYPT_P: u32 = 4;
+
+#[derive(Debug)]
+pub enum KsfError {
+    InvalidEncoding,
+    InvalidParams,
+    StretchError,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyStretching {
+    /// No stretching (only for testing).
+    Identity,
+    /// Argon2id with memory cost `m_cost` (KiB), `t_cost` iterations and `p_cost` lanes.
+    Argon2id { m_cost: u32, t_cost: u32, p_cost: u32 },
+    /// scrypt with cost `2^log_n`, block size `r` and parallelism `p`.
+    Scrypt { log_n: u8, r: u32, p: u32 },
+}
+
+impl Default for KeyStretching {
+    /// Argon2id with the OWASP recommended minimum (19 MiB, 2 iterations, 1 lane).
+    fn default() -> Self {
+        KeyStretching::Argon2id {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KeyStretching {
+    /// Stretches `input` into a 32-byte output.
+    pub fn stretch(&self, input: &[u8]) -> Result<Secret<Vec<u8>>, KsfError> {
+        match *self {
+            KeyStretching::Identity => Ok(Secret::new(input.to_vec())),
+            KeyStretching::Argon2id { m_cost, t_cost, p_cost } => {
+                let params = Params::new(m_cost, t_cost, p_cost, Some(OUTPUT_LEN))
+                    .map_err(|_| KsfError::InvalidParams)?;
+                let mut out = Secret::new(vec![0u8; OUTPUT_LEN]);
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(input, &SALT, &mut out)
+                    .map_err(|_| KsfError::StretchError)?;
+                Ok(out)
+            }
+            KeyStretching::Scrypt { log_n, r, p } => {
+                let params = scrypt::Params::new(log_n, r, p, OUTPUT_LEN)
+                    .map_err(|_| KsfError::InvalidParams)?;
+                let mut out = Secret::new(vec![0u8; OUTPUT_LEN]);
+                scrypt::scrypt(input, &SALT, &params, &mut out)
+                    .map_err(|_| KsfError::StretchError)?;
+                Ok(out)
+            }
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; PARAMS_LEN] {
+        let (tag, a, b, c) = match self {
+            KeyStretching::Identity => (0u8, 0, 0, 0),
+            KeyStretching::Argon2id { m_cost, t_cost, p_cost } => (1u8, m_cost, t_cost, p_cost),
+            KeyStretching::Scrypt { log_n, r, p } => (2u8, log_n as u32, r, p),
+        };
+        let mut out = [0u8; PARAMS_LEN];
+        out[0] = tag;
+        out[1..5].copy_from_slice(&a.to_be_bytes());
+        out[5..9].copy_from_slice(&b.to_be_bytes());
+        out[9..13].copy_from_slice(&c.to_be_bytes());
+        out
+    }
+
+    /// Whether the parameters stay within the `MAX_*` bounds.
+    pub fn is_bounded(&self) -> bool {
+        match *self {
+            KeyStretching::Identity => true,
+            KeyStretching::Argon2id { m_cost, t_cost, p_cost } => {
+                u64::from(m_cost) * 1024 <= MAX_MEMORY && t_cost <= MAX_ARGON2_T_COST && p_cost <= MAX_ARGON2_P_COST
+            }
+            KeyStretching::Scrypt { log_n, r, p } => {
+                log_n <= MAX_SCRYPT_LOG_N
+                    && r <= MAX_SCRYPT_R
+                    && p <= MAX_SCRYPT_P
+                    && (128 * u64::from(r)) << log_n <= MAX_MEMORY
+            }
+        }
+    }
+
+    /// Decodes parameters sent by the server, refusing those beyond the `MAX_*` bounds.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, KsfError> {
+        if bytes.len() != PARAMS_LEN {
+            return Err(KsfError::InvalidEncoding);
+        }
+        let a = u32::from_be_bytes(bytes[1..5].try_into().unwrap());
+        let b = u32::from_be_bytes(bytes[5..9].try_into().unwrap());
+        let c = u32::from_be_bytes(bytes[9..13].try_into().unwrap());
+        let ksf = match bytes[0] {
+            0 => KeyStretching::Identity,
+            1 => KeyStretching::Argon2id { m_cost: a, t_cost: b, p_cost: c },
+            2 => {
+                let log_n = u8::try_from(a).map_err(|_| KsfError::InvalidEncoding)?;
+                KeyStretching::Scrypt { log_n, r: b, p: c }
+            }
+            _ => return Err(KsfError::InvalidEncoding),
+        };
+        if !ksf.is_bounded() {
+            return Err(KsfError::InvalidParams);
+        }
+        Ok(ksf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_round_trip() {
+        for ksf in [
+            KeyStretching::Identity,
+            KeyStretching::default(),
+            KeyStretching::Scrypt { log_n: 10, r: 8, p: 1 },
+        ] {
+            assert_eq!(KeyStretching::from_bytes(&ksf.to_bytes()).unwrap(), ksf);
+        }
+    }
+
+    #[test]
+    fn unbounded_params_are_refused() {
+        for ksf in [
+            KeyStretching::Argon2id { m_cost: u32::MAX, t_cost: 2, p_cost: 1 },
+            KeyStretching::Argon2id { m_cost: 19 * 1024, t_cost: u32::MAX, p_cost: 1 },
+            KeyStretching::Argon2id { m_cost: 19 * 1024, t_cost: 2, p_cost: 255 },
+            KeyStretching::Scrypt { log_n: 63, r: 8, p: 1 },
+            KeyStretching::Scrypt { log_n: 20, r: 32, p: 1 },
+            KeyStretching::Scrypt { log_n: 10, r: 8, p: u32::MAX },
+        ] {
+            assert!(matches!(KeyStretching::from_bytes(&ksf.to_bytes()), Err(KsfError::InvalidParams)));
+        }
+        assert!(KeyStretching::Argon2id { m_cost: 1 << 20, t_cost: 10, p_cost: 16 }.is_bounded());
+    }
+
+    #[test]
+    fn stretch_is_deterministic_and_parameter_bound() {
+        let argon2 = KeyStretching::Argon2id { m_cost: 64, t_cost: 1, p_cost: 1 };
+        let scrypt = KeyStretching::Scrypt { log_n: 4, r: 8, p: 1 };
+        let out = argon2.stretch(b"oprf output").unwrap();
+        assert_eq!(out.len(), OUTPUT_LEN);
+        assert!(out == argon2.stretch(b"oprf output").unwrap());
+        assert!(out != scrypt.stretch(b"oprf output").unwrap());
+        assert!(out != argon2.stretch(b"other output").unwrap());
+    }
+}
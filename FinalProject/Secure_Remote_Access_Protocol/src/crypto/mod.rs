@@ -2,4 +2,10 @@ pub mod participant;
 pub mod hash2curve;
 pub mod key_schedule;
 pub mod hmac;
-pub mod aead;
\ No newline at end of file
+pub mod aead;
+pub mod voprf;
+pub mod ksf;
+pub mod envelope;
+pub mod totp;
+pub mod secret;
+pub mod dh_group;
\ No newline at end of file
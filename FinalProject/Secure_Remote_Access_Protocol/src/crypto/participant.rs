@@ -1,17 +1,35 @@
 use ml_dsa::{signature::Signer, KeyGen, KeyPair, MlDsa65, Seed, Signature, VerifyingKey};
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use sha2::{Digest, Sha256};
 use std::sync::Arc;
-use elliptic_curve::{ProjectivePoint, Scalar};
+use crate::crypto::dh_group::DhElement;
+use crate::crypto::envelope::Envelope;
+use crate::crypto::ksf::KeyStretching;
+use crate::crypto::secret::{Secret, SecretKey};
+use crate::crypto::totp;
+use crate::server::rate_limit::AttemptCounter;
+use crate::handshake::{AlertDescription, Event, Handshake, HandshakeError};
+use crate::transport::{Transport, TransportError};
+
+/// Version of the wire format and the protocol, sent in every frame header.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Versions this build can decode and negotiate, most preferred first.
+pub const SUPPORTED_VERSIONS: &[u16] = &[PROTOCOL_VERSION];
+/// Version in the header of the hellos and of the frames sent before them. Every build
+/// accepts it, so a peer with newer versions is still heard until the hellos negotiate.
+pub const HELLO_VERSION: u16 = 1;
 
 #[derive(Serialize, Deserialize)]
 pub enum Message {
     PqtlsClientHello {
+        /// Protocol versions Alice offers, most preferred first.
+        versions: Vec<u16>,
         nonce_c: Vec<u8>,
         ek: Vec<u8>,
     },
     PqtlsServerHello {
+        /// The version Google picked from the offered ones.
+        version: u16,
         nonce_s: Vec<u8>,
         ct: Vec<u8>,
         verifying_key: Vec<u8>,
@@ -23,16 +41,89 @@ pub enum Message {
     SimplePayload {
         payload: Vec<u8>,
     },
-    Reset {},
+    /// An `AlertDescription` code, sent in the clear before the sender derived k3.
+    Alert {
+        description: u8,
+    },
+    /// The same under the sender's k3.
+    EncryptedAlert {
+        nonce: [u8; 12],
+        aead_payload: Vec<u8>,
+    },
+    /// A keepalive request or its answer under the sender's k3, see `handshake::heartbeat`.
+    Heartbeat {
+        nonce: [u8; 12],
+        aead_payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// Type byte in the frame header.
+    pub fn message_type(&self) -> u8 {
+        match self {
+            Message::PqtlsClientHello { .. } => 1,
+            Message::PqtlsServerHello { .. } => 2,
+            Message::AeadCiphertext { .. } => 3,
+            Message::SimplePayload { .. } => 4,
+            Message::Alert { .. } => 5,
+            Message::EncryptedAlert { .. } => 6,
+            Message::Heartbeat { .. } => 7,
+        }
+    }
+
+    /// Whether `message_type` names a message of this version.
+    pub fn is_known_type(message_type: u8) -> bool {
+        (1..=7).contains(&message_type)
+    }
+
+    /// Whether `message_type` names one of the hellos, which are sent before a version
+    /// is negotiated.
+    pub fn is_hello_type(message_type: u8) -> bool {
+        matches!(message_type, 1 | 2)
+    }
+
+    /// Whether `message_type` names an alert sent in the clear.
+    pub fn is_alert_type(message_type: u8) -> bool {
+        message_type == 5
+    }
 }
 
+/// Server identity bound into every OPAQUE envelope.
+pub const SERVER_IDENTITY: &[u8] = b"Google";
+
+/// Status replies for the account operations (Register, ChangePassword, DeleteAccount).
+pub const STATUS_OK: &[u8] = b"OK";
+pub const STATUS_USER_EXISTS: &[u8] = b"Error;UserExists";
+pub const STATUS_FAILED: &[u8] = b"Error;Failed";
+/// Sent instead of the login response, followed by `;` and the seconds to wait.
+pub const STATUS_RATE_LIMITED: &[u8] = b"Error;RateLimited";
+pub const STATUS_LOCKED: &[u8] = b"Error;Locked";
+/// Sent after key confirmation in place of `STATUS_OK` when the user enrolled a TOTP secret.
+pub const STATUS_TOTP_REQUIRED: &[u8] = b"TotpRequired";
+pub const STATUS_INVALID_TOTP: &[u8] = b"Error;InvalidTotp";
+/// Sent once the login succeeded, followed by the resumption ticket.
+pub const STATUS_TICKET: &[u8] = b"Ticket;";
+/// Sent instead of the ephemeral key when Google does not accept a resumption ticket.
+pub const STATUS_RESUME_REFUSED: &[u8] = b"Error;ResumeRefused";
+/// Sent after key confirmation, before the other statuses, when the envelope was created
+/// for an older AKE key, followed by the current key's ID and public key.
+pub const STATUS_REWRAP: &[u8] = b"Rewrap;";
+
+#[derive(Clone)]
 pub struct DatabaseContent {
-    pub salt: Scalar<k256::Secp256k1>,
-    pub lpk_c: ProjectivePoint<k256::Secp256k1>,
-    pub lpk_s: ProjectivePoint<k256::Secp256k1>,
-    pub lsk_s: Scalar<k256::Secp256k1>,
-    pub aead_nonce: [u8; 12],
-    pub enc_client_keys: Vec<u8>
+    pub lpk_c: DhElement,
+    /// ID of Google's AKE key the envelope was created for, see `server::ake_keys`.
+    pub key_id: u32,
+    pub masking_key: SecretKey,
+    pub envelope: Envelope,
+    pub ksf: KeyStretching,
+    pub attempts: AttemptCounter,
+    pub totp_secret: Option<Secret<[u8; totp::SECRET_LEN]>>,
+    /// Time step of the last accepted TOTP code, to reject replays.
+    pub totp_last_step: Option<u64>,
+    /// Drawn at random for every registration and password change. Resumption tickets
+    /// carry it, so they only resume into the record they were issued for.
+    pub generation: u64,
 }
 
 pub struct User {
@@ -41,36 +132,140 @@ pub struct User {
 
 impl User {
 
-    pub fn send_bytes(stream: &mut TcpStream, msg: &Message) {
-        let data = bincode::serialize(msg).unwrap();
-        let len = (data.len() as u32).to_be_bytes();
+    /// Sends `msg` to the peer in a frame of the version `handshake` negotiated.
+    pub async fn send_bytes(stream: &mut impl Transport, handshake: &impl Handshake, msg: &Message) -> Result<(), TransportError> {
+        stream.send_versioned(msg, handshake.version()).await
+    }
+
+    pub async fn recv_bytes(stream: &mut impl Transport, handshake: &impl Handshake) -> Result<Message, TransportError> {
+        stream.recv_versioned(handshake.version()).await
+    }
+
+    /// Sends every message `handshake` has queued.
+    pub async fn flush(stream: &mut impl Transport, handshake: &mut impl Handshake) -> Result<(), TransportError> {
+        while let Some(msg) = handshake.poll_transmit() {
+            User::send_bytes(stream, handshake, &msg).await?;
+        }
+        Ok(())
+    }
 
-        stream.write_all(&len).unwrap();
-        stream.write_all(&data).unwrap();
+    /// Runs `handshake` over `stream` until it reports the next event. Errors of the
+    /// handshake are handled as by `fail`, the outer error ends the connection.
+    pub async fn drive(stream: &mut impl Transport, handshake: &mut impl Handshake) -> Result<Result<Event, HandshakeError>, TransportError> {
+        loop {
+            User::flush(stream, handshake).await?;
+            if let Some(event) = handshake.poll_event() {
+                return Ok(Ok(event));
+            }
+            let msg = User::recv_bytes(stream, handshake).await?;
+            if let Err(e) = handshake.handle(msg) {
+                return Ok(Err(User::fail(stream, handshake, e).await?));
+            }
+        }
     }
 
-    pub fn recv_bytes(stream: &mut TcpStream) -> Message {
-        let mut len_buf = [0u8; 4];
-        stream.read_exact(&mut len_buf).unwrap();
-        let len = u32::from_be_bytes(len_buf);
+    /// Receives a message that travels under the keys of `handshake` and decrypts it.
+    /// Errors are handled as by `drive`.
+    pub async fn recv_sealed(stream: &mut impl Transport, handshake: &mut impl Handshake) -> Result<Result<Vec<u8>, HandshakeError>, TransportError> {
+        let msg = User::recv_bytes(stream, handshake).await?;
+        match handshake.open(msg) {
+            Ok(plaintext) => Ok(Ok(plaintext)),
+            Err(e) => Ok(Err(User::fail(stream, handshake, e).await?)),
+        }
+    }
 
-        let mut buf = vec![0u8; len as usize];
-        stream.read_exact(&mut buf).unwrap();
+    /// Ends the session of `handshake` with `description` and sends the alert.
+    pub async fn send_alert(stream: &mut impl Transport, handshake: &mut impl Handshake, description: AlertDescription) -> Result<(), TransportError> {
+        handshake.alert(description);
+        User::flush(stream, handshake).await
+    }
 
-        bincode::deserialize(&buf).unwrap()
+    /// Ends the session of `handshake` with close_notify and waits for the peer's. What the
+    /// peer sent before it saw the close_notify is dropped.
+    pub async fn close(stream: &mut impl Transport, handshake: &mut impl Handshake) -> Result<(), TransportError> {
+        User::send_alert(stream, handshake, AlertDescription::CloseNotify).await?;
+        loop {
+            let msg = User::recv_bytes(stream, handshake).await?;
+            match handshake.handle(msg) {
+                Err(HandshakeError::Alert(AlertDescription::CloseNotify)) => return Ok(()),
+                Err(HandshakeError::Alert(description)) => return Err(TransportError::Alert(description)),
+                _ => continue,
+            }
+        }
     }
+
+    /// Handles an error of `handshake`. A fatal alert from the peer is returned as
+    /// `TransportError::Alert`, without answering it. A close_notify is answered and
+    /// returned, so the caller can tell a session the peer ended from a failed one. Any
+    /// other error is reported to the peer with the matching alert and returned.
+    pub async fn fail(stream: &mut impl Transport, handshake: &mut impl Handshake, e: HandshakeError) -> Result<HandshakeError, TransportError> {
+        if let HandshakeError::Alert(description) = e {
+            User::flush(stream, handshake).await?;
+            if description.is_fatal() {
+                return Err(TransportError::Alert(description));
+            }
+        }
+        if let Some(description) = e.alert() {
+            User::send_alert(stream, handshake, description).await?;
+        }
+        Ok(e)
+    }
+}
+
+/// Why a request of Alice or Google ended early.
+#[derive(Debug)]
+pub enum RequestError {
+    /// The request failed. The error was reported, and alerted to the peer, where it was
+    /// found, and the next request starts with a new handshake.
+    Failed,
+    /// The connection failed, or the peer ended the session with a fatal alert.
+    Transport(TransportError),
 }
 
+impl From<TransportError> for RequestError {
+    fn from(e: TransportError) -> Self {
+        RequestError::Transport(e)
+    }
+}
 
 #[derive(Clone)]
 pub struct CA {
     key_pair: Arc<KeyPair<MlDsa65>>,
+    /// Fingerprints of the server keys whose certificates were revoked.
+    revoked: Arc<Vec<[u8; 32]>>,
+}
+
+impl Default for CA {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CA {
     pub fn new() -> Self {
-        let kp = MlDsa65::from_seed(&Seed::default());
-        Self { key_pair: Arc::from(kp) }
+        Self::from_seed(&Seed::default().into())
+    }
+
+    /// The CA whose ML-DSA key pair is derived from `seed`.
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let kp = MlDsa65::from_seed(&Seed::from(*seed));
+        Self { key_pair: Arc::from(kp), revoked: Arc::default() }
+    }
+
+    /// Refuses the certificates of the server keys with these fingerprints.
+    pub fn with_revoked(mut self, revoked: Vec<[u8; 32]>) -> Self {
+        self.revoked = Arc::new(revoked);
+        self
+    }
+
+    /// SHA-256 of an encoded server verifying key, which names its certificate for revocation.
+    pub fn fingerprint(verifying_key: &[u8]) -> [u8; 32] {
+        Sha256::digest(verifying_key).into()
+    }
+
+    /// Whether the certificate of `verifying_key` was revoked.
+    pub fn is_revoked(&self, verifying_key: &[u8]) -> bool {
+        self.revoked.contains(&Self::fingerprint(verifying_key))
     }
 
     pub fn verifying_key(&self) -> &VerifyingKey<MlDsa65> {
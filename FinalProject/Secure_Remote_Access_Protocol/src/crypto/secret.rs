@@ -0,0 +1,100 @@
+//! Wrapper for secret key material.
+//!
+//! `Secret<T>` wipes its contents when dropped, has no `Debug` implementation so it
+//! cannot end up in a log by accident, and compares in constant time. It dereferences
+//! to `T`, so functions taking `&[u8; 32]` or `&[u8]` accept a `&SecretKey` directly.
+
+use std::ops::{Deref, DerefMut};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+/// A 32-byte symmetric key (AEAD, HMAC or KDF key, PRK).
+pub type SecretKey = Secret<[u8; 32]>;
+
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> DerefMut for Secret<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Secret(value)
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Secret(self.0.clone())
+    }
+}
+
+impl<T: Zeroize + Default> Default for Secret<T> {
+    fn default() -> Self {
+        Secret(T::default())
+    }
+}
+
+/// Constant-time comparison (the lengths are not hidden).
+impl<T: Zeroize + AsRef<[u8]>> PartialEq for Secret<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_ref().ct_eq(other.0.as_ref()).into()
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> Eq for Secret<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn secrets_compare_by_value() {
+        let key = SecretKey::new([7u8; 32]);
+        assert!(key == SecretKey::new([7u8; 32]));
+        assert!(key != SecretKey::new([8u8; 32]));
+        assert!(Secret::new(vec![1u8, 2]) != Secret::new(vec![1u8, 2, 3]));
+    }
+
+    /// Records whether it was zeroized.
+    struct Probe(Rc<Cell<bool>>);
+
+    impl Zeroize for Probe {
+        fn zeroize(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn drop_wipes_the_value() {
+        let wiped = Rc::new(Cell::new(false));
+        let secret = Secret::new(Probe(wiped.clone()));
+        assert!(!wiped.get());
+        drop(secret);
+        assert!(wiped.get());
+    }
+}
@@ -0,0 +1,84 @@
+//! Time-based one-time passwords (RFC 6238) on top of HOTP (RFC 4226).
+//!
+//! The HMAC is the crate's HMAC-SHA3-256 from `crypto::hmac` rather than HMAC-SHA-1, so
+//! codes are computed by `totp` below instead of a stock authenticator app.
+
+use crate::crypto::hmac::compute_hmac;
+use crate::crypto::secret::Secret;
+use rand_core::{OsRng, RngCore};
+
+/// Length of a TOTP secret (the RFC 4226 recommendation of 160 bits).
+pub const SECRET_LEN: usize = 20;
+/// Number of decimal digits in a code.
+pub const DIGITS: u32 = 6;
+/// Length of a time step in seconds.
+pub const TIME_STEP: u64 = 30;
+/// Number of time steps before and after the current one that are still accepted.
+pub const SKEW: u64 = 1;
+
+pub fn generate_secret() -> Secret<[u8; SECRET_LEN]> {
+    let mut secret = Secret::new([0u8; SECRET_LEN]);
+    OsRng.fill_bytes(secret.as_mut_slice());
+    secret
+}
+
+/// `HOTP(K, C) = Truncate(HMAC(K, C)) mod 10^DIGITS`
+pub fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mac = compute_hmac(secret, &counter.to_be_bytes());
+
+    // Dynamic truncation: the low nibble of the last byte selects a 31-bit window
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes(mac[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Time step of the unix time `unix_time`.
+pub fn time_step(unix_time: u64) -> u64 {
+    unix_time / TIME_STEP
+}
+
+/// The code for the unix time `unix_time`, zero-padded to `DIGITS` digits.
+pub fn totp(secret: &[u8], unix_time: u64) -> String {
+    format!("{:0width$}", hotp(secret, time_step(unix_time)), width = DIGITS as usize)
+}
+
+/// Checks `code` against the time steps around `unix_time`. Returns the matching time
+/// step, which must be later than `last_step` so that a code cannot be replayed.
+pub fn verify(secret: &[u8], code: &str, unix_time: u64, last_step: Option<u64>) -> Option<u64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let current = time_step(unix_time);
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .filter(|&step| last_step.is_none_or(|last| step > last))
+        .find(|&step| totp(secret, step * TIME_STEP) == code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_accepted_within_skew_only() {
+        let secret = generate_secret();
+        let now = 1_700_000_000;
+        let code = totp(secret.as_slice(), now);
+        assert_eq!(code.len(), DIGITS as usize);
+
+        assert_eq!(verify(secret.as_slice(), &code, now, None), Some(time_step(now)));
+        assert_eq!(verify(secret.as_slice(), &code, now + TIME_STEP, None), Some(time_step(now)));
+        assert_eq!(verify(secret.as_slice(), &code, now + 3 * TIME_STEP, None), None);
+        assert_eq!(verify(generate_secret().as_slice(), &code, now, None), None);
+    }
+
+    #[test]
+    fn used_codes_cannot_be_replayed() {
+        let secret = generate_secret();
+        let now = 1_700_000_000;
+        let code = totp(secret.as_slice(), now);
+
+        let step = verify(secret.as_slice(), &code, now, None).unwrap();
+        assert_eq!(verify(secret.as_slice(), &code, now, Some(step)), None);
+        assert!(verify(secret.as_slice(), &totp(secret.as_slice(), now + TIME_STEP), now + TIME_STEP, Some(step)).is_some());
+    }
+}
@@ -0,0 +1,160 @@
+//! Verifiable OPRF (VOPRF) helpers, following the DLEQ construction of RFC 9497.
+//!
+//! The server holds an OPRF key `k` and publishes `pk = G * k`. When it evaluates a
+//! blinded element `M` to `Z = M * k`, it attaches a proof that
+//! `log_G(pk) == log_M(Z)`, so the client can check that the expected key was used.
+
+use crate::crypto::secret::Secret;
+use elliptic_curve::group::GroupEncoding;
+use elliptic_curve::hash2curve::{ExpandMsgXmd, GroupDigest};
+use elliptic_curve::{Field, PrimeField};
+use k256::{ProjectivePoint, Scalar, Secp256k1};
+use rand_core::OsRng;
+use sha3::Sha3_256;
+
+/// Domain separation tag for the challenge hash.
+const CHALLENGE_DST: &[u8] = b"CRYPTOGRAPHY_ENGINEERING-VOPRF-DLEQ-Challenge";
+/// Domain separation tag for deriving OPRF keys from the server seed.
+const DERIVE_KEY_DST: &[u8] = b"CRYPTOGRAPHY_ENGINEERING-VOPRF-DeriveKeyPair";
+
+/// Length of an encoded element (compressed SEC1), whichever DH group the 3DH uses.
+pub const ELEMENT_LEN: usize = 33;
+/// Length of an encoded proof: challenge `c` || response `s`.
+pub const PROOF_LEN: usize = 64;
+
+#[derive(Debug)]
+pub enum VoprfError {
+    InvalidEncoding,
+    InvalidProof,
+    DeriveKeyPairError,
+}
+
+/// A non-interactive DLEQ proof `(c, s)`.
+#[derive(Clone, Copy, Debug)]
+pub struct DleqProof {
+    pub c: Scalar,
+    pub s: Scalar,
+}
+
+impl DleqProof {
+    pub fn to_bytes(self) -> [u8; PROOF_LEN] {
+        let mut out = [0u8; PROOF_LEN];
+        out[..32].copy_from_slice(&self.c.to_bytes());
+        out[32..].copy_from_slice(&self.s.to_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VoprfError> {
+        if bytes.len() != PROOF_LEN {
+            return Err(VoprfError::InvalidEncoding);
+        }
+        let c_bytes: [u8; 32] = bytes[..32].try_into().unwrap();
+        let s_bytes: [u8; 32] = bytes[32..].try_into().unwrap();
+        let c = Option::<Scalar>::from(Scalar::from_repr(c_bytes.into())).ok_or(VoprfError::InvalidEncoding)?;
+        let s = Option::<Scalar>::from(Scalar::from_repr(s_bytes.into())).ok_or(VoprfError::InvalidEncoding)?;
+        Ok(Self { c, s })
+    }
+}
+
+/// Derives the OPRF key for `credential_identifier` from the server-wide `seed`
+/// (the `DeriveKeyPair(seed, info)` step of RFC 9497, as used by OPAQUE).
+pub fn derive_key(seed: &[u8], credential_identifier: &[u8]) -> Result<Secret<Scalar>, VoprfError> {
+    let k = Secret::new(Secp256k1::hash_to_scalar::<ExpandMsgXmd<Sha3_256>>(&[seed, credential_identifier], &[DERIVE_KEY_DST])
+        .map_err(|_| VoprfError::DeriveKeyPairError)?);
+    if bool::from(k.is_zero()) {
+        return Err(VoprfError::DeriveKeyPairError);
+    }
+    Ok(k)
+}
+
+/// Returns the public key `G * k` for the OPRF key `k`.
+pub fn public_key(k: &Scalar) -> ProjectivePoint {
+    ProjectivePoint::GENERATOR * k
+}
+
+/// Computes the challenge `c = H(pk || M || Z || t2 || t3)`.
+fn challenge(
+    pk: &ProjectivePoint,
+    blinded: &ProjectivePoint,
+    evaluated: &ProjectivePoint,
+    t2: &ProjectivePoint,
+    t3: &ProjectivePoint,
+) -> Result<Scalar, VoprfError> {
+    let mut transcript = Vec::new();
+    for point in [pk, blinded, evaluated, t2, t3] {
+        transcript.extend_from_slice(&point.to_bytes());
+    }
+    Secp256k1::hash_to_scalar::<ExpandMsgXmd<Sha3_256>>(&[&transcript], &[CHALLENGE_DST])
+        .map_err(|_| VoprfError::InvalidProof)
+}
+
+/// Server side: proves that `evaluated == blinded * k` for the key behind `public_key(k)`.
+pub fn generate_proof(
+    k: &Scalar,
+    blinded: &ProjectivePoint,
+    evaluated: &ProjectivePoint,
+) -> Result<DleqProof, VoprfError> {
+    let pk = public_key(k);
+    let r = Scalar::random(&mut OsRng);
+    let t2 = ProjectivePoint::GENERATOR * r;
+    let t3 = *blinded * r;
+
+    let c = challenge(&pk, blinded, evaluated, &t2, &t3)?;
+    let s = r - c * k;
+    Ok(DleqProof { c, s })
+}
+
+/// Client side: checks that `evaluated` was computed with the key behind `pk`.
+pub fn verify_proof(
+    pk: &ProjectivePoint,
+    blinded: &ProjectivePoint,
+    evaluated: &ProjectivePoint,
+    proof: &DleqProof,
+) -> Result<(), VoprfError> {
+    let t2 = ProjectivePoint::GENERATOR * proof.s + *pk * proof.c;
+    let t3 = *blinded * proof.s + *evaluated * proof.c;
+
+    let expected_c = challenge(pk, blinded, evaluated, &t2, &t3)?;
+    if expected_c == proof.c {
+        Ok(())
+    } else {
+        Err(VoprfError::InvalidProof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elliptic_curve::Group;
+
+    #[test]
+    fn dleq_proof_verifies() {
+        let k = Scalar::random(&mut OsRng);
+        let blinded = ProjectivePoint::random(&mut OsRng);
+        let evaluated = blinded * k;
+
+        let proof = generate_proof(&k, &blinded, &evaluated).unwrap();
+        let decoded = DleqProof::from_bytes(&proof.to_bytes()).unwrap();
+        assert!(verify_proof(&public_key(&k), &blinded, &evaluated, &decoded).is_ok());
+    }
+
+    #[test]
+    fn dleq_proof_rejects_other_key() {
+        let k = Scalar::random(&mut OsRng);
+        let other_k = Scalar::random(&mut OsRng);
+        let blinded = ProjectivePoint::random(&mut OsRng);
+        let evaluated = blinded * other_k;
+
+        let proof = generate_proof(&other_k, &blinded, &evaluated).unwrap();
+        assert!(verify_proof(&public_key(&k), &blinded, &evaluated, &proof).is_err());
+    }
+
+    #[test]
+    fn derive_key_is_deterministic_per_user() {
+        let seed = [7u8; 32];
+        let k_alice = derive_key(&seed, b"alice").unwrap();
+        assert_eq!(*k_alice, *derive_key(&seed, b"alice").unwrap());
+        assert_ne!(*k_alice, *derive_key(&seed, b"bob").unwrap());
+        assert_ne!(*k_alice, *derive_key(&[8u8; 32], b"alice").unwrap());
+    }
+}
@@ -0,0 +1,154 @@
+//! Alerts, modelled on the TLS alert protocol. Either side ends a session with an alert:
+//! `CloseNotify` when it is done, any other description when it found an error.
+//!
+//! An alert travels as `Message::Alert` until the sender derived k3, and afterwards as
+//! `Message::EncryptedAlert` under the sender's k3. Plaintext alerts are only accepted
+//! until the peer proved its keys, so nobody on the path can end an established session.
+//!
+//! Every alert ends the session: both sides drop its keys, and the next request starts
+//! over with a new handshake on the same connection.
+//! - `CloseNotify` is not an error. The receiver answers it with its own `CloseNotify`,
+//!   so the side that closed knows the peer dropped the session as well.
+//! - Every other alert is fatal and never answered. The receiver reports the error.
+
+use super::HandshakeError;
+use crate::crypto;
+use crate::crypto::participant::Message;
+use crate::crypto::secret::SecretKey;
+use aes_gcm::aead::OsRng;
+use rand_core::RngCore;
+use std::fmt;
+use std::mem;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertDescription {
+    /// The sender is done with the session.
+    CloseNotify,
+    /// A message that is not valid in the current state, or that cannot be parsed.
+    UnexpectedMessage,
+    /// A certificate from the CA did not verify.
+    BadCertificate,
+    /// A message failed to decrypt, or a signature or MAC did not verify.
+    DecryptError,
+    /// No protocol version in common.
+    ProtocolVersion,
+    /// A local failure unrelated to the peer.
+    InternalError,
+    /// The account an authenticated request refers to no longer exists.
+    UnknownUser,
+}
+
+impl AlertDescription {
+    /// Code on the wire, the one of the matching TLS alert where there is one.
+    pub fn code(self) -> u8 {
+        match self {
+            AlertDescription::CloseNotify => 0,
+            AlertDescription::UnexpectedMessage => 10,
+            AlertDescription::BadCertificate => 42,
+            AlertDescription::DecryptError => 51,
+            AlertDescription::ProtocolVersion => 70,
+            AlertDescription::InternalError => 80,
+            AlertDescription::UnknownUser => 115,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        [
+            AlertDescription::CloseNotify,
+            AlertDescription::UnexpectedMessage,
+            AlertDescription::BadCertificate,
+            AlertDescription::DecryptError,
+            AlertDescription::ProtocolVersion,
+            AlertDescription::InternalError,
+            AlertDescription::UnknownUser,
+        ]
+        .into_iter()
+        .find(|description| description.code() == code)
+    }
+
+    /// Whether the alert reports an error. Only `CloseNotify` is not fatal.
+    pub fn is_fatal(self) -> bool {
+        self != AlertDescription::CloseNotify
+    }
+}
+
+impl fmt::Display for AlertDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AlertDescription::CloseNotify => "close_notify",
+            AlertDescription::UnexpectedMessage => "unexpected_message",
+            AlertDescription::BadCertificate => "bad_certificate",
+            AlertDescription::DecryptError => "decrypt_error",
+            AlertDescription::ProtocolVersion => "protocol_version",
+            AlertDescription::InternalError => "internal_error",
+            AlertDescription::UnknownUser => "unknown_user",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Alerts of one side of a session.
+#[derive(Default)]
+pub(super) struct AlertChannel {
+    /// This side's and the peer's k3, from the moment this side derived them.
+    keys: Option<(SecretKey, SecretKey)>,
+    /// The peer proved its keys, plaintext alerts are no longer accepted.
+    established: bool,
+    /// This side sent an alert and sends nothing more.
+    closed: bool,
+}
+
+impl AlertChannel {
+    /// Encrypts later alerts under `seal` and decrypts the peer's under `open`.
+    pub(super) fn set_keys(&mut self, seal: &SecretKey, open: &SecretKey) {
+        self.keys = Some((seal.clone(), open.clone()));
+    }
+
+    pub(super) fn establish(&mut self) {
+        self.established = true;
+    }
+
+    /// Drops the keys once no more alerts can follow.
+    pub(super) fn close(&mut self) {
+        self.keys = None;
+        self.closed = true;
+    }
+
+    /// The alert message for `description`, or `None` if this side already sent one.
+    pub(super) fn seal(&mut self, ad: &[u8], description: AlertDescription) -> Option<Message> {
+        if mem::replace(&mut self.closed, true) {
+            return None;
+        }
+        let Some((seal, _)) = &self.keys else {
+            return Some(Message::Alert { description: description.code() });
+        };
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let aead_payload = crypto::aead::encrypt(seal, &nonce, &[description.code()], &alert_ad(ad)).ok()?;
+        Some(Message::EncryptedAlert { nonce, aead_payload })
+    }
+
+    /// The alert `msg` carries, `None` if it is no alert. Alerts that are not protected
+    /// as they should be at this point are rejected.
+    pub(super) fn read(&self, ad: &[u8], msg: &Message) -> Result<Option<AlertDescription>, HandshakeError> {
+        let code = match msg {
+            Message::Alert { description } if !self.established => *description,
+            Message::Alert { .. } => return Err(HandshakeError::UnexpectedMessage),
+            Message::EncryptedAlert { nonce, aead_payload } => {
+                let (_, open) = self.keys.as_ref().ok_or(HandshakeError::UnexpectedMessage)?;
+                match crypto::aead::decrypt(open, nonce, aead_payload, &alert_ad(ad)) {
+                    Ok(plaintext) if plaintext.len() == 1 => plaintext[0],
+                    Ok(_) => return Err(HandshakeError::Malformed("alert")),
+                    Err(_) => return Err(HandshakeError::Decrypt),
+                }
+            }
+            _ => return Ok(None),
+        };
+        AlertDescription::from_code(code).map(Some).ok_or(HandshakeError::Malformed("alert"))
+    }
+}
+
+/// Alerts are bound to their own associated data, so they never pass for other messages.
+fn alert_ad(ad: &[u8]) -> Vec<u8> {
+    [ad, b"Alert"].concat()
+}
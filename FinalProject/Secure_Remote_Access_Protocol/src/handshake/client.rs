@@ -0,0 +1,546 @@
+//! Alice's side: pq_tls, then the OPAQUE login for an action or the resumption of an
+//! earlier session, then the double ratchet.
+
+use super::ratchet::ClientRatchet;
+use super::alert::AlertChannel;
+use super::heartbeat::Heartbeats;
+use super::{confirmation_keys, decode_element, decode_point, decrypt, encrypt, oprf_key_input, resumed_session_key, version_transcript, AlertDescription, Event, Handshake, HandshakeError, TrafficKeys, KEY_ID_LEN};
+use crate::crypto;
+use crate::crypto::dh_group::{self, DhElement, DhGroup, DhScalar, Group};
+use crate::crypto::envelope;
+use crate::crypto::hash2curve::hash2curve_demo;
+use crate::crypto::hmac::{compute_hmac, verify_hmac};
+use crate::crypto::key_schedule::{key_schedule_1, key_schedule_2, key_schedule_3};
+use crate::crypto::ksf::{self, KeyStretching};
+use crate::crypto::participant::{Message, CA, SERVER_IDENTITY, STATUS_LOCKED, STATUS_RATE_LIMITED, STATUS_RESUME_REFUSED, SUPPORTED_VERSIONS};
+use crate::crypto::secret::{Secret, SecretKey};
+use crate::crypto::voprf;
+use aes_gcm::aead::OsRng;
+use elliptic_curve::group::GroupEncoding;
+use elliptic_curve::hash2curve::ExpandMsgXmd;
+use elliptic_curve::Field;
+use k256::{ProjectivePoint, Scalar};
+use kem::Decapsulate;
+use ml_dsa::signature::Verifier;
+use ml_dsa::{EncodedVerifyingKey, MlDsa65, Signature, VerifyingKey};
+use ml_kem::{Ciphertext, EncodedSizeUser, KemCore, MlKem768};
+use rand_core::RngCore;
+use sha2::{Digest, Sha256};
+use sha3::Sha3_256;
+use std::collections::VecDeque;
+use std::mem;
+
+/// Algorithms used by pq_tls and the protocol stages on top of it.
+pub const CIPHER_SUITE: &str = "ML-KEM-768 + ML-DSA-65, AES-256-GCM, HKDF-SHA256";
+
+/// Length of an OPRF key certificate (an ML-DSA-65 signature of Google's identity key).
+const CERT_LEN: usize = 3309;
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// What Alice learned about Google during pq_tls.
+#[derive(Clone, Default)]
+pub struct HandshakeInfo {
+    /// Protocol version negotiated in the hellos.
+    pub version: u16,
+    pub suite: &'static str,
+    /// SHA-256 of Google's ML-DSA verifying key (the key certified by the CA), in hex.
+    pub server_key_fingerprint: String,
+}
+
+/// The ServerHello and what Alice derived from it.
+struct ServerHello {
+    version: u16,
+    nonce_c: [u8; 8],
+    ek: Vec<u8>,
+    nonce_s: Vec<u8>,
+    verifying_key: VerifyingKey<MlDsa65>,
+    shared_key: SecretKey,
+    k1_c: SecretKey,
+    k1_s: SecretKey,
+    k2_c: SecretKey,
+    k2_s: SecretKey,
+}
+
+impl ServerHello {
+    /// nonce_c || ek || nonce_s || verifying_key || versions, the start of every signed or
+    /// MACed input.
+    fn transcript(&self) -> Vec<u8> {
+        let versions = version_transcript(SUPPORTED_VERSIONS, self.version);
+        [self.nonce_c.as_slice(), &self.ek, &self.nonce_s, self.verifying_key.encode().as_slice(), &versions].concat()
+    }
+}
+
+/// What Alice needs to re-wrap her envelope for a newer AKE key of Google.
+struct Rewrap {
+    username: Vec<u8>,
+    randomized_pw: SecretKey,
+    /// ID of the AKE key the envelope was created for.
+    key_id: u32,
+}
+
+enum State {
+    /// ClientHello queued, waiting for the ServerHello.
+    ClientHello { nonce_c: [u8; 8], dk: Box<DecapsulationKey>, ek: Box<EncapsulationKey> },
+    /// Waiting for Google's certificate, signature and MAC.
+    ServerHello(Box<ServerHello>),
+    Established,
+    /// Login request sent, waiting for the OPRF evaluation and the masked credentials.
+    LoginRequest { username: Vec<u8>, pw: Secret<Vec<u8>>, a: Secret<Scalar>, h_pw_a: ProjectivePoint },
+    /// Resumption ticket and ephemeral key sent, waiting for Google's ephemeral key.
+    ResumeRequest { psk: SecretKey, x: Secret<DhScalar> },
+    /// Ephemeral key sent, waiting for Google's.
+    Ephemeral { lsk_c: Secret<DhScalar>, lpk_s: DhElement, x: Secret<DhScalar> },
+    /// mac_c sent, waiting for mac_s.
+    KeyConfirmation { sk: SecretKey, large_y: DhElement, ks: SecretKey },
+    LoggedIn(ClientRatchet),
+    Failed,
+}
+
+pub struct ClientHandshake {
+    ca: CA,
+    ad: [u8; 13],
+    state: State,
+    /// Protocol version negotiated in the hellos.
+    version: Option<u16>,
+    keys: Option<TrafficKeys>,
+    session_key: Option<SecretKey>,
+    info: HandshakeInfo,
+    /// Google's identity key, once pq_tls checked its certificate. It signs the OPRF keys.
+    server_key: Option<Box<VerifyingKey<MlDsa65>>>,
+    /// Kept from the login until the first app data.
+    rewrap: Option<Rewrap>,
+    alerts: AlertChannel,
+    heartbeats: Heartbeats,
+    outgoing: VecDeque<Message>,
+    events: VecDeque<Event>,
+}
+
+impl ClientHandshake {
+    /// Starts pq_tls: generates the ML-KEM key pair and queues the ClientHello.
+    pub fn new(ca: &CA, ad: &[u8; 13]) -> Self {
+        let mut nonce_c = [0u8; 8];
+        OsRng.fill_bytes(&mut nonce_c);
+        let (dk, ek) = MlKem768::generate(&mut OsRng);
+        let hello = Message::PqtlsClientHello {
+            versions: SUPPORTED_VERSIONS.to_vec(),
+            nonce_c: nonce_c.to_vec(),
+            ek: ek.as_bytes().to_vec(),
+        };
+
+        Self {
+            ca: ca.clone(),
+            ad: *ad,
+            state: State::ClientHello { nonce_c, dk: Box::new(dk), ek: Box::new(ek) },
+            version: None,
+            keys: None,
+            session_key: None,
+            info: HandshakeInfo::default(),
+            server_key: None,
+            rewrap: None,
+            alerts: AlertChannel::default(),
+            heartbeats: Heartbeats::default(),
+            outgoing: VecDeque::from([hello]),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Keys derived by pq_tls, once established.
+    pub fn keys(&self) -> Option<&TrafficKeys> {
+        self.keys.as_ref()
+    }
+
+    /// What Alice learned about Google, once established.
+    pub fn info(&self) -> &HandshakeInfo {
+        &self.info
+    }
+
+    /// The 3DH session key, once the login succeeded.
+    pub fn session_key(&self) -> Option<&SecretKey> {
+        self.session_key.as_ref()
+    }
+
+    /// Encrypts a request for Google under k3_c. Requests and status replies are not
+    /// part of the handshake, but travel under its keys.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Message, HandshakeError> {
+        let keys = self.keys.as_ref().ok_or(HandshakeError::InvalidState)?;
+        encrypt(&keys.k3_c, &self.ad, plaintext)
+    }
+
+    /// Starts the OPAQUE login for `action` (`Login`, `ChangePassword` or
+    /// `DeleteAccount`) once pq_tls is established: queues the blinded password.
+    pub fn start_login(&mut self, action: &[u8], username: &[u8], pw: &[u8]) -> Result<(), HandshakeError> {
+        if !matches!(self.state, State::Established) {
+            return Err(HandshakeError::InvalidState);
+        }
+
+        // ----------- OPRF stage -----------
+        let a = Secret::new(Scalar::random(&mut OsRng));
+        let h_pw: ProjectivePoint =
+            hash2curve_demo::<k256::Secp256k1, ExpandMsgXmd<Sha3_256>>(pw)
+                .expect("hash2curve_demo (k256 + SHA3-256) failed");
+        let h_pw_a = h_pw * *a;
+
+        let request = [action, b";", username, b";", h_pw_a.to_bytes().as_slice()].concat();
+        let msg = self.seal(&request)?;
+        self.outgoing.push_back(msg);
+        self.state = State::LoginRequest { username: username.to_vec(), pw: Secret::new(pw.to_vec()), a, h_pw_a };
+        Ok(())
+    }
+
+    /// Answers Google's request to re-wrap the envelope of this login for its current AKE
+    /// key, `key_id || lpk_s`: queues the new envelope and client public key. The request
+    /// arrives after key confirmation, so the old key vouches for the new one.
+    pub fn rewrap_envelope(&mut self, request: &[u8]) -> Result<(), HandshakeError> {
+        let Some(Rewrap { username, randomized_pw, key_id }) = self.rewrap.take() else {
+            return Err(HandshakeError::InvalidState);
+        };
+        if !matches!(self.state, State::LoggedIn(_)) {
+            return Err(HandshakeError::InvalidState);
+        }
+        if request.len() != KEY_ID_LEN + dh_group::ELEMENT_LEN {
+            return Err(HandshakeError::Malformed("re-wrap request"));
+        }
+        let (new_key_id, lpk_s) = request.split_at(KEY_ID_LEN);
+        if u32::from_be_bytes(new_key_id.try_into().unwrap()) <= key_id {
+            return Err(HandshakeError::Malformed("re-wrap request"));
+        }
+        let lpk_s = decode_element(lpk_s, "AKE key")?;
+
+        // A fresh envelope nonce, so the client key pair changes along with the server key
+        let mut nonce = [0u8; envelope::NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let stored = envelope::store(randomized_pw.as_slice(), nonce, &lpk_s, SERVER_IDENTITY, &username)
+            .map_err(|_| HandshakeError::Crypto("envelope"))?;
+
+        // {{envelope, lpk_c}}
+        let reply = [stored.envelope.to_bytes().as_slice(), &Group::encode(&stored.client_public_key)].concat();
+        let msg = self.seal(&reply)?;
+        self.outgoing.push_back(msg);
+        Ok(())
+    }
+
+    /// Resumes an earlier session once pq_tls is established, with the `ticket` Google
+    /// issued for it and the resumption secret `psk` of that session: queues the ticket
+    /// with a fresh ephemeral key. Key confirmation then proves that Alice knows `psk`.
+    pub fn start_resume(&mut self, username: &[u8], ticket: &[u8], psk: &SecretKey) -> Result<(), HandshakeError> {
+        if !matches!(self.state, State::Established) {
+            return Err(HandshakeError::InvalidState);
+        }
+
+        let x = Secret::new(Group::random_scalar());
+        let request = [b"Resume;".as_slice(), username, b";", &Group::encode(&Group::public_key(&x)), ticket].concat();
+        let msg = self.seal(&request)?;
+        self.outgoing.push_back(msg);
+        self.state = State::ResumeRequest { psk: psk.clone(), x };
+        Ok(())
+    }
+
+    /// Sends a heartbeat to Google once the login succeeded. The answer arrives as
+    /// `Event::Heartbeat`.
+    pub fn send_heartbeat(&mut self) -> Result<(), HandshakeError> {
+        let (State::LoggedIn(_), Some(keys)) = (&self.state, &self.keys) else {
+            return Err(HandshakeError::InvalidState);
+        };
+        let msg = self.heartbeats.request(&keys.k3_c, &self.ad)?;
+        self.outgoing.push_back(msg);
+        Ok(())
+    }
+
+    /// Sends `message` to Google over the double ratchet once the login succeeded. The
+    /// answer arrives as `Event::AppData` before the next message can be sent.
+    pub fn send_app_data(&mut self, message: &str) -> Result<(), HandshakeError> {
+        let (State::LoggedIn(ratchet), Some(keys)) = (&mut self.state, &self.keys) else {
+            return Err(HandshakeError::InvalidState);
+        };
+        let msg = ratchet.seal(&keys.k3_c, &self.ad, message)?;
+        self.outgoing.push_back(msg);
+        self.rewrap = None;
+        Ok(())
+    }
+
+    fn server_hello(&mut self, nonce_c: [u8; 8], dk: &DecapsulationKey, ek: &EncapsulationKey, msg: Message) -> Result<(), HandshakeError> {
+        let Message::PqtlsServerHello { version, nonce_s, ct, verifying_key } = msg else {
+            return Err(HandshakeError::UnexpectedMessage);
+        };
+        if !SUPPORTED_VERSIONS.contains(&version) {
+            return Err(HandshakeError::UnsupportedVersion);
+        }
+        self.version = Some(version);
+        let verifying_key = EncodedVerifyingKey::<MlDsa65>::try_from(verifying_key.as_slice())
+            .map(|encoded| VerifyingKey::<MlDsa65>::decode(&encoded))
+            .map_err(|_| HandshakeError::Malformed("verifying key"))?;
+        let ct = Ciphertext::<MlKem768>::try_from(ct.as_slice())
+            .map_err(|_| HandshakeError::Malformed("ciphertext"))?;
+
+        // Calculate shared key and K1_c, K1_s, K2_c, K2_s
+        let decapsulated = dk.decapsulate(&ct).map_err(|_| HandshakeError::Crypto("decapsulation"))?;
+        let mut shared_key = SecretKey::default();
+        shared_key.copy_from_slice(decapsulated.as_slice());
+        let ek = ek.as_bytes().to_vec();
+        let (k1_c, k1_s) = key_schedule_1(shared_key.as_slice());
+        let (k2_c, k2_s) = key_schedule_2(&nonce_c, &ek, &nonce_s, verifying_key.encode().as_slice(), shared_key.as_slice());
+
+        self.state = State::ServerHello(Box::new(ServerHello { version, nonce_c, ek, nonce_s, verifying_key, shared_key, k1_c, k1_s, k2_c, k2_s }));
+        Ok(())
+    }
+
+    fn server_finished(&mut self, hello: ServerHello, msg: Message) -> Result<(), HandshakeError> {
+        let decrypted_msg = decrypt(&hello.k1_s, &self.ad, msg)?;
+
+        // cert || google_sign || google_mac, the two signatures have the same length
+        let mac_len = 32;
+        if decrypted_msg.len() < mac_len || (decrypted_msg.len() - mac_len) % 2 != 0 {
+            return Err(HandshakeError::Malformed("server finished message"));
+        }
+        let (rest, google_mac) = decrypted_msg.split_at(decrypted_msg.len() - mac_len);
+        let (cert_bytes, sign_bytes) = rest.split_at(rest.len() / 2);
+        let cert = Signature::<MlDsa65>::try_from(cert_bytes).map_err(|_| HandshakeError::Malformed("certificate"))?;
+        let google_sign = Signature::<MlDsa65>::try_from(sign_bytes).map_err(|_| HandshakeError::Malformed("signature"))?;
+        let verifying_key = hello.verifying_key.encode();
+
+        // Calculate K3_c, K3_s
+        let (k3_c, k3_s) = key_schedule_3(
+            &hello.nonce_c,
+            &hello.ek,
+            &hello.nonce_s,
+            verifying_key.as_slice(),
+            hello.shared_key.as_slice(),
+            google_sign.encode().as_slice(),
+            cert.encode().as_slice(),
+            google_mac,
+        );
+        self.alerts.set_keys(&k3_c, &k3_s);
+
+        // Verify the signature, certificate and MAC tag from google
+        let transcript = hello.transcript();
+        let sign_input = [transcript.as_slice(), cert.encode().as_slice()].concat();
+        if hello.verifying_key.verify(&Sha256::digest(&sign_input), &google_sign).is_err() {
+            return Err(HandshakeError::AuthenticationFailed("server signature"));
+        }
+        if self.ca.verifying_key().verify(verifying_key.as_slice(), &cert).is_err() {
+            return Err(HandshakeError::BadCertificate("server certificate"));
+        }
+        if self.ca.is_revoked(verifying_key.as_slice()) {
+            return Err(HandshakeError::BadCertificate("revoked server certificate"));
+        }
+        let (google_sign, cert) = (google_sign.encode(), cert.encode());
+        let mac_s_input = [transcript.as_slice(), google_sign.as_slice(), cert.as_slice(), b"ServerMAC"].concat();
+        if !verify_hmac(hello.k2_s.as_slice(), &Sha256::digest(&mac_s_input), google_mac) {
+            return Err(HandshakeError::AuthenticationFailed("server MAC"));
+        }
+
+        // Calculate and send alice's MAC tag
+        let mac_c_input = [transcript.as_slice(), google_sign.as_slice(), cert.as_slice(), b"ClientMAC"].concat();
+        let mac_c = compute_hmac(hello.k2_c.as_slice(), &Sha256::digest(&mac_c_input));
+        self.outgoing.push_back(encrypt(&hello.k1_c, &self.ad, &mac_c)?);
+
+        let fingerprint = Sha256::digest(verifying_key.as_slice());
+        self.info = HandshakeInfo {
+            version: hello.version,
+            suite: CIPHER_SUITE,
+            server_key_fingerprint: fingerprint.iter().map(|b| format!("{b:02x}")).collect(),
+        };
+        let ServerHello { k1_c, k1_s, k2_c, k2_s, verifying_key, .. } = hello;
+        self.server_key = Some(Box::new(verifying_key));
+        self.keys = Some(TrafficKeys { k1_c, k1_s, k2_c, k2_s, k3_c, k3_s });
+        self.alerts.establish();
+        self.state = State::Established;
+        self.events.push_back(Event::Established);
+        Ok(())
+    }
+
+    fn login_response(
+        &mut self,
+        username: &[u8],
+        pw: &[u8],
+        a: &Scalar,
+        h_pw_a: &ProjectivePoint,
+        msg: Message,
+    ) -> Result<(), HandshakeError> {
+        let decrypted_msg = self.open(msg)?;
+
+        // Google refuses the attempt while the username or our address is backing off or locked
+        if decrypted_msg.starts_with(STATUS_RATE_LIMITED) || decrypted_msg.starts_with(STATUS_LOCKED) {
+            let retry_after = String::from_utf8_lossy(decrypted_msg.rsplit(|&b| b == b';').next().unwrap_or(&[])).into_owned();
+            return Err(HandshakeError::Refused { locked: decrypted_msg.starts_with(STATUS_LOCKED), retry_after });
+        }
+
+        // {{key_id, h_pw^as, proof, oprf_pk, oprf_pk_cert, ksf, masking_nonce, masked_response}}
+        const POINT_LEN: usize = voprf::ELEMENT_LEN;
+        if decrypted_msg.len() != KEY_ID_LEN + 2 * POINT_LEN + voprf::PROOF_LEN + CERT_LEN + ksf::PARAMS_LEN + envelope::NONCE_LEN + envelope::MASKED_RESPONSE_LEN {
+            return Err(HandshakeError::Malformed("login response"));
+        }
+        let (key_id_bytes, rest_bytes) = decrypted_msg.split_at(KEY_ID_LEN);
+        let (h_pw_as_bytes, rest_bytes) = rest_bytes.split_at(POINT_LEN);
+        let (proof_bytes, rest_bytes) = rest_bytes.split_at(voprf::PROOF_LEN);
+        let (oprf_pk_bytes, rest_bytes) = rest_bytes.split_at(POINT_LEN);
+        let (oprf_pk_cert_bytes, rest_bytes) = rest_bytes.split_at(CERT_LEN);
+        let (ksf_bytes, rest_bytes) = rest_bytes.split_at(ksf::PARAMS_LEN);
+        let (masking_nonce, masked_response) = rest_bytes.split_at(envelope::NONCE_LEN);
+        let h_pw_as = decode_point(h_pw_as_bytes, "OPRF evaluation")?;
+
+        // Verify the OPRF key certificate and the DLEQ proof
+        let oprf_pk_cert = Signature::<MlDsa65>::try_from(oprf_pk_cert_bytes)
+            .map_err(|_| HandshakeError::Malformed("OPRF key certificate"))?;
+        let server_key = self.server_key.as_ref().ok_or(HandshakeError::InvalidState)?;
+        if server_key.verify(&oprf_key_input(username, oprf_pk_bytes), &oprf_pk_cert).is_err() {
+            return Err(HandshakeError::BadCertificate("OPRF key certificate"));
+        }
+        let oprf_pk = decode_point(oprf_pk_bytes, "OPRF key")?;
+        let proof = voprf::DleqProof::from_bytes(proof_bytes).map_err(|_| HandshakeError::Malformed("DLEQ proof"))?;
+        if voprf::verify_proof(&oprf_pk, h_pw_a, &h_pw_as, &proof).is_err() {
+            return Err(HandshakeError::AuthenticationFailed("DLEQ proof"));
+        }
+
+        // Compute randomized_pw, unmask the credential response and recover the client keys
+        let h_pw_s = h_pw_as * a.invert().unwrap();
+        let rw = Secret::new(<[u8; 32]>::from(Sha3_256::digest(Secret::new([pw, h_pw_s.to_bytes().as_slice()].concat()).as_slice())));
+        let ksf = KeyStretching::from_bytes(ksf_bytes).map_err(|_| HandshakeError::Malformed("key stretching parameters"))?;
+        let stretched_rw = ksf.stretch(rw.as_slice()).map_err(|_| HandshakeError::Crypto("key stretching"))?;
+        let (randomized_pw, _) = crypto::key_schedule::extract(None, Secret::new([rw.as_slice(), stretched_rw.as_slice()].concat()).as_slice());
+        let masking_key = envelope::masking_key(randomized_pw.as_slice()).map_err(|_| HandshakeError::Crypto("masking key derivation"))?;
+        let (lpk_s, client_envelope) = envelope::unmask_response(masking_key.as_slice(), masking_nonce.try_into().unwrap(), masked_response)
+            .map_err(|_| HandshakeError::InvalidCredentials)?;
+        let credentials = envelope::recover(randomized_pw.as_slice(), &client_envelope, &lpk_s, SERVER_IDENTITY, username)
+            .map_err(|_| HandshakeError::InvalidCredentials)?;
+
+        let lsk_c: Secret<DhScalar> = credentials.client_private_key;
+        let _lpk_c: DhElement = credentials.client_public_key;
+        let key_id = u32::from_be_bytes(key_id_bytes.try_into().unwrap());
+        self.rewrap = Some(Rewrap { username: username.to_vec(), randomized_pw, key_id });
+
+        // ----------- AKE stage: 3DH -----------
+        let x = Secret::new(Group::random_scalar());
+        let msg = self.seal(&Group::encode(&Group::public_key(&x)))?;
+        self.outgoing.push_back(msg);
+        self.state = State::Ephemeral { lsk_c, lpk_s, x };
+        Ok(())
+    }
+
+    fn server_ephemeral(&mut self, lsk_c: &DhScalar, lpk_s: DhElement, x: &DhScalar, msg: Message) -> Result<(), HandshakeError> {
+        let large_y = decode_element(&self.open(msg)?, "ephemeral key")?;
+
+        // 3DH-KClient(𝑎, 𝑥, 𝐵, 𝑌)
+        let mut key_input = Secret::new(Vec::new());
+        key_input.extend_from_slice(&Group::dh(x, &lpk_s));
+        key_input.extend_from_slice(&Group::dh(x, &large_y));
+        key_input.extend_from_slice(&Group::dh(lsk_c, &large_y));
+        let (sk, _) = crypto::key_schedule::extract(None, key_input.as_slice());
+
+        // ----------- Key Confirmation -----------
+        let (kc, ks) = confirmation_keys(&sk);
+        let msg = self.seal(&compute_hmac(kc.as_slice(), b"Client KC"))?;
+        self.outgoing.push_back(msg);
+        self.state = State::KeyConfirmation { sk, large_y, ks };
+        Ok(())
+    }
+
+    fn resume_response(&mut self, psk: &SecretKey, x: &DhScalar, msg: Message) -> Result<(), HandshakeError> {
+        let decrypted_msg = self.open(msg)?;
+        if decrypted_msg == STATUS_RESUME_REFUSED {
+            return Err(HandshakeError::TicketRefused);
+        }
+        let large_y = decode_element(&decrypted_msg, "ephemeral key")?;
+        let sk = resumed_session_key(psk, &Group::dh(x, &large_y));
+
+        // ----------- Key Confirmation -----------
+        let (kc, ks) = confirmation_keys(&sk);
+        let msg = self.seal(&compute_hmac(kc.as_slice(), b"Client KC"))?;
+        self.outgoing.push_back(msg);
+        self.state = State::KeyConfirmation { sk, large_y, ks };
+        Ok(())
+    }
+
+    fn server_mac(&mut self, sk: SecretKey, large_y: DhElement, ks: &SecretKey, msg: Message) -> Result<(), HandshakeError> {
+        let mac_s = self.open(msg)?;
+        if !verify_hmac(ks.as_slice(), b"Server KC", &mac_s) {
+            return Err(HandshakeError::AuthenticationFailed("key confirmation"));
+        }
+
+        self.session_key = Some(sk.clone());
+        self.state = State::LoggedIn(ClientRatchet::new(sk, large_y));
+        self.events.push_back(Event::LoginSucceeded);
+        Ok(())
+    }
+
+    /// Ends the session if `msg` is an alert from Google, answering a close_notify.
+    fn read_alert(&mut self, msg: &Message) -> Result<(), HandshakeError> {
+        let Some(description) = self.alerts.read(&self.ad, msg)? else {
+            return Ok(());
+        };
+        if description == AlertDescription::CloseNotify {
+            self.outgoing.extend(self.alerts.seal(&self.ad, description));
+        }
+        self.alerts.close();
+        self.end_session();
+        Err(HandshakeError::Alert(description))
+    }
+
+    /// Drops the state and keys of the session.
+    fn end_session(&mut self) {
+        self.state = State::Failed;
+        self.keys = None;
+        self.session_key = None;
+        self.rewrap = None;
+        self.events.clear();
+    }
+}
+
+impl Handshake for ClientHandshake {
+    fn handle(&mut self, msg: Message) -> Result<(), HandshakeError> {
+        self.read_alert(&msg)?;
+
+        // The state is only put back once the message was accepted
+        match mem::replace(&mut self.state, State::Failed) {
+            State::ClientHello { nonce_c, dk, ek } => self.server_hello(nonce_c, &dk, &ek, msg),
+            State::ServerHello(hello) => self.server_finished(*hello, msg),
+            State::LoginRequest { username, pw, a, h_pw_a } => self.login_response(&username, &pw, &a, &h_pw_a, msg),
+            State::ResumeRequest { psk, x } => self.resume_response(&psk, &x, msg),
+            State::Ephemeral { lsk_c, lpk_s, x } => self.server_ephemeral(&lsk_c, lpk_s, &x, msg),
+            State::KeyConfirmation { sk, large_y, ks } => self.server_mac(sk, large_y, &ks, msg),
+            State::LoggedIn(ratchet) if matches!(msg, Message::Heartbeat { .. }) => {
+                let keys = self.keys.as_ref().ok_or(HandshakeError::InvalidState)?;
+                self.heartbeats.read_response(&keys.k3_s, &self.ad, &msg)?;
+                self.state = State::LoggedIn(ratchet);
+                self.events.push_back(Event::Heartbeat);
+                Ok(())
+            }
+            State::LoggedIn(mut ratchet) => {
+                let keys = self.keys.as_ref().ok_or(HandshakeError::InvalidState)?;
+                let answer = ratchet.open(&keys.k3_s, &self.ad, msg)?;
+                self.state = State::LoggedIn(ratchet);
+                self.events.push_back(Event::AppData(answer));
+                Ok(())
+            }
+            State::Established | State::Failed => Err(HandshakeError::UnexpectedMessage),
+        }
+    }
+
+    fn open(&mut self, msg: Message) -> Result<Vec<u8>, HandshakeError> {
+        self.read_alert(&msg)?;
+        let keys = self.keys.as_ref().ok_or(HandshakeError::InvalidState)?;
+        decrypt(&keys.k3_s, &self.ad, msg)
+    }
+
+    fn alert(&mut self, description: AlertDescription) {
+        self.outgoing.extend(self.alerts.seal(&self.ad, description));
+        if description.is_fatal() {
+            self.alerts.close();
+        }
+        self.end_session();
+    }
+
+    fn poll_transmit(&mut self) -> Option<Message> {
+        self.outgoing.pop_front()
+    }
+
+    fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    fn version(&self) -> Option<u16> {
+        self.version
+    }
+}
@@ -0,0 +1,85 @@
+//! Keepalive for a logged-in session, modelled on the TLS heartbeat extension.
+//!
+//! Alice sends a request while she has nothing else to send, and Google answers it
+//! right away. Both travel as `Message::Heartbeat` under the sender's k3 and carry a
+//! sequence number: Google only accepts numbers above the last one it saw, and Alice
+//! only the one she is waiting for, so an old heartbeat cannot be replayed to keep a
+//! session alive.
+
+use super::HandshakeError;
+use crate::crypto;
+use crate::crypto::participant::Message;
+use crate::crypto::secret::SecretKey;
+use aes_gcm::aead::OsRng;
+use rand_core::RngCore;
+
+const REQUEST: u8 = 1;
+const RESPONSE: u8 = 2;
+
+/// Heartbeats of one side of a session.
+#[derive(Default)]
+pub(super) struct Heartbeats {
+    /// Sequence number of the last request, sent or received.
+    last: u64,
+    /// A request is waiting for its answer.
+    outstanding: bool,
+}
+
+impl Heartbeats {
+    /// Alice's next request, sealed under `seal`. Only one may be outstanding.
+    pub(super) fn request(&mut self, seal: &SecretKey, ad: &[u8]) -> Result<Message, HandshakeError> {
+        if self.outstanding {
+            return Err(HandshakeError::InvalidState);
+        }
+        let msg = seal_heartbeat(seal, ad, REQUEST, self.last + 1)?;
+        self.last += 1;
+        self.outstanding = true;
+        Ok(msg)
+    }
+
+    /// Checks Google's answer to the outstanding request.
+    pub(super) fn read_response(&mut self, open: &SecretKey, ad: &[u8], msg: &Message) -> Result<(), HandshakeError> {
+        let (kind, sequence) = open_heartbeat(open, ad, msg)?;
+        if kind != RESPONSE || !self.outstanding || sequence != self.last {
+            return Err(HandshakeError::UnexpectedMessage);
+        }
+        self.outstanding = false;
+        Ok(())
+    }
+
+    /// Checks Alice's request and returns the answer, sealed under `seal`.
+    pub(super) fn answer(&mut self, seal: &SecretKey, open: &SecretKey, ad: &[u8], msg: &Message) -> Result<Message, HandshakeError> {
+        let (kind, sequence) = open_heartbeat(open, ad, msg)?;
+        if kind != REQUEST || sequence <= self.last {
+            return Err(HandshakeError::UnexpectedMessage);
+        }
+        self.last = sequence;
+        seal_heartbeat(seal, ad, RESPONSE, sequence)
+    }
+}
+
+fn seal_heartbeat(key: &SecretKey, ad: &[u8], kind: u8, sequence: u64) -> Result<Message, HandshakeError> {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let plaintext = [[kind].as_slice(), &sequence.to_be_bytes()].concat();
+    let aead_payload = crypto::aead::encrypt(key, &nonce, &plaintext, &heartbeat_ad(ad))
+        .map_err(|_| HandshakeError::Crypto("encryption"))?;
+    Ok(Message::Heartbeat { nonce, aead_payload })
+}
+
+/// The kind and sequence number of a heartbeat.
+fn open_heartbeat(key: &SecretKey, ad: &[u8], msg: &Message) -> Result<(u8, u64), HandshakeError> {
+    let Message::Heartbeat { nonce, aead_payload } = msg else {
+        return Err(HandshakeError::UnexpectedMessage);
+    };
+    let plaintext = crypto::aead::decrypt(key, nonce, aead_payload, &heartbeat_ad(ad)).map_err(|_| HandshakeError::Decrypt)?;
+    let (&kind, sequence) = plaintext.split_first().ok_or(HandshakeError::Malformed("heartbeat"))?;
+    let sequence = <[u8; 8]>::try_from(sequence).map_err(|_| HandshakeError::Malformed("heartbeat"))?;
+    Ok((kind, u64::from_be_bytes(sequence)))
+}
+
+/// Keeps a heartbeat from opening as application data or an alert under the same k3, as
+/// `alert_ad` does for alerts.
+fn heartbeat_ad(ad: &[u8]) -> Vec<u8> {
+    [ad, b"Heartbeat"].concat()
+}
@@ -0,0 +1,448 @@
+//! Sans-IO state machines for both sides of a connection: pq_tls, the OPAQUE login
+//! (OPRF stage, 3DH and key confirmation) and the double ratchet that carries the
+//! application data afterwards.
+//!
+//! A handshake is fed the peer's `Message`s through `handle` and queues the messages to
+//! send (`poll_transmit`) and the `Event`s for its caller (`poll_event`). It never
+//! touches a connection, so it can be driven from any event loop or fed arbitrary
+//! messages. `client::alice` and `server::google` drive them over a `Transport` with
+//! `User::drive`. Sessions end with an alert, see `alert`, and are kept alive with
+//! heartbeats, see `heartbeat`.
+//!
+//! The 3DH and ratchet key pairs use the standard generator of the group picked in
+//! `crypto::dh_group`, so separately started clients and servers agree on it.
+
+pub mod alert;
+pub mod client;
+pub mod heartbeat;
+pub mod ratchet;
+pub mod server;
+
+pub use alert::AlertDescription;
+pub use client::ClientHandshake;
+pub use server::ServerHandshake;
+
+use crate::crypto;
+use crate::crypto::dh_group::{DhElement, DhGroup, Group};
+use crate::crypto::participant::{Message, SUPPORTED_VERSIONS};
+use crate::crypto::secret::{Secret, SecretKey};
+use crate::crypto::voprf;
+use aes_gcm::aead::OsRng;
+use elliptic_curve::group::GroupEncoding;
+use k256::ProjectivePoint;
+use rand_core::RngCore;
+use std::fmt;
+
+/// Length of the ID of Google's AKE key, big-endian in the login response and a re-wrap
+/// request.
+pub const KEY_ID_LEN: usize = 4;
+
+/// Progress reported by a handshake.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// pq_tls finished, the `TrafficKeys` protect every later message.
+    Established,
+    /// Both sides confirmed the 3DH session key, so Alice knows the password.
+    LoginSucceeded,
+    /// A message from the peer over the double ratchet.
+    AppData(String),
+    /// A heartbeat from the peer. Google has already queued the answer.
+    Heartbeat,
+}
+
+/// Interface shared by `ClientHandshake` and `ServerHandshake`.
+pub trait Handshake {
+    /// Processes a message from the peer. After an error the handshake is unusable: the
+    /// session ends with the error's alert, or it already ended with the peer's alert.
+    fn handle(&mut self, msg: Message) -> Result<(), HandshakeError>;
+
+    /// Decrypts a message from the peer that travels under the pq_tls keys outside the
+    /// handshake. An alert ends the session as in `handle`.
+    fn open(&mut self, msg: Message) -> Result<Vec<u8>, HandshakeError>;
+
+    /// Ends the session with `description` and queues the alert. Only the answer to a
+    /// close_notify is accepted afterwards.
+    fn alert(&mut self, description: AlertDescription);
+
+    /// Next message to send to the peer.
+    fn poll_transmit(&mut self) -> Option<Message>;
+
+    /// Next event for the caller.
+    fn poll_event(&mut self) -> Option<Event>;
+
+    /// Protocol version the hellos negotiated, `None` before the peer's hello was accepted.
+    fn version(&self) -> Option<u16>;
+}
+
+/// Keys derived by pq_tls. k1 protects the finished messages, k2 keys their MACs and
+/// k3 protects everything after the handshake.
+pub struct TrafficKeys {
+    pub k1_c: SecretKey,
+    pub k1_s: SecretKey,
+    pub k2_c: SecretKey,
+    pub k2_s: SecretKey,
+    pub k3_c: SecretKey,
+    pub k3_s: SecretKey,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The message is not valid in the current state.
+    UnexpectedMessage,
+    /// The operation is not valid in the current state.
+    InvalidState,
+    /// A message failed to decrypt.
+    Decrypt,
+    /// A message has the wrong length or encoding.
+    Malformed(&'static str),
+    /// A local cryptographic operation failed.
+    Crypto(&'static str),
+    /// The peer failed to prove its identity or its key.
+    AuthenticationFailed(&'static str),
+    /// A certificate from the CA did not verify.
+    BadCertificate(&'static str),
+    /// The password is wrong or the stored credentials are corrupted.
+    InvalidCredentials,
+    /// Alice and Google have no protocol version in common.
+    UnsupportedVersion,
+    /// Google refused the login attempt. `retry_after` is in seconds.
+    Refused { locked: bool, retry_after: String },
+    /// Google did not accept the resumption ticket, a full login is needed.
+    TicketRefused,
+    /// The peer ended the session with an alert.
+    Alert(AlertDescription),
+}
+
+impl HandshakeError {
+    /// Alert that tells the peer about the error. Refusals and alerts from the peer are
+    /// not answered, the peer ended the session itself.
+    pub fn alert(&self) -> Option<AlertDescription> {
+        match self {
+            HandshakeError::UnexpectedMessage | HandshakeError::Malformed(_) => Some(AlertDescription::UnexpectedMessage),
+            HandshakeError::InvalidState | HandshakeError::Crypto(_) => Some(AlertDescription::InternalError),
+            HandshakeError::Decrypt | HandshakeError::AuthenticationFailed(_) | HandshakeError::InvalidCredentials => {
+                Some(AlertDescription::DecryptError)
+            }
+            HandshakeError::BadCertificate(_) => Some(AlertDescription::BadCertificate),
+            HandshakeError::UnsupportedVersion => Some(AlertDescription::ProtocolVersion),
+            HandshakeError::Refused { .. } | HandshakeError::TicketRefused | HandshakeError::Alert(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::UnexpectedMessage => write!(f, "unexpected message"),
+            HandshakeError::InvalidState => write!(f, "operation not valid in this state"),
+            HandshakeError::Decrypt => write!(f, "message failed to decrypt"),
+            HandshakeError::Malformed(what) => write!(f, "malformed {what}"),
+            HandshakeError::Crypto(what) => write!(f, "{what} failed"),
+            HandshakeError::AuthenticationFailed(what) | HandshakeError::BadCertificate(what) => write!(f, "invalid {what}"),
+            HandshakeError::InvalidCredentials => write!(f, "incorrect password or corrupted data"),
+            HandshakeError::UnsupportedVersion => write!(f, "no common protocol version"),
+            HandshakeError::Refused { locked, retry_after } => {
+                let reason = if *locked { "account locked" } else { "too many attempts" };
+                write!(f, "{reason}, retry in {retry_after} seconds")
+            }
+            HandshakeError::TicketRefused => write!(f, "resumption ticket refused"),
+            HandshakeError::Alert(AlertDescription::CloseNotify) => write!(f, "session closed by the peer"),
+            HandshakeError::Alert(description) => write!(f, "peer sent alert {description}"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Picks the first version Alice offered that this build supports.
+fn negotiate_version(offered: &[u16]) -> Option<u16> {
+    offered.iter().copied().find(|version| SUPPORTED_VERSIONS.contains(version))
+}
+
+/// The offered versions and the chosen one as they enter the signed and MACed
+/// transcript, so a peer in the middle cannot downgrade the negotiation.
+fn version_transcript(offered: &[u16], version: u16) -> Vec<u8> {
+    offered.iter().chain([&version]).flat_map(|v| v.to_be_bytes()).collect()
+}
+
+/// Encrypts `plaintext` under `key` with a fresh nonce.
+fn encrypt(key: &SecretKey, ad: &[u8], plaintext: &[u8]) -> Result<Message, HandshakeError> {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let aead_payload = crypto::aead::encrypt(key, &nonce, plaintext, ad)
+        .map_err(|_| HandshakeError::Crypto("encryption"))?;
+    Ok(Message::AeadCiphertext { nonce, aead_payload })
+}
+
+/// Decrypts an `AeadCiphertext` under `key`.
+fn decrypt(key: &SecretKey, ad: &[u8], msg: Message) -> Result<Vec<u8>, HandshakeError> {
+    match msg {
+        Message::AeadCiphertext { nonce, aead_payload } => {
+            crypto::aead::decrypt(key, &nonce, &aead_payload, ad).map_err(|_| HandshakeError::Decrypt)
+        }
+        _ => Err(HandshakeError::UnexpectedMessage),
+    }
+}
+
+/// Decodes an OPRF element sent by the peer.
+fn decode_point(bytes: &[u8], what: &'static str) -> Result<ProjectivePoint, HandshakeError> {
+    if bytes.len() != voprf::ELEMENT_LEN {
+        return Err(HandshakeError::Malformed(what));
+    }
+    Option::from(ProjectivePoint::from_bytes(bytes.into())).ok_or(HandshakeError::Malformed(what))
+}
+
+/// Decodes a DH element sent by the peer, see `dh_group`.
+fn decode_element(bytes: &[u8], what: &'static str) -> Result<DhElement, HandshakeError> {
+    Group::decode(bytes).ok_or(HandshakeError::Malformed(what))
+}
+
+/// Derives the session key of a resumed session from the resumption secret `psk` and
+/// the DH of both fresh ephemeral keys, so every resumption gets its own key.
+fn resumed_session_key(psk: &SecretKey, dh: &[u8]) -> SecretKey {
+    let key_input = Secret::new([psk.as_slice(), dh].concat());
+    let (sk, _) = crypto::key_schedule::extract(None, key_input.as_slice());
+    sk
+}
+
+/// What Google signs with its identity key to vouch for the OPRF key `oprf_pk` of
+/// `username`. The CA certified the identity key offline, so the signature serves as the
+/// OPRF key's certificate without the CA signing anything while Google runs.
+fn oprf_key_input(username: &[u8], oprf_pk: &[u8]) -> Vec<u8> {
+    [b"OPRF-Key;".as_slice(), username, b";", oprf_pk].concat()
+}
+
+/// Derives the key confirmation keys (kc, ks) from the 3DH session key.
+fn confirmation_keys(sk: &SecretKey) -> (SecretKey, SecretKey) {
+    let (_, hk) = crypto::key_schedule::extract(None, sk.as_slice());
+    let combined_key = crypto::key_schedule::expand::<64>(&hk, b"Key Confirmation").unwrap();
+    let (mut kc, mut ks) = (SecretKey::default(), SecretKey::default());
+    kc.copy_from_slice(&combined_key[..32]);
+    ks.copy_from_slice(&combined_key[32..]);
+    (kc, ks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::dh_group;
+    use crate::crypto::ksf::KeyStretching;
+    use crate::crypto::participant::{CA, PROTOCOL_VERSION};
+    use crate::server::ake_keys::AkeKeys;
+    use crate::server::google;
+
+    const AD: &[u8; 13] = b"Alice,Google,";
+
+    /// Delivers queued messages between the two sides until neither has any left.
+    fn exchange(alice: &mut ClientHandshake, google: &mut ServerHandshake) -> Result<(), HandshakeError> {
+        loop {
+            let mut idle = true;
+            while let Some(msg) = alice.poll_transmit() {
+                google.handle(msg)?;
+                idle = false;
+            }
+            while let Some(msg) = google.poll_transmit() {
+                alice.handle(msg)?;
+                idle = false;
+            }
+            if idle {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs pq_tls and a login attempt with `pw` against the record for password `12345`.
+    fn login(pw: &[u8]) -> (ClientHandshake, ServerHandshake, Result<(), HandshakeError>) {
+        let ca = CA::new();
+        let mut oprf_seed = [0u8; 32];
+        OsRng.fill_bytes(&mut oprf_seed);
+        let server_key = AkeKeys::default().current().clone();
+        let record = google::create_record(&oprf_seed, &KeyStretching::Identity, &server_key, b"alice", b"12345").unwrap();
+
+        let mut alice = ClientHandshake::new(&ca, AD);
+        let mut google = ServerHandshake::new(&ca, AD);
+        exchange(&mut alice, &mut google).unwrap();
+        assert_eq!(alice.poll_event(), Some(Event::Established));
+        assert_eq!(google.poll_event(), Some(Event::Established));
+
+        alice.start_login(b"Login", b"alice", pw).unwrap();
+        let request = google.open(alice.poll_transmit().unwrap()).unwrap();
+        let h_pw_a = request.strip_prefix(b"Login;alice;".as_slice()).unwrap();
+        let oprf_key = voprf::derive_key(&oprf_seed, b"alice").unwrap();
+        google.start_login(h_pw_a, b"alice", &record, &oprf_key, &server_key).unwrap();
+
+        let result = exchange(&mut alice, &mut google);
+        (alice, google, result)
+    }
+
+    #[test]
+    fn login_and_app_data_round_trip() {
+        let (mut alice, mut google, result) = login(b"12345");
+        result.unwrap();
+        assert_eq!(alice.poll_event(), Some(Event::LoginSucceeded));
+        assert_eq!(google.poll_event(), Some(Event::LoginSucceeded));
+        assert!(alice.session_key().unwrap() == google.session_key().unwrap());
+
+        for text in ["Hello, world!", "How are you?"] {
+            alice.send_app_data(text).unwrap();
+            exchange(&mut alice, &mut google).unwrap();
+            assert_eq!(google.poll_event(), Some(Event::AppData(text.to_string())));
+
+            google.send_app_data(&format!("Echo => {text}")).unwrap();
+            exchange(&mut alice, &mut google).unwrap();
+            assert_eq!(alice.poll_event(), Some(Event::AppData(format!("Echo => {text}"))));
+        }
+    }
+
+    #[test]
+    fn wrong_password_fails_before_key_confirmation() {
+        let (mut alice, mut google, result) = login(b"54321");
+        assert!(matches!(result, Err(HandshakeError::InvalidCredentials)));
+        assert_eq!(alice.poll_event(), None);
+        assert_eq!(google.poll_event(), None);
+        assert!(matches!(alice.send_app_data("Hello"), Err(HandshakeError::InvalidState)));
+    }
+
+    #[test]
+    fn messages_out_of_order_are_rejected() {
+        let ca = CA::new();
+        let mut alice = ClientHandshake::new(&ca, AD);
+        let mut google = ServerHandshake::new(&ca, AD);
+
+        // Google expects the ClientHello first, Alice the ServerHello
+        assert!(matches!(google.handle(Message::SimplePayload { payload: Vec::new() }), Err(HandshakeError::UnexpectedMessage)));
+        let hello = alice.poll_transmit().unwrap();
+        assert!(matches!(alice.handle(hello), Err(HandshakeError::UnexpectedMessage)));
+    }
+
+    #[test]
+    fn versions_are_negotiated() {
+        let ca = CA::new();
+        let mut alice = ClientHandshake::new(&ca, AD);
+        let mut google = ServerHandshake::new(&ca, AD);
+        exchange(&mut alice, &mut google).unwrap();
+        assert_eq!(alice.info().version, PROTOCOL_VERSION);
+
+        // Google skips versions it does not know and refuses if none is left
+        assert_eq!(negotiate_version(&[7, PROTOCOL_VERSION]), Some(PROTOCOL_VERSION));
+        let mut google = ServerHandshake::new(&ca, AD);
+        let hello = Message::PqtlsClientHello { versions: vec![7], nonce_c: vec![0; 8], ek: vec![0; 1184] };
+        assert!(matches!(google.handle(hello), Err(HandshakeError::UnsupportedVersion)));
+        assert!(google.poll_transmit().is_none());
+    }
+
+    #[test]
+    fn alerts_before_pq_tls_travel_in_the_clear() {
+        let ca = CA::new();
+        let mut alice = ClientHandshake::new(&ca, AD);
+        let mut google = ServerHandshake::new(&ca, AD);
+        let mut hello = alice.poll_transmit().unwrap();
+        if let Message::PqtlsClientHello { versions, .. } = &mut hello {
+            *versions = vec![7];
+        }
+
+        let e = google.handle(hello).unwrap_err();
+        google.alert(e.alert().unwrap());
+        let alert = google.poll_transmit().unwrap();
+        assert!(matches!(alert, Message::Alert { description: 70 }));
+        assert!(matches!(alice.handle(alert), Err(HandshakeError::Alert(AlertDescription::ProtocolVersion))));
+        assert!(alice.poll_transmit().is_none());
+    }
+
+    #[test]
+    fn fatal_alerts_end_the_session() {
+        let (mut alice, mut google, result) = login(b"12345");
+        result.unwrap();
+
+        alice.alert(AlertDescription::DecryptError);
+        let alert = alice.poll_transmit().unwrap();
+        assert!(matches!(alert, Message::EncryptedAlert { .. }));
+        assert!(matches!(google.handle(alert), Err(HandshakeError::Alert(AlertDescription::DecryptError))));
+
+        // Neither side answers or keeps the keys
+        assert!(google.poll_transmit().is_none());
+        assert!(alice.session_key().is_none() && google.session_key().is_none());
+        assert!(matches!(google.send_app_data("Hello"), Err(HandshakeError::InvalidState)));
+        assert!(matches!(alice.seal(b"Hello"), Err(HandshakeError::InvalidState)));
+    }
+
+    #[test]
+    fn plaintext_alerts_are_refused_once_established() {
+        let (_, mut google, result) = login(b"12345");
+        result.unwrap();
+        assert!(matches!(google.handle(Message::Alert { description: 0 }), Err(HandshakeError::UnexpectedMessage)));
+    }
+
+    #[test]
+    fn close_notify_is_answered() {
+        let (mut alice, mut google, result) = login(b"12345");
+        result.unwrap();
+
+        alice.alert(AlertDescription::CloseNotify);
+        assert!(matches!(alice.send_app_data("Hello"), Err(HandshakeError::InvalidState)));
+        let close = alice.poll_transmit().unwrap();
+        assert!(matches!(google.handle(close), Err(HandshakeError::Alert(AlertDescription::CloseNotify))));
+        let answer = google.poll_transmit().unwrap();
+        assert!(matches!(alice.handle(answer), Err(HandshakeError::Alert(AlertDescription::CloseNotify))));
+        assert!(alice.poll_transmit().is_none());
+    }
+
+    #[test]
+    fn heartbeats_are_answered_and_not_replayed() {
+        let (mut alice, mut google, result) = login(b"12345");
+        result.unwrap();
+        assert_eq!(google.poll_event(), Some(Event::LoginSucceeded));
+
+        alice.send_heartbeat().unwrap();
+        assert!(matches!(alice.send_heartbeat(), Err(HandshakeError::InvalidState)));
+        let request = alice.poll_transmit().unwrap();
+        let replay: Message = bincode::deserialize(&bincode::serialize(&request).unwrap()).unwrap();
+        google.handle(request).unwrap();
+        assert_eq!(google.poll_event(), Some(Event::Heartbeat));
+        alice.handle(google.poll_transmit().unwrap()).unwrap();
+        assert_eq!(alice.poll_event(), Some(Event::LoginSucceeded));
+        assert_eq!(alice.poll_event(), Some(Event::Heartbeat));
+
+        assert!(matches!(google.handle(replay), Err(HandshakeError::UnexpectedMessage)));
+    }
+
+    /// Runs pq_tls and resumes a session, Alice with `alice_psk` and Google with `google_psk`.
+    fn resume(alice_psk: &SecretKey, google_psk: &SecretKey) -> (ClientHandshake, ServerHandshake, Result<(), HandshakeError>) {
+        let ca = CA::new();
+        let mut alice = ClientHandshake::new(&ca, AD);
+        let mut google = ServerHandshake::new(&ca, AD);
+        exchange(&mut alice, &mut google).unwrap();
+        assert_eq!(alice.poll_event(), Some(Event::Established));
+        assert_eq!(google.poll_event(), Some(Event::Established));
+
+        alice.start_resume(b"alice", b"ticket", alice_psk).unwrap();
+        let request = google.open(alice.poll_transmit().unwrap()).unwrap();
+        let rest = request.strip_prefix(b"Resume;alice;".as_slice()).unwrap();
+        assert_eq!(&rest[dh_group::ELEMENT_LEN..], b"ticket");
+        google.start_resume(&rest[..dh_group::ELEMENT_LEN], google_psk).unwrap();
+
+        let result = exchange(&mut alice, &mut google);
+        (alice, google, result)
+    }
+
+    #[test]
+    fn sessions_resume_with_the_resumption_secret() {
+        let (alice, _, result) = login(b"12345");
+        result.unwrap();
+        let psk = crypto::key_schedule::resumption_secret(alice.session_key().unwrap().as_slice());
+
+        let (mut alice, mut google, result) = resume(&psk, &psk);
+        result.unwrap();
+        assert_eq!(alice.poll_event(), Some(Event::LoginSucceeded));
+        assert_eq!(google.poll_event(), Some(Event::LoginSucceeded));
+        assert!(alice.session_key().unwrap() == google.session_key().unwrap());
+
+        alice.send_app_data("Hello").unwrap();
+        exchange(&mut alice, &mut google).unwrap();
+        assert_eq!(google.poll_event(), Some(Event::AppData("Hello".to_string())));
+
+        // Without the resumption secret key confirmation fails
+        let (_, _, result) = resume(&SecretKey::default(), &psk);
+        assert!(matches!(result, Err(HandshakeError::InvalidCredentials)));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,156 @@
+//! Double ratchet for the application data after login.
+//!
+//! Every message of Alice carries a fresh ephemeral key X_i+1 and every answer of
+//! Google a fresh Y_i+1. Each DH result steps the root key, and the message key comes
+//! from the chain key started by that step. The payload, AEAD(k3, {{nonce, X or Y,
+//! c1}}), is the same in both directions.
+
+use super::{decode_element, decrypt, encrypt, HandshakeError};
+use crate::crypto;
+use crate::crypto::dh_group::{self, DhElement, DhGroup, DhScalar, Group};
+use crate::crypto::hmac;
+use crate::crypto::participant::Message;
+use crate::crypto::secret::{Secret, SecretKey};
+use aes_gcm::aead::OsRng;
+use rand_core::RngCore;
+
+/// Length of the inner nonce and the ephemeral key in front of c1.
+const HEADER_LEN: usize = 12 + dh_group::ELEMENT_LEN;
+
+/// Alice's side: sends a message, then waits for Google's answer.
+pub struct ClientRatchet {
+    rk_i: SecretKey,
+    large_y_i: DhElement,
+    /// x_i+1 and rk_i+1 while the answer to a sent message is outstanding.
+    pending: Option<(Secret<DhScalar>, SecretKey)>,
+}
+
+impl ClientRatchet {
+    /// Starts the ratchet from the 3DH session key `sk` and Google's ephemeral key `large_y`.
+    pub fn new(sk: SecretKey, large_y: DhElement) -> Self {
+        Self { rk_i: sk, large_y_i: large_y, pending: None }
+    }
+
+    /// Encrypts `message` for Google under a new ephemeral key.
+    pub fn seal(&mut self, k3_c: &SecretKey, ad: &[u8], message: &str) -> Result<Message, HandshakeError> {
+        if self.pending.is_some() {
+            return Err(HandshakeError::InvalidState);
+        }
+
+        // Calculate the new ratchet keys and encrypt the message using mk_1
+        let x_i_plus_1 = Secret::new(Group::random_scalar());
+        let (rk_i_plus_1, ck_0) = kdf_rk(&self.rk_i, &Group::dh(&x_i_plus_1, &self.large_y_i));
+        let (_ck_1, mk_1) = kdf_ck(&ck_0);
+        let msg = seal_payload(k3_c, &mk_1, ad, Group::public_key(&x_i_plus_1), message)?;
+
+        self.pending = Some((x_i_plus_1, rk_i_plus_1));
+        Ok(msg)
+    }
+
+    /// Decrypts Google's answer to the last sealed message.
+    pub fn open(&mut self, k3_s: &SecretKey, ad: &[u8], msg: Message) -> Result<String, HandshakeError> {
+        let (x_i_plus_1, rk_i_plus_1) = self.pending.take().ok_or(HandshakeError::UnexpectedMessage)?;
+        let payload = decrypt(k3_s, ad, msg)?;
+        let (large_y_plus_one, nonce, c1) = split_payload(&payload)?;
+
+        // Recover the chains
+        let (rk_i_plus_2, ck_0) = kdf_rk(&rk_i_plus_1, &Group::dh(&x_i_plus_1, &large_y_plus_one));
+        let (_ck_1, mk_1) = kdf_ck(&ck_0);
+        let message = open_payload(&mk_1, ad, nonce, c1)?;
+
+        self.rk_i = rk_i_plus_2;
+        self.large_y_i = large_y_plus_one;
+        Ok(message)
+    }
+}
+
+/// Google's side: receives a message, then answers it.
+pub struct ServerRatchet {
+    rk_i: SecretKey,
+    y_i: Secret<DhScalar>,
+    /// X_i+1 and rk_i+1 while Alice waits for the answer.
+    pending: Option<(DhElement, SecretKey)>,
+}
+
+impl ServerRatchet {
+    /// Starts the ratchet from the 3DH session key `sk` and Google's ephemeral secret `y`.
+    pub fn new(sk: SecretKey, y: Secret<DhScalar>) -> Self {
+        Self { rk_i: sk, y_i: y, pending: None }
+    }
+
+    /// Decrypts a message from Alice.
+    pub fn open(&mut self, k3_c: &SecretKey, ad: &[u8], msg: Message) -> Result<String, HandshakeError> {
+        if self.pending.is_some() {
+            return Err(HandshakeError::UnexpectedMessage);
+        }
+        let payload = decrypt(k3_c, ad, msg)?;
+        let (large_x_plus_one, nonce, c1) = split_payload(&payload)?;
+
+        // Recover the chains
+        let (rk_i_plus_1, ck_0) = kdf_rk(&self.rk_i, &Group::dh(&self.y_i, &large_x_plus_one));
+        let (_ck_1, mk_1) = kdf_ck(&ck_0);
+        let message = open_payload(&mk_1, ad, nonce, c1)?;
+
+        self.pending = Some((large_x_plus_one, rk_i_plus_1));
+        Ok(message)
+    }
+
+    /// Encrypts the answer to the last opened message under a new ephemeral key.
+    pub fn seal(&mut self, k3_s: &SecretKey, ad: &[u8], message: &str) -> Result<Message, HandshakeError> {
+        let (large_x_plus_one, rk_i_plus_1) = self.pending.take().ok_or(HandshakeError::InvalidState)?;
+
+        // Encrypt the answer with DH Ratchet and Sym Ratchet
+        let y_i_plus_1 = Secret::new(Group::random_scalar());
+        let (rk_i_plus_2, ck_0) = kdf_rk(&rk_i_plus_1, &Group::dh(&y_i_plus_1, &large_x_plus_one));
+        let (_ck_1, mk_1) = kdf_ck(&ck_0);
+        let msg = seal_payload(k3_s, &mk_1, ad, Group::public_key(&y_i_plus_1), message)?;
+
+        self.rk_i = rk_i_plus_2;
+        self.y_i = y_i_plus_1;
+        Ok(msg)
+    }
+}
+
+/// Builds AEAD(k3, {{nonce, ephemeral_pk, AEAD(mk, message)}}).
+fn seal_payload(k3: &SecretKey, mk: &SecretKey, ad: &[u8], ephemeral_pk: DhElement, message: &str) -> Result<Message, HandshakeError> {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let c1 = crypto::aead::encrypt(mk, &nonce, message.as_bytes(), ad)
+        .map_err(|_| HandshakeError::Crypto("encryption"))?;
+
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&nonce);
+    payload.extend_from_slice(&Group::encode(&ephemeral_pk));
+    payload.extend_from_slice(&c1);
+    encrypt(k3, ad, &payload)
+}
+
+/// Splits a decrypted payload into the ephemeral key, the inner nonce and c1.
+fn split_payload(payload: &[u8]) -> Result<(DhElement, &[u8; 12], &[u8]), HandshakeError> {
+    if payload.len() < HEADER_LEN {
+        return Err(HandshakeError::Malformed("ratchet payload"));
+    }
+    let (header, c1) = payload.split_at(HEADER_LEN);
+    let (nonce, ephemeral_pk) = header.split_at(12);
+    Ok((decode_element(ephemeral_pk, "ratchet key")?, nonce.try_into().unwrap(), c1))
+}
+
+fn open_payload(mk: &SecretKey, ad: &[u8], nonce: &[u8; 12], c1: &[u8]) -> Result<String, HandshakeError> {
+    let message = crypto::aead::decrypt(mk, nonce, c1, ad).map_err(|_| HandshakeError::Decrypt)?;
+    Ok(String::from_utf8_lossy(&message).into_owned())
+}
+
+fn kdf_ck(ck_i: &SecretKey) -> (SecretKey, SecretKey) {
+    let ck_i_plus_1 = hmac::derive_key(ck_i.as_slice(), b"ChainKey");
+    let mk_i = hmac::derive_key(ck_i.as_slice(), b"MessageKey");
+
+    (ck_i_plus_1, mk_i)
+}
+
+fn kdf_rk(rk_i: &SecretKey, dh: &[u8]) -> (SecretKey, SecretKey) {
+    let (_, hk) = crypto::key_schedule::extract(Some(rk_i.as_slice()), dh);
+    let rk_i_plus_1 = crypto::key_schedule::expand::<32>(&hk, b"RootKey").unwrap();
+    let ck_i = crypto::key_schedule::expand::<32>(&hk, b"ChainKey").unwrap();
+
+    (rk_i_plus_1, ck_i)
+}
@@ -0,0 +1,407 @@
+//! Google's side: pq_tls, then the OPAQUE login for a stored record or the resumption of
+//! an earlier session, then the double ratchet.
+
+use super::ratchet::ServerRatchet;
+use super::alert::AlertChannel;
+use super::heartbeat::Heartbeats;
+use super::{confirmation_keys, decode_element, decode_point, decrypt, encrypt, negotiate_version, oprf_key_input, resumed_session_key, version_transcript, AlertDescription, Event, Handshake, HandshakeError, TrafficKeys};
+use crate::crypto;
+use crate::crypto::dh_group::{DhElement, DhGroup, DhScalar, Group};
+use crate::crypto::envelope;
+use crate::crypto::hmac::{compute_hmac, verify_hmac};
+use crate::crypto::key_schedule::{key_schedule_1, key_schedule_2, key_schedule_3};
+use crate::crypto::participant::{DatabaseContent, Message, CA};
+use crate::crypto::secret::{Secret, SecretKey};
+use crate::crypto::voprf;
+use crate::metrics::{Timer, METRICS};
+use crate::server::ake_keys::AkeKey;
+use aes_gcm::aead::OsRng;
+use elliptic_curve::group::GroupEncoding;
+use k256::Scalar;
+use kem::Encapsulate;
+use ml_dsa::signature::Signer;
+use ml_dsa::{KeyGen, KeyPair, MlDsa65, Seed};
+use ml_kem::kem::EncapsulationKey;
+use ml_kem::{EncodedSizeUser, MlKem768Params};
+use rand_core::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::mem;
+
+/// Length of an encoded ML-KEM-768 encapsulation key.
+const EK768_LEN: usize = 1184;
+
+/// Google's ML-DSA signing key and the CA's certificate for it, sent in every ServerHello.
+#[derive(Clone, Default)]
+pub struct ServerIdentity {
+    seed: Secret<[u8; 32]>,
+    /// Issued ahead of time. Without one, the CA certifies the key in every handshake.
+    certificate: Option<Vec<u8>>,
+}
+
+impl ServerIdentity {
+    pub fn new(seed: [u8; 32], certificate: Vec<u8>) -> Self {
+        ServerIdentity { seed: Secret::new(seed), certificate: Some(certificate) }
+    }
+
+    pub fn key_pair(&self) -> KeyPair<MlDsa65> {
+        MlDsa65::from_seed(&Seed::from(*self.seed))
+    }
+
+    /// The encoded certificate for `verifying_key`, this identity's key.
+    fn certificate(&self, ca: &CA, verifying_key: &[u8]) -> Vec<u8> {
+        match &self.certificate {
+            Some(certificate) => certificate.clone(),
+            None => ca.generate_certificate(verifying_key).encode().to_vec(),
+        }
+    }
+}
+
+/// Google's ML-DSA key pair when no identity was issued with srap-admin.
+pub fn server_key_pair() -> KeyPair<MlDsa65> {
+    ServerIdentity::default().key_pair()
+}
+
+enum State {
+    /// Waiting for the ClientHello.
+    ClientHello,
+    /// ServerHello and finished message queued, waiting for Alice's MAC. The timer of
+    /// pq_tls runs from the ClientHello.
+    ServerFinished { keys: Box<TrafficKeys>, mac_c_input: Vec<u8>, timer: Timer<'static> },
+    Established,
+    /// Login response queued, waiting for Alice's ephemeral key.
+    LoginResponse { lsk_s: Secret<DhScalar>, lpk_c: DhElement },
+    /// Ephemeral key queued, waiting for mac_c.
+    KeyConfirmation { sk: SecretKey, y: Secret<DhScalar>, kc: SecretKey, ks: SecretKey },
+    LoggedIn(ServerRatchet),
+    Failed,
+}
+
+pub struct ServerHandshake {
+    ca: CA,
+    identity: ServerIdentity,
+    ad: [u8; 13],
+    state: State,
+    /// Protocol version negotiated in the hellos.
+    version: Option<u16>,
+    keys: Option<TrafficKeys>,
+    session_key: Option<SecretKey>,
+    alerts: AlertChannel,
+    heartbeats: Heartbeats,
+    outgoing: VecDeque<Message>,
+    events: VecDeque<Event>,
+}
+
+impl ServerHandshake {
+    /// Waits for Alice's ClientHello, to answer it with the default identity.
+    pub fn new(ca: &CA, ad: &[u8; 13]) -> Self {
+        Self::with_identity(ca, &ServerIdentity::default(), ad)
+    }
+
+    /// Waits for Alice's ClientHello, to answer it as `identity`.
+    pub fn with_identity(ca: &CA, identity: &ServerIdentity, ad: &[u8; 13]) -> Self {
+        Self {
+            ca: ca.clone(),
+            identity: identity.clone(),
+            ad: *ad,
+            state: State::ClientHello,
+            version: None,
+            keys: None,
+            session_key: None,
+            alerts: AlertChannel::default(),
+            heartbeats: Heartbeats::default(),
+            outgoing: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Keys derived by pq_tls, once established.
+    pub fn keys(&self) -> Option<&TrafficKeys> {
+        self.keys.as_ref()
+    }
+
+    /// The 3DH session key, once Alice confirmed it.
+    pub fn session_key(&self) -> Option<&SecretKey> {
+        self.session_key.as_ref()
+    }
+
+    /// Encrypts a status reply for Alice under k3_s.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Message, HandshakeError> {
+        let keys = self.keys.as_ref().ok_or(HandshakeError::InvalidState)?;
+        encrypt(&keys.k3_s, &self.ad, plaintext)
+    }
+
+    /// Answers `username`'s blinded password `h_pw_a` for `record` once pq_tls is
+    /// established: queues the OPRF evaluation with its proof, the OPRF key signed with
+    /// Google's identity key and the masked credentials. Which record, OPRF key and AKE key
+    /// to use, and whether to answer at all, is up to the caller.
+    pub fn start_login(
+        &mut self,
+        h_pw_a: &[u8],
+        username: &[u8],
+        record: &DatabaseContent,
+        oprf_key: &Secret<Scalar>,
+        server_key: &AkeKey,
+    ) -> Result<(), HandshakeError> {
+        if !matches!(self.state, State::Established) {
+            return Err(HandshakeError::InvalidState);
+        }
+
+        // ----------- OPRF stage -----------
+        let timer = METRICS.oprf.start();
+        let h_pw_a = decode_point(h_pw_a, "blinded password")?;
+
+        // Evaluate the blinded element and prove that the certified OPRF key was used
+        let h_pw_as = h_pw_a * **oprf_key;
+        let proof = voprf::generate_proof(oprf_key, &h_pw_a, &h_pw_as).map_err(|_| HandshakeError::Crypto("DLEQ proof"))?;
+        let oprf_pk = voprf::public_key(oprf_key).to_bytes();
+        let oprf_pk_cert = self.identity.key_pair().signing_key().sign(&oprf_key_input(username, oprf_pk.as_slice())).encode();
+
+        // Mask lpk_s and the envelope under a fresh masking nonce
+        let mut masking_nonce = [0u8; envelope::NONCE_LEN];
+        OsRng.fill_bytes(&mut masking_nonce);
+        let lpk_s = server_key.public_key();
+        let masked_response = envelope::mask_response(record.masking_key.as_slice(), &masking_nonce, &lpk_s, &record.envelope)
+            .map_err(|_| HandshakeError::Crypto("masking"))?;
+
+        // {{key_id, h_pw^as, proof, oprf_pk, oprf_pk_cert, ksf, masking_nonce, masked_response}}
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&server_key.id.to_be_bytes());
+        msg.extend_from_slice(h_pw_as.to_bytes().as_slice());
+        msg.extend_from_slice(&proof.to_bytes());
+        msg.extend_from_slice(oprf_pk.as_slice());
+        msg.extend_from_slice(oprf_pk_cert.as_slice());
+        msg.extend_from_slice(&record.ksf.to_bytes());
+        msg.extend_from_slice(&masking_nonce);
+        msg.extend_from_slice(&masked_response);
+        let msg = self.seal(&msg)?;
+        self.outgoing.push_back(msg);
+        timer.succeed();
+
+        self.state = State::LoginResponse { lsk_s: server_key.private_key.clone(), lpk_c: record.lpk_c };
+        Ok(())
+    }
+
+    /// Resumes an earlier session once pq_tls is established: answers Alice's ephemeral
+    /// key `large_x` with Google's and keys the session with the resumption secret `psk`
+    /// from her ticket. Checking the ticket is up to the caller, key confirmation then
+    /// proves that Alice knows `psk`.
+    pub fn start_resume(&mut self, large_x: &[u8], psk: &SecretKey) -> Result<(), HandshakeError> {
+        if !matches!(self.state, State::Established) {
+            return Err(HandshakeError::InvalidState);
+        }
+        let large_x = decode_element(large_x, "ephemeral key")?;
+
+        let y = Secret::new(Group::random_scalar());
+        let reply = self.seal(&Group::encode(&Group::public_key(&y)))?;
+        self.outgoing.push_back(reply);
+
+        let sk = resumed_session_key(psk, &Group::dh(&y, &large_x));
+        let (kc, ks) = confirmation_keys(&sk);
+        self.state = State::KeyConfirmation { sk, y, kc, ks };
+        Ok(())
+    }
+
+    /// Answers the last message Alice sent over the double ratchet.
+    pub fn send_app_data(&mut self, message: &str) -> Result<(), HandshakeError> {
+        let (State::LoggedIn(ratchet), Some(keys)) = (&mut self.state, &self.keys) else {
+            return Err(HandshakeError::InvalidState);
+        };
+        let timer = METRICS.ratchet.start();
+        let msg = ratchet.seal(&keys.k3_s, &self.ad, message)?;
+        timer.succeed();
+        self.outgoing.push_back(msg);
+        Ok(())
+    }
+
+    fn client_hello(&mut self, msg: Message) -> Result<(), HandshakeError> {
+        let Message::PqtlsClientHello { versions, nonce_c, ek } = msg else {
+            return Err(HandshakeError::UnexpectedMessage);
+        };
+        let timer = METRICS.pq_tls.start();
+        let version = negotiate_version(&versions).ok_or(HandshakeError::UnsupportedVersion)?;
+        self.version = Some(version);
+        let ek_arr: [u8; EK768_LEN] = ek.as_slice().try_into().map_err(|_| HandshakeError::Malformed("encapsulation key"))?;
+        let ek = EncapsulationKey::<MlKem768Params>::from_bytes((&ek_arr).into());
+
+        let mut nonce_s: [u8; 8] = [0u8; 8];
+        OsRng.fill_bytes(&mut nonce_s);
+
+        // Generate key pair and calculate shared key and ciphertext
+        let key_pair = self.identity.key_pair();
+        let verifying_key = key_pair.verifying_key().encode();
+        let (ct, shared_key) = ek.encapsulate(&mut OsRng).map_err(|_| HandshakeError::Crypto("encapsulation"))?;
+        let shared_key = SecretKey::new(shared_key.into());
+
+        // Calculate K1_c, K1_s, K2_c, K2_s
+        let (k1_c, k1_s) = key_schedule_1(shared_key.as_slice());
+        let (k2_c, k2_s) = key_schedule_2(&nonce_c, &ek_arr, &nonce_s, verifying_key.as_slice(), shared_key.as_slice());
+
+        // Certificate for google's public key, then google's signature and MAC tag
+        let cert = self.identity.certificate(&self.ca, verifying_key.as_slice());
+        let transcript = [nonce_c.as_slice(), &ek_arr, &nonce_s, verifying_key.as_slice(), &version_transcript(&versions, version)].concat();
+        let sign_input = [transcript.as_slice(), cert.as_slice()].concat();
+        let google_sign = key_pair.signing_key().sign(&Sha256::digest(&sign_input)).encode();
+        let mac_s_input = [transcript.as_slice(), google_sign.as_slice(), cert.as_slice(), b"ServerMAC"].concat();
+        let mac_s = compute_hmac(k2_s.as_slice(), &Sha256::digest(&mac_s_input));
+
+        // Calculate K3_c, K3_s
+        let (k3_c, k3_s) = key_schedule_3(
+            &nonce_c,
+            &ek_arr,
+            &nonce_s,
+            verifying_key.as_slice(),
+            shared_key.as_slice(),
+            google_sign.as_slice(),
+            cert.as_slice(),
+            &mac_s,
+        );
+        self.alerts.set_keys(&k3_s, &k3_c);
+
+        // Send nonce_s, ct, verifying_key, then AEAD(k1_s, {{cert, google_sign, mac_s}})
+        self.outgoing.push_back(Message::PqtlsServerHello {
+            version,
+            nonce_s: nonce_s.to_vec(),
+            ct: ct.to_vec(),
+            verifying_key: verifying_key.to_vec(),
+        });
+        let finished = [cert.as_slice(), google_sign.as_slice(), &mac_s].concat();
+        self.outgoing.push_back(encrypt(&k1_s, &self.ad, &finished)?);
+
+        let mac_c_input = [transcript.as_slice(), google_sign.as_slice(), cert.as_slice(), b"ClientMAC"].concat();
+        let keys = Box::new(TrafficKeys { k1_c, k1_s, k2_c, k2_s, k3_c, k3_s });
+        self.state = State::ServerFinished { keys, mac_c_input, timer };
+        Ok(())
+    }
+
+    fn client_finished(&mut self, keys: TrafficKeys, mac_c_input: &[u8], timer: Timer<'static>, msg: Message) -> Result<(), HandshakeError> {
+        // Verify the MAC tag from Alice
+        let mac_c = decrypt(&keys.k1_c, &self.ad, msg)?;
+        if !verify_hmac(keys.k2_c.as_slice(), &Sha256::digest(mac_c_input), &mac_c) {
+            return Err(HandshakeError::AuthenticationFailed("client MAC"));
+        }
+        timer.succeed();
+
+        self.keys = Some(keys);
+        self.alerts.establish();
+        self.state = State::Established;
+        self.events.push_back(Event::Established);
+        Ok(())
+    }
+
+    fn client_ephemeral(&mut self, lsk_s: &DhScalar, lpk_c: DhElement, msg: Message) -> Result<(), HandshakeError> {
+        let large_x = decode_element(&self.open(msg)?, "ephemeral key")?;
+
+        // ----------- AKE stage: 3DH -----------
+        let timer = METRICS.ake.start();
+        let y = Secret::new(Group::random_scalar());
+        let reply = self.seal(&Group::encode(&Group::public_key(&y)))?;
+        self.outgoing.push_back(reply);
+
+        // 3DH-KServer (𝑏, 𝑦, 𝐴, 𝑋)
+        let mut key_input = Secret::new(Vec::new());
+        key_input.extend_from_slice(&Group::dh(lsk_s, &large_x));
+        key_input.extend_from_slice(&Group::dh(&y, &large_x));
+        key_input.extend_from_slice(&Group::dh(&y, &lpk_c));
+        let (sk, _) = crypto::key_schedule::extract(None, key_input.as_slice());
+        timer.succeed();
+
+        let (kc, ks) = confirmation_keys(&sk);
+        self.state = State::KeyConfirmation { sk, y, kc, ks };
+        Ok(())
+    }
+
+    fn client_mac(&mut self, sk: SecretKey, y: Secret<DhScalar>, kc: &SecretKey, ks: &SecretKey, msg: Message) -> Result<(), HandshakeError> {
+        // ----------- Key Confirmation -----------
+        let mac_c = self.open(msg)?;
+        if !verify_hmac(kc.as_slice(), b"Client KC", &mac_c) {
+            return Err(HandshakeError::InvalidCredentials);
+        }
+        let reply = self.seal(&compute_hmac(ks.as_slice(), b"Server KC"))?;
+        self.outgoing.push_back(reply);
+
+        self.session_key = Some(sk.clone());
+        self.state = State::LoggedIn(ServerRatchet::new(sk, y));
+        self.events.push_back(Event::LoginSucceeded);
+        Ok(())
+    }
+
+    /// Ends the session if `msg` is an alert from Alice, answering a close_notify.
+    fn read_alert(&mut self, msg: &Message) -> Result<(), HandshakeError> {
+        let Some(description) = self.alerts.read(&self.ad, msg)? else {
+            return Ok(());
+        };
+        if description == AlertDescription::CloseNotify {
+            self.outgoing.extend(self.alerts.seal(&self.ad, description));
+        }
+        self.alerts.close();
+        self.end_session();
+        Err(HandshakeError::Alert(description))
+    }
+
+    /// Drops the state and keys of the session.
+    fn end_session(&mut self) {
+        self.state = State::Failed;
+        self.keys = None;
+        self.session_key = None;
+        self.events.clear();
+    }
+}
+
+impl Handshake for ServerHandshake {
+    fn handle(&mut self, msg: Message) -> Result<(), HandshakeError> {
+        self.read_alert(&msg)?;
+
+        // The state is only put back once the message was accepted
+        match mem::replace(&mut self.state, State::Failed) {
+            State::ClientHello => self.client_hello(msg),
+            State::ServerFinished { keys, mac_c_input, timer } => self.client_finished(*keys, &mac_c_input, timer, msg),
+            State::LoginResponse { lsk_s, lpk_c } => self.client_ephemeral(&lsk_s, lpk_c, msg),
+            State::KeyConfirmation { sk, y, kc, ks } => self.client_mac(sk, y, &kc, &ks, msg),
+            State::LoggedIn(ratchet) if matches!(msg, Message::Heartbeat { .. }) => {
+                let keys = self.keys.as_ref().ok_or(HandshakeError::InvalidState)?;
+                let reply = self.heartbeats.answer(&keys.k3_s, &keys.k3_c, &self.ad, &msg)?;
+                self.outgoing.push_back(reply);
+                self.state = State::LoggedIn(ratchet);
+                self.events.push_back(Event::Heartbeat);
+                Ok(())
+            }
+            State::LoggedIn(mut ratchet) => {
+                let keys = self.keys.as_ref().ok_or(HandshakeError::InvalidState)?;
+                let timer = METRICS.ratchet.start();
+                let message = ratchet.open(&keys.k3_c, &self.ad, msg)?;
+                timer.succeed();
+                self.state = State::LoggedIn(ratchet);
+                self.events.push_back(Event::AppData(message));
+                Ok(())
+            }
+            State::Established | State::Failed => Err(HandshakeError::UnexpectedMessage),
+        }
+    }
+
+    fn open(&mut self, msg: Message) -> Result<Vec<u8>, HandshakeError> {
+        self.read_alert(&msg)?;
+        let keys = self.keys.as_ref().ok_or(HandshakeError::InvalidState)?;
+        decrypt(&keys.k3_c, &self.ad, msg)
+    }
+
+    fn alert(&mut self, description: AlertDescription) {
+        self.outgoing.extend(self.alerts.seal(&self.ad, description));
+        if description.is_fatal() {
+            self.alerts.close();
+        }
+        self.end_session();
+    }
+
+    fn poll_transmit(&mut self) -> Option<Message> {
+        self.outgoing.pop_front()
+    }
+
+    fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    fn version(&self) -> Option<u16> {
+        self.version
+    }
+}
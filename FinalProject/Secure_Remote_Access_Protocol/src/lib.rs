@@ -0,0 +1,14 @@
+//! Secure Remote Access Protocol: pq_tls, an OPAQUE login with a second factor and a
+//! double ratchet session between Alice (`client`) and Google (`server`).
+//!
+//! The library is shared by the demo in `main.rs`, which runs both sides in one process,
+//! and the `srap-admin` tool for Google's stored data.
+
+pub mod client;
+pub mod crypto;
+pub mod handshake;
+pub mod metrics;
+pub mod server;
+pub mod transport;
+#[cfg(test)]
+mod tests;
@@ -1,27 +1,47 @@
-use crate::crypto::participant;
-use elliptic_curve::Group;
-use k256::ProjectivePoint;
-use rand_core::OsRng;
-use crate::client::alice::alice;
-use crate::server::google::google;
-
-mod crypto;
-mod tests;
-mod client;
-mod server;
+use srap::client;
+use srap::client::blocking::alice;
+use srap::crypto::participant;
+use srap::server;
+use srap::server::blocking::google;
 
 fn main() {
-    let mut ca = participant::CA::new();
+    // `--verify-audit [file]` checks Google's audit trail instead of running the protocol
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i) = args.iter().position(|arg| arg == "--verify-audit") {
+        let path = args.get(i + 1).map_or(server::google::AUDIT_LOG_PATH, String::as_str);
+        // Only reads the audit key, a missing one is not created
+        let store = server::store::Store::new(server::store::DATA_DIR);
+        let verifying_key = match store.audit_verifying_key() {
+            Ok(verifying_key) => verifying_key,
+            Err(e) => {
+                eprintln!("Cannot load the audit key from {}: {e}", server::store::DATA_DIR);
+                std::process::exit(1);
+            }
+        };
+        std::process::exit(server::audit::verify_file(path, &verifying_key) as i32);
+    }
+
+    // The CA Google's certificate was issued by, shared with Alice
+    let mut ca = server::store::Store::new(server::store::DATA_DIR).ca().unwrap_or_else(|e| {
+        eprintln!("Cannot load the CA from {}: {e}", server::store::DATA_DIR);
+        participant::CA::new()
+    });
     let mut ca_clone = ca.clone();
-    let mut g: ProjectivePoint = ProjectivePoint::random(&mut OsRng);
 
     let handle = std::thread::spawn(move || {
-        google(&mut ca_clone, &mut g);
+        google(&mut ca_clone);
     });
 
     std::thread::sleep(std::time::Duration::from_millis(500));
 
-    alice(&mut ca, &mut g);
+    // `--gui` starts the eframe client instead of the terminal one
+    if std::env::args().any(|arg| arg == "--gui") {
+        if let Err(e) = client::gui::run(ca) {
+            eprintln!("Alice: GUI error: {e}");
+        }
+    } else {
+        alice(&mut ca);
+    }
 
     handle.join().unwrap();
 }
\ No newline at end of file
@@ -0,0 +1,71 @@
+//! Minimal HTTP endpoint for Prometheus: `GET /metrics` answers with the metrics in the
+//! text exposition format, every other request with an error. One request per connection.
+
+use super::Metrics;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+/// Local address Google serves its metrics on.
+pub const METRICS_ADDR: &str = "127.0.0.1:9100";
+
+/// Longest request head accepted from a scraper.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// Longest time a scraper may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answers scrapes of `metrics` on `listener` until the process exits.
+pub async fn serve(listener: TcpListener, metrics: &'static Metrics) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("Metrics accept error: {e}");
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            match time::timeout(REQUEST_TIMEOUT, answer(stream, metrics)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => tracing::debug!("Metrics request error: {e}"),
+                Err(_) => tracing::debug!("Metrics request timed out"),
+            }
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    // Read the request head, the body of a GET is ignored
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = String::from_utf8_lossy(request.split(|&b| b == b'\r').next().unwrap_or_default()).into_owned();
+    let mut parts = request_line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => respond(&mut stream, "200 OK", &metrics.render()).await,
+        (Some("GET"), _) => respond(&mut stream, "404 Not Found", "Not found\n").await,
+        _ => respond(&mut stream, "405 Method Not Allowed", "Method not allowed\n").await,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
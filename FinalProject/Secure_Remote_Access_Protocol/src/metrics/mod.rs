@@ -0,0 +1,159 @@
+//! Counters and latency histograms of the server, in the Prometheus text format.
+//!
+//! Every stage of the protocol is a `Stage`: a count of runs by result and a histogram
+//! of their durations. `METRICS` is process-wide, so the sans-IO handshake can record its
+//! own steps, and `http` serves it to a scraper.
+//!
+//! - `pq_tls`: Google's side of pq_tls, from the ClientHello to Alice's finished MAC.
+//! - `oprf`: evaluation of a blinded password, with its proof and masked response.
+//! - `ake`: Google's 3DH computation.
+//! - `ratchet`: one double ratchet step, sealing or opening a message.
+//! - `login`: the OPRF stage and AKE up to key confirmation, as seen by the connection.
+
+pub mod http;
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+pub static METRICS: Metrics = Metrics {
+    pq_tls: Stage::new("pq_tls", "pq_tls handshakes"),
+    oprf: Stage::new("oprf", "OPRF evaluations"),
+    ake: Stage::new("ake", "3DH key exchanges"),
+    ratchet: Stage::new("ratchet", "Double ratchet steps"),
+    login: Stage::new("login", "Logins up to key confirmation"),
+};
+
+pub struct Metrics {
+    pub pq_tls: Stage,
+    pub oprf: Stage,
+    pub ake: Stage,
+    pub ratchet: Stage,
+    pub login: Stage,
+}
+
+impl Metrics {
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for stage in [&self.pq_tls, &self.oprf, &self.ake, &self.ratchet, &self.login] {
+            stage.render(&mut out);
+        }
+        out
+    }
+}
+
+/// Runs of one stage, by result, and how long they took.
+pub struct Stage {
+    name: &'static str,
+    help: &'static str,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    /// Runs per bucket of `BUCKETS`, not cumulative.
+    buckets: [AtomicU64; BUCKETS.len()],
+    /// Total duration of all runs, in nanoseconds.
+    sum_nanos: AtomicU64,
+}
+
+impl Stage {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Stage {
+            name,
+            help,
+            succeeded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// Starts timing a run, which counts as failed unless it is marked as succeeded.
+    pub fn start(&self) -> Timer<'_> {
+        Timer { stage: self, started: Instant::now(), succeeded: false }
+    }
+
+    pub fn record(&self, duration: Duration, succeeded: bool) {
+        let counter = if succeeded { &self.succeeded } else { &self.failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_nanos.fetch_add(duration.as_nanos().try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        let (name, help) = (self.name, self.help);
+        let succeeded = self.succeeded.load(Ordering::Relaxed);
+        let failed = self.failed.load(Ordering::Relaxed);
+
+        // Writing to a String cannot fail
+        let _ = writeln!(out, "# HELP srap_{name}_total {help}, by result.");
+        let _ = writeln!(out, "# TYPE srap_{name}_total counter");
+        let _ = writeln!(out, "srap_{name}_total{{result=\"ok\"}} {succeeded}");
+        let _ = writeln!(out, "srap_{name}_total{{result=\"error\"}} {failed}");
+
+        let _ = writeln!(out, "# HELP srap_{name}_seconds Duration of the {name} stage.");
+        let _ = writeln!(out, "# TYPE srap_{name}_seconds histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "srap_{name}_seconds_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let count = succeeded + failed;
+        let _ = writeln!(out, "srap_{name}_seconds_bucket{{le=\"+Inf\"}} {count}");
+        let sum = Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)).as_secs_f64();
+        let _ = writeln!(out, "srap_{name}_seconds_sum {sum}");
+        let _ = writeln!(out, "srap_{name}_seconds_count {count}");
+    }
+}
+
+/// A run of a `Stage`, recorded when dropped. Runs that end early, with an error or an
+/// unwind, count as failed.
+pub struct Timer<'a> {
+    stage: &'a Stage,
+    started: Instant,
+    succeeded: bool,
+}
+
+impl Timer<'_> {
+    pub fn succeed(mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.stage.record(self.started.elapsed(), self.succeeded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_render_counters_and_cumulative_histograms() {
+        let stage = Stage::new("oprf", "OPRF evaluations");
+        stage.record(Duration::from_micros(200), true);
+        stage.record(Duration::from_millis(20), true);
+        stage.record(Duration::from_secs(2), false);
+        drop(stage.start());
+        stage.start().succeed();
+
+        let mut out = String::new();
+        stage.render(&mut out);
+        assert!(out.contains("# TYPE srap_oprf_total counter\n"));
+        assert!(out.contains("srap_oprf_total{result=\"ok\"} 3\n"));
+        assert!(out.contains("srap_oprf_total{result=\"error\"} 2\n"));
+        assert!(out.contains("# TYPE srap_oprf_seconds histogram\n"));
+        assert!(out.contains("srap_oprf_seconds_bucket{le=\"0.0005\"} 3\n"));
+        assert!(out.contains("srap_oprf_seconds_bucket{le=\"0.025\"} 4\n"));
+        assert!(out.contains("srap_oprf_seconds_bucket{le=\"1\"} 4\n"));
+        assert!(out.contains("srap_oprf_seconds_bucket{le=\"+Inf\"} 5\n"));
+        assert!(out.contains("srap_oprf_seconds_count 5\n"));
+    }
+}
@@ -0,0 +1,115 @@
+//! Google's long-term 3DH key pairs.
+//!
+//! All users share the current key pair. Every registration record names the key its
+//! envelope was created for, and the login response tells Alice its ID. After a rotation
+//! the previous key is still accepted for `overlap`, and a login with it ends with Alice
+//! re-wrapping her envelope for the current key. Once the overlap ended, users who did
+//! not log in have to register again.
+//!
+//! Only the private keys are kept, the public keys are derived in the DH group, see
+//! `crypto::dh_group`.
+
+use crate::crypto::dh_group::{DhElement, DhGroup, DhScalar, Group};
+use crate::crypto::secret::Secret;
+use std::time::{Duration, SystemTime};
+
+/// How long the previous key is accepted after a rotation, unless given otherwise.
+pub const DEFAULT_OVERLAP: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Clone)]
+pub struct AkeKey {
+    pub id: u32,
+    pub private_key: Secret<DhScalar>,
+    /// When the key stops being accepted, none for the current key.
+    pub retires_at: Option<SystemTime>,
+}
+
+impl AkeKey {
+    fn generate(id: u32) -> Self {
+        AkeKey { id, private_key: Secret::new(Group::random_scalar()), retires_at: None }
+    }
+
+    pub fn public_key(&self) -> DhElement {
+        Group::public_key(&self.private_key)
+    }
+}
+
+/// The current key, last, and the previous ones still in their overlap.
+#[derive(Clone)]
+pub struct AkeKeys {
+    keys: Vec<AkeKey>,
+}
+
+impl Default for AkeKeys {
+    /// A single fresh key with ID 1.
+    fn default() -> Self {
+        AkeKeys { keys: vec![AkeKey::generate(1)] }
+    }
+}
+
+impl AkeKeys {
+    /// Keys loaded from the store, `None` unless exactly the last one is current.
+    pub fn from_keys(keys: Vec<AkeKey>) -> Option<Self> {
+        let (current, previous) = keys.split_last()?;
+        if current.retires_at.is_some() || previous.iter().any(|key| key.retires_at.is_none()) {
+            return None;
+        }
+        Some(AkeKeys { keys })
+    }
+
+    pub fn keys(&self) -> &[AkeKey] {
+        &self.keys
+    }
+
+    pub fn current(&self) -> &AkeKey {
+        // Never empty, see `from_keys`
+        &self.keys[self.keys.len() - 1]
+    }
+
+    /// The key with `id`, unless it retired before `now`.
+    pub fn get(&self, id: u32, now: SystemTime) -> Option<&AkeKey> {
+        self.keys
+            .iter()
+            .find(|key| key.id == id)
+            .filter(|key| key.retires_at.is_none_or(|retires_at| retires_at > now))
+    }
+
+    /// Makes a fresh key current. The previous one is accepted for `overlap`, keys that
+    /// retired before `now` are dropped.
+    pub fn rotate(&mut self, overlap: Duration, now: SystemTime) -> &AkeKey {
+        let id = self.current().id + 1;
+        for key in self.keys.iter_mut().filter(|key| key.retires_at.is_none()) {
+            key.retires_at = Some(now + overlap);
+        }
+        self.keys.retain(|key| key.retires_at.is_some_and(|retires_at| retires_at > now));
+        self.keys.push(AkeKey::generate(id));
+        self.current()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previous_key_is_accepted_until_the_overlap_ends() {
+        let now = SystemTime::now();
+        let mut keys = AkeKeys::default();
+        let first = keys.current().clone();
+        assert_eq!(keys.rotate(Duration::from_secs(60), now).id, first.id + 1);
+
+        assert_eq!(keys.current().id, 2);
+        assert_eq!(keys.get(1, now).unwrap().public_key(), first.public_key());
+        assert!(keys.get(1, now + Duration::from_secs(61)).is_none());
+        assert!(keys.get(2, now + Duration::from_secs(61)).is_some());
+        assert!(keys.get(3, now).is_none());
+
+        // A later rotation drops the retired key and keeps the other one for its overlap
+        keys.rotate(Duration::from_secs(60), now + Duration::from_secs(120));
+        let ids: Vec<u32> = keys.keys().iter().map(|key| key.id).collect();
+        assert_eq!(ids, [2, 3]);
+        assert!(AkeKeys::from_keys(keys.keys().to_vec()).is_some());
+        assert!(AkeKeys::from_keys(keys.keys()[..1].to_vec()).is_none());
+        assert!(AkeKeys::from_keys(Vec::new()).is_none());
+    }
+}
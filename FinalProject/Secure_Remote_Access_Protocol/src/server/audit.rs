@@ -0,0 +1,404 @@
+//! Append-only, tamper-evident audit trail of security events.
+//!
+//! Kept apart from the diagnostic log in `tracing`: one line per event, with its time,
+//! the username it concerns and the peer address, for example
+//!
+//! ```text
+//! time=1760873000.123 event=login_failed user="alice" peer=127.0.0.1 mac=5c0f…
+//! ```
+//!
+//! Entries never contain passwords, keys, TOTP codes or tickets. Usernames are chosen by
+//! the client, so they are written escaped and quoted and cannot forge a line.
+//!
+//! Every line ends with `mac = HMAC(previous mac, line)`, starting from `GENESIS`, so a
+//! deleted, reordered or altered line breaks the chain at the line after it. Anyone can
+//! recompute the chain, so every `CHECKPOINT_EVERY` entries (and on demand) Google also
+//! appends a checkpoint signing the chain head with its audit key, see `Store::audit_key_pair`.
+//! `verify` checks both; entries after the last checkpoint are only as trustworthy as the
+//! file itself.
+
+use crate::crypto::hmac::{compute_hmac, verify_hmac};
+use ml_dsa::signature::{Signer, Verifier};
+use ml_dsa::{KeyPair, MlDsa65, Signature, VerifyingKey};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Key of the first entry's MAC.
+const GENESIS: [u8; 32] = [0u8; 32];
+
+/// Entries after which a checkpoint is written.
+pub const CHECKPOINT_EVERY: u64 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A new account was registered.
+    Registered,
+    /// A registration was refused, e.g. because the username is taken.
+    RegistrationRejected,
+    /// Key confirmation succeeded.
+    LoginSucceeded,
+    /// A counted login attempt did not reach key confirmation.
+    LoginFailed,
+    /// A login was refused by the rate limiter, before the OPRF stage.
+    LoginRefused,
+    /// The failure that locked the account.
+    Lockout,
+    /// A wrong TOTP code after key confirmation.
+    SecondFactorFailed,
+    PasswordChanged,
+    AccountDeleted,
+    SessionResumed,
+    ResumeRefused,
+    /// Alice re-wrapped her envelope for the current AKE key of the server.
+    EnvelopeRewrapped,
+    /// Alice rejected a certificate of the server with a bad_certificate alert.
+    CertificateRejected,
+}
+
+impl AuditEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditEvent::Registered => "registered",
+            AuditEvent::RegistrationRejected => "registration_rejected",
+            AuditEvent::LoginSucceeded => "login_succeeded",
+            AuditEvent::LoginFailed => "login_failed",
+            AuditEvent::LoginRefused => "login_refused",
+            AuditEvent::Lockout => "lockout",
+            AuditEvent::SecondFactorFailed => "second_factor_failed",
+            AuditEvent::PasswordChanged => "password_changed",
+            AuditEvent::AccountDeleted => "account_deleted",
+            AuditEvent::SessionResumed => "session_resumed",
+            AuditEvent::ResumeRefused => "resume_refused",
+            AuditEvent::EnvelopeRewrapped => "envelope_rewrapped",
+            AuditEvent::CertificateRejected => "certificate_rejected",
+        }
+    }
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Audit trail of the server. The default one records nothing.
+#[derive(Default)]
+pub struct AuditLog {
+    file: Option<File>,
+    /// Signs the checkpoints.
+    key_pair: Option<KeyPair<MlDsa65>>,
+    /// Where the chain stands after the last line.
+    chain: Chain,
+}
+
+impl AuditLog {
+    /// Appends to the audit file at `path`, creating it if needed, and continues the
+    /// chain of the lines already in it. Checkpoints are signed with `key_pair`.
+    pub fn open(path: impl AsRef<Path>, key_pair: KeyPair<MlDsa65>) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut log = String::new();
+        file.read_to_string(&mut log)?;
+        let chain = Chain::resume(&log)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "audit log does not end with a chained entry"))?;
+        Ok(AuditLog { file: Some(file), key_pair: Some(key_pair), chain })
+    }
+
+    /// Appends `event` concerning `username`, or the whole connection if `None`, from `peer`.
+    pub fn record(&mut self, event: AuditEvent, username: Option<&[u8]>, peer: IpAddr) {
+        let mut line = format!("{} event={event}", timestamp(SystemTime::now()));
+        if let Some(username) = username {
+            line += &format!(" user={:?}", String::from_utf8_lossy(username));
+        }
+        line += &format!(" peer={peer}");
+        self.append(line);
+
+        if self.chain.unsigned >= CHECKPOINT_EVERY {
+            self.checkpoint();
+        }
+    }
+
+    /// Signs the chain head, unless every entry is already covered by a checkpoint.
+    pub fn checkpoint(&mut self) {
+        let Some(key_pair) = &self.key_pair else {
+            return;
+        };
+        if self.chain.unsigned == 0 {
+            return;
+        }
+        let signature = key_pair.signing_key().sign(&checkpoint_input(self.chain.entries, &self.chain.mac));
+        let line = format!(
+            "{} event=checkpoint entries={} sig={}",
+            timestamp(SystemTime::now()),
+            self.chain.entries,
+            to_hex(&signature.encode())
+        );
+        self.append(line);
+        self.chain.unsigned = 0;
+    }
+
+    fn append(&mut self, line: String) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let mac = compute_hmac(&self.chain.mac, line.as_bytes());
+        // A single write per line, so a crash never leaves half an entry before the next
+        if let Err(e) = file.write_all(format!("{line} mac={}\n", to_hex(&mac)).as_bytes()) {
+            tracing::error!("Audit log write error: {e}");
+            return;
+        }
+        self.chain.mac = mac;
+        self.chain.entries += 1;
+        self.chain.unsigned += 1;
+    }
+}
+
+/// Position in the chain after some lines of a log.
+#[derive(Debug, PartialEq, Eq)]
+struct Chain {
+    /// MAC of the last line, the key of the next one.
+    mac: Vec<u8>,
+    /// Lines so far, checkpoints included.
+    entries: u64,
+    /// Lines since the last checkpoint.
+    unsigned: u64,
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Chain { mac: GENESIS.to_vec(), entries: 0, unsigned: 0 }
+    }
+}
+
+impl Chain {
+    /// Where the chain of `log` ends, without checking it. `None` if a line carries
+    /// no MAC.
+    fn resume(log: &str) -> Option<Chain> {
+        let mut chain = Chain::default();
+        for line in log.lines() {
+            let (body, mac) = line.rsplit_once(" mac=")?;
+            chain.mac = from_hex(mac)?;
+            chain.entries += 1;
+            chain.unsigned = if is_checkpoint(body) { 0 } else { chain.unsigned + 1 };
+        }
+        Some(chain)
+    }
+}
+
+/// Outcome of a successful `verify`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Verified {
+    /// Lines in the log, checkpoints included.
+    pub entries: u64,
+    /// Lines after the last checkpoint, which no signature covers.
+    pub unsigned: u64,
+}
+
+/// Where `verify` found the log tampered with. Lines are numbered from 1.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuditError {
+    /// The line has no MAC, or a checkpoint cannot be parsed.
+    Malformed(usize),
+    /// The MAC does not follow from the line before: a line was deleted, reordered or
+    /// altered at or just before this one.
+    BrokenChain(usize),
+    /// A checkpoint whose signature does not cover the chain before it.
+    BadCheckpoint(usize),
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditError::Malformed(line) => write!(f, "line {line} is malformed"),
+            AuditError::BrokenChain(line) => write!(f, "chain broken at line {line}: an entry was deleted, reordered or altered"),
+            AuditError::BadCheckpoint(line) => write!(f, "checkpoint at line {line} is not signed by the server"),
+        }
+    }
+}
+
+/// Checks the chain of `log` and the checkpoint signatures against `verifying_key`.
+pub fn verify(log: &str, verifying_key: &VerifyingKey<MlDsa65>) -> Result<Verified, AuditError> {
+    let mut chain = Chain::default();
+    for (index, line) in log.lines().enumerate() {
+        let number = index + 1;
+        let (body, mac) = line.rsplit_once(" mac=").ok_or(AuditError::Malformed(number))?;
+        let mac = from_hex(mac).ok_or(AuditError::Malformed(number))?;
+        if !verify_hmac(&chain.mac, body.as_bytes(), &mac) {
+            return Err(AuditError::BrokenChain(number));
+        }
+
+        if is_checkpoint(body) {
+            let field = |name: &str| body.split(' ').find_map(|field| field.strip_prefix(name));
+            let entries = field("entries=").and_then(|entries| entries.parse::<u64>().ok());
+            let signature = field("sig=")
+                .and_then(from_hex)
+                .and_then(|signature| Signature::<MlDsa65>::try_from(signature.as_slice()).ok());
+            let (Some(entries), Some(signature)) = (entries, signature) else {
+                return Err(AuditError::Malformed(number));
+            };
+            if entries != chain.entries || verifying_key.verify(&checkpoint_input(entries, &chain.mac), &signature).is_err() {
+                return Err(AuditError::BadCheckpoint(number));
+            }
+            chain.unsigned = 0;
+        } else {
+            chain.unsigned += 1;
+        }
+        chain.mac = mac;
+        chain.entries += 1;
+    }
+    Ok(Verified { entries: chain.entries, unsigned: chain.unsigned })
+}
+
+/// Verifies the audit file at `path` and reports the outcome. Returns true on error.
+pub fn verify_file(path: impl AsRef<Path>, verifying_key: &VerifyingKey<MlDsa65>) -> bool {
+    let log = match std::fs::read_to_string(&path) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("Audit: Cannot read {}: {e}", path.as_ref().display());
+            return true;
+        }
+    };
+    match verify(&log, verifying_key) {
+        Ok(Verified { entries, unsigned }) => {
+            println!("Audit: {entries} entries verified");
+            if unsigned > 0 {
+                println!("Audit: The last {unsigned} entries are not covered by a checkpoint yet");
+            }
+            false
+        }
+        Err(e) => {
+            eprintln!("Audit: {e}");
+            true
+        }
+    }
+}
+
+/// Checkpoints sign the number of lines before them and the MAC of the last one.
+fn checkpoint_input(entries: u64, mac: &[u8]) -> Vec<u8> {
+    [b"SRAP audit checkpoint;".as_slice(), &entries.to_be_bytes(), mac].concat()
+}
+
+/// The event is always the second field, before any client-chosen username.
+fn is_checkpoint(body: &str) -> bool {
+    body.split(' ').nth(1) == Some("event=checkpoint")
+}
+
+fn timestamp(time: SystemTime) -> String {
+    let time = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    format!("time={}.{:03}", time.as_secs(), time.subsec_millis())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::server::server_key_pair;
+    use rand_core::{OsRng, RngCore};
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("srap_audit_{}.log", OsRng.next_u64()))
+    }
+
+    /// Writes `entries` entries to a new log, with a checkpoint after the first two.
+    fn write_log(entries: usize) -> String {
+        let path = temp_path();
+        let mut log = AuditLog::open(&path, server_key_pair()).unwrap();
+        for i in 0..entries {
+            log.record(AuditEvent::LoginFailed, Some(format!("user{i}").as_bytes()), PEER);
+            if i == 1 {
+                log.checkpoint();
+            }
+        }
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        contents
+    }
+
+    fn verify_lines(lines: &[&str]) -> Result<Verified, AuditError> {
+        verify(&lines.iter().map(|line| format!("{line}\n")).collect::<String>(), server_key_pair().verifying_key())
+    }
+
+    #[test]
+    fn entries_are_single_escaped_lines() {
+        let path = temp_path();
+        let mut audit = AuditLog::open(&path, server_key_pair()).unwrap();
+        audit.record(AuditEvent::Lockout, Some(b"eve\" peer=10.0.0.1\ntime=0"), PEER);
+        audit.record(AuditEvent::CertificateRejected, None, PEER);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(" event=lockout user=\"eve\\\" peer=10.0.0.1\\ntime=0\" peer=127.0.0.1 mac="));
+        assert!(lines[1].contains(" event=certificate_rejected peer=127.0.0.1 mac="));
+    }
+
+    #[test]
+    fn untouched_logs_verify() {
+        let log = write_log(5);
+        assert_eq!(verify(&log, server_key_pair().verifying_key()), Ok(Verified { entries: 6, unsigned: 3 }));
+    }
+
+    #[test]
+    fn deleted_reordered_and_altered_entries_are_detected() {
+        let log = write_log(5);
+        let lines: Vec<&str> = log.lines().collect();
+
+        let mut deleted = lines.clone();
+        deleted.remove(3);
+        assert_eq!(verify_lines(&deleted), Err(AuditError::BrokenChain(4)));
+
+        let mut reordered = lines.clone();
+        reordered.swap(3, 4);
+        assert_eq!(verify_lines(&reordered), Err(AuditError::BrokenChain(4)));
+
+        let altered = lines[4].replace("user3", "user5");
+        let mut modified = lines.clone();
+        modified[4] = &altered;
+        assert_eq!(verify_lines(&modified), Err(AuditError::BrokenChain(5)));
+    }
+
+    #[test]
+    fn rewritten_chains_fail_at_the_checkpoint() {
+        // Alter the first entry and recompute every MAC, as anyone can
+        let log = write_log(5);
+        let mut chain = Chain::default();
+        let mut forged = String::new();
+        for line in log.replacen("user0", "user9", 1).lines() {
+            let (body, _) = line.rsplit_once(" mac=").unwrap();
+            chain.mac = compute_hmac(&chain.mac, body.as_bytes());
+            forged += &format!("{body} mac={}\n", to_hex(&chain.mac));
+        }
+        assert_eq!(verify(&forged, server_key_pair().verifying_key()), Err(AuditError::BadCheckpoint(3)));
+    }
+
+    #[test]
+    fn reopened_logs_continue_the_chain() {
+        let path = temp_path();
+        AuditLog::open(&path, server_key_pair()).unwrap().record(AuditEvent::Registered, Some(b"alice"), PEER);
+        let mut audit = AuditLog::open(&path, server_key_pair()).unwrap();
+        audit.record(AuditEvent::LoginSucceeded, Some(b"alice"), PEER);
+        audit.checkpoint();
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(verify(&log, server_key_pair().verifying_key()), Ok(Verified { entries: 3, unsigned: 0 }));
+    }
+}
@@ -0,0 +1,14 @@
+//! Synchronous entry point to the async server in `google`.
+
+use crate::crypto::participant::CA;
+use crate::server::google;
+use tokio::runtime::Builder;
+
+/// Runs Google on a multi-threaded runtime until the process exits.
+pub fn google(ca: &mut CA) {
+    let runtime = Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Error starting the tokio runtime");
+    runtime.block_on(google::serve(ca));
+}
@@ -1,654 +1,996 @@
 use crate::crypto;
+use crate::crypto::dh_group::{self, DhElement, DhGroup, Group};
 use crate::crypto::hash2curve::hash2curve_demo;
-use crate::crypto::hmac::{compute_hmac, verify_hmac};
-use crate::crypto::key_schedule::{key_schedule_1, key_schedule_2, key_schedule_3};
-use crate::crypto::participant::{DatabaseContent, Message, User, CA};
+use crate::crypto::envelope::{self, Envelope};
+use crate::crypto::ksf::KeyStretching;
+use crate::crypto::secret::Secret;
+use crate::crypto::participant::{DatabaseContent, RequestError, User, CA, SERVER_IDENTITY, STATUS_FAILED, STATUS_INVALID_TOTP, STATUS_LOCKED, STATUS_OK, STATUS_RATE_LIMITED, STATUS_RESUME_REFUSED, STATUS_REWRAP, STATUS_TICKET, STATUS_TOTP_REQUIRED, STATUS_USER_EXISTS};
+use crate::crypto::{totp, voprf};
+use crate::handshake::server::ServerIdentity;
+use crate::handshake::{AlertDescription, Event, HandshakeError, ServerHandshake};
+use crate::metrics::{http, METRICS};
+use crate::server::ake_keys::{AkeKey, AkeKeys};
+use crate::server::audit::{AuditEvent, AuditLog};
+use crate::server::rate_limit::{AttemptCounter, Limited, RateLimiter};
+use crate::server::session::{SessionPolicy, Ticket};
+use crate::server::store::{Store, DATA_DIR};
 use aes_gcm::aead::OsRng;
 use elliptic_curve::group::GroupEncoding;
 use elliptic_curve::hash2curve::ExpandMsgXmd;
-use elliptic_curve::Field;
-use hmac::digest::{Digest, Output};
+use sha2::digest::Digest;
 use image::EncodableLayout;
-use k256::{ProjectivePoint, Scalar};
-use kem::Encapsulate;
-use ml_dsa::signature::Signer;
-use ml_dsa::{KeyGen, MlDsa65, Seed};
-use ml_kem::kem::EncapsulationKey;
-use ml_kem::{EncodedSizeUser, MlKem768Params};
+use k256::ProjectivePoint;
 use rand_core::RngCore;
-use sha2::Sha256;
 use sha3::Sha3_256;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::net::{TcpListener, TcpStream};
-use std::panic;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpListener;
+use tokio::{task, time};
+use tracing::{debug, error, info_span, warn, Instrument};
+use crate::transport::{FrameCodec, Framed, Transport, TransportError};
+
+/// Longest time a client may take from its request to key confirmation.
+pub(crate) const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// File the audit trail of `serve` is appended to.
+pub const AUDIT_LOG_PATH: &str = "google_audit.log";
+
+/// Longest time an audit entry waits for a signed checkpoint.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Stand-in records kept for unknown usernames before one is evicted.
+const MAX_FAKE_RECORDS: usize = 10_000;
+
+/// State shared by all connections. The user records and attempt counters survive
+/// connection resets.
+#[derive(Default)]
+pub struct ServerState {
+    pub database: HashMap<Vec<u8>, DatabaseContent>,
+    pub limiter: RateLimiter,
+    pub sessions: SessionPolicy,
+    pub audit: AuditLog,
+    /// Google's 3DH key pairs, shared by all users.
+    pub ake_keys: AkeKeys,
+    /// Google's ML-DSA key pair and certificate for pq_tls.
+    pub identity: ServerIdentity,
+    /// Where the records are saved after every request, if anywhere.
+    pub store: Option<Store>,
+    /// Stand-in records of unknown usernames, built by `fake_record`.
+    pub fake_records: HashMap<Vec<u8>, DatabaseContent>,
+}
+
+/// Locks `state`. Nothing panics while holding the lock or across an `.await`, so the
+/// records behind a poisoned lock are still consistent.
+fn lock(state: &Mutex<ServerState>) -> MutexGuard<'_, ServerState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Appends `event` about `username` and the peer of `stream` to the audit trail.
+fn audit(state: &Mutex<ServerState>, stream: &impl Transport, event: AuditEvent, username: Option<&[u8]>) {
+    lock(state).audit.record(event, username, peer_ip(stream));
+}
+
+fn peer_ip(stream: &impl Transport) -> IpAddr {
+    stream.peer_ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
 
-static RECEIVED_RESET: AtomicBool = AtomicBool::new(false);
+/// What the requests of one connection share: the stream, its associated data and peer
+/// address, and the state shared with the other connections.
+pub(crate) struct Connection<'a, S> {
+    pub(crate) stream: &'a mut S,
+    pub(crate) state: &'a Mutex<ServerState>,
+    pub(crate) ad: &'a [u8; 13],
+    pub(crate) peer: IpAddr,
+}
+
+impl<'a, S: Transport> Connection<'a, S> {
+    pub(crate) fn new(stream: &'a mut S, state: &'a Mutex<ServerState>, ad: &'a [u8; 13]) -> Self {
+        let peer = peer_ip(stream);
+        Connection { stream, state, ad, peer }
+    }
+
+    /// Appends `event` about `username` and the peer to the audit trail.
+    fn audit(&self, event: AuditEvent, username: Option<&[u8]>) {
+        lock(self.state).audit.record(event, username, self.peer);
+    }
+
+    /// Clears the attempt counted by `authenticate` once the request is accepted.
+    fn accept_attempt(&self, username: &[u8]) {
+        let ServerState { database, limiter, .. } = &mut *lock(self.state);
+        limiter.record_success(database, username, self.peer);
+    }
+}
+
+/// Accepts connections on 127.0.0.1:9000 and serves each one on its own task. Every
+/// connection logs under a `connection` span with its own id and the peer address.
+pub async fn serve(ca: &CA) {
+    // RUST_LOG is not parsed, diagnostics go to stderr from info up
+    let _ = tracing_subscriber::fmt().with_writer(std::io::stderr).try_init();
+
+    let listener = TcpListener::bind("127.0.0.1:9000").await.unwrap();
+
+    // Records and keys of earlier runs. Without them nothing is saved, rather than
+    // overwriting records that could not be read.
+    let (mut state, oprf_seed) = load_state(Store::new(DATA_DIR), ca).unwrap_or_else(|e| {
+        error!("Cannot load {DATA_DIR}, records will not be saved: {e}");
+        let mut oprf_seed = [0u8; 32];
+        OsRng.fill_bytes(&mut oprf_seed);
+        (ServerState::default(), oprf_seed)
+    });
+    let audit = Store::new(DATA_DIR).audit_key_pair().and_then(|key_pair| AuditLog::open(AUDIT_LOG_PATH, key_pair));
+    state.audit = audit.unwrap_or_else(|e| {
+        error!("Cannot open the audit log {AUDIT_LOG_PATH}: {e}");
+        AuditLog::default()
+    });
+    let state = Arc::new(Mutex::new(state));
+
+    // Sign the audit trail regularly, so entries of quiet periods do not stay unsigned
+    let checkpoints = Arc::clone(&state);
+    tokio::spawn(async move {
+        let mut interval = time::interval(CHECKPOINT_INTERVAL);
+        loop {
+            interval.tick().await;
+            lock(&checkpoints).audit.checkpoint();
+        }
+    });
+    let connection_ids = AtomicU64::new(1);
+
+    // Metrics for local scrapes; the server runs without them if the port is taken
+    match TcpListener::bind(http::METRICS_ADDR).await {
+        Ok(metrics) => {
+            tokio::spawn(http::serve(metrics, &METRICS));
+        }
+        Err(e) => error!("Cannot serve metrics on {}: {e}", http::METRICS_ADDR),
+    }
+
+    // Limits for every frame a client sends
+    let codec = FrameCodec::default();
 
-pub fn google(ca: &mut CA, group_element: &mut ProjectivePoint) {
-    let listener = TcpListener::bind("127.0.0.1:9000").unwrap();
-    let (mut stream, _) = listener.accept().unwrap();
     loop {
-        panic::set_hook(Box::new(|_| {
-        }));
-        match panic::catch_unwind(panic::AssertUnwindSafe(|| {
-            match google_inner(ca, group_element, &mut stream) {
-                _ => panic!("Google: Error in google_inner"),
-            }
-        })) {
-            _ => {
-                if !RECEIVED_RESET.load(Ordering::Relaxed) {
-                    // println!("Google: An error occurred, resetting connection...");
-                    User::send_bytes(&mut stream, &Message::Reset {});
-                };
-                RECEIVED_RESET.store(false, Ordering::Relaxed);
+        let (mut stream, peer) = match listener.accept().await {
+            Ok((stream, peer)) => (Framed::new(stream, codec), peer),
+            Err(e) => {
+                error!("Accept error: {e}");
+                continue;
             }
-        }
+        };
+        let mut ca = ca.clone();
+        let state = Arc::clone(&state);
+        let span = info_span!("connection", id = connection_ids.fetch_add(1, Ordering::Relaxed), %peer);
+        tokio::spawn(async move {
+            debug!("Accepted connection");
+            google_inner(&mut ca, &mut stream, &oprf_seed, &state).await;
+        }.instrument(span));
+    }
+}
+
+/// The saved records, the server-wide seed every per-user OPRF key is derived from,
+/// Google's AKE keys and its identity, issued by `ca` on the first run.
+fn load_state(store: Store, ca: &CA) -> std::io::Result<(ServerState, [u8; 32])> {
+    let database = store.load_users()?;
+    let oprf_seed = store.oprf_seed()?;
+    let ake_keys = store.ake_keys()?;
+    let identity = store.identity(ca)?;
+    let state = ServerState { database, ake_keys, identity, store: Some(store), ..ServerState::default() };
+    Ok((state, oprf_seed))
+}
+
+/// Writes the records to the store of `state`, if it has one.
+fn save(state: &Mutex<ServerState>) {
+    let state = lock(state);
+    if let Some(Err(e)) = state.store.as_ref().map(|store| store.save_users(&state.database)) {
+        error!("Cannot save the records: {e}");
     }
 }
 
-pub fn google_inner(ca: &mut CA, group_element: &mut ProjectivePoint, mut stream: &mut TcpStream) {
-    let mut aead_nonce: [u8; 12] = [0u8; 12];
+/// Serves requests on one connection until Alice disconnects. Every request runs its own
+/// session, which ends on an error with an alert, and the next request starts with a
+/// new handshake.
+pub async fn google_inner(
+    ca: &mut CA,
+    stream: &mut impl Transport,
+    oprf_seed: &[u8; 32],
+    state: &Mutex<ServerState>
+) {
     let ad = b"Alice,Google,";
-    let mut database: HashMap<Vec<u8>, DatabaseContent> = HashMap::new();
-    let g = group_element.clone();
+
+    // Key stretching applied to the OPRF output of newly registered users
+    let ksf = KeyStretching::default();
 
     loop {
-        // Establish TLS connection
-        // println!("Google: Establishing TLS connection");
-        let (_k1_c, _k1_s, _k2_c, _k2_s, k3_c, k3_s) = pq_tls(&mut stream, ca, ad);
-        // println!("Google: TLS connection established.");
-
-        // Receive message from Alice
-        // println!("Google: Waiting for message from Alice");
-        let msg = User::recv_bytes(&mut stream);
-        let (nonce, aead_payload) = match msg {
-            Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-            _ => {
-                match msg {
-                    Message::Reset {} => (),
-                    _ => {
-                        eprintln!("Google: Unexpected message");
-                        return;
-                    }
+        let result = handle_request(ca, oprf_seed, &ksf, stream, ad, state).await;
+        save(state);
+        // Errors were alerted to Alice where they were found
+        match result {
+            Ok(()) | Err(RequestError::Failed) => continue,
+            Err(RequestError::Transport(TransportError::Alert(description))) => {
+                warn!("Alice ended the session: {description}");
+                if description == AlertDescription::BadCertificate {
+                    audit(state, stream, AuditEvent::CertificateRejected, None);
                 }
-                RECEIVED_RESET.store(true, Ordering::Relaxed);
-                panic!("Google: Unexpected message")
-            },
-        };
-        let decrypted_msg: Vec<u8> = match crypto::aead::decrypt(&k3_c, &nonce, &aead_payload, &ad.as_ref()) {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Google: Decrypt error: {e}");
-                return;
-            }
-        };
-        let mut parts = decrypted_msg.splitn(3, |&b| b == b';');
-        let action = parts.next().unwrap_or(&[]);
-        let action_text = String::from_utf8_lossy(&action);
-        let mut username = parts.next().unwrap_or(&[]);
-        let mut content = parts.next().unwrap_or(&[]);
-
-        if action == b"Register" {
-            if register(
-                &mut aead_nonce,
-                &ad,
-                &mut database,
-                g,
-                &mut username,
-                &mut content
-            ) {
-                eprintln!("Google: Register error");
-                return; 
             }
-        } else if action == b"Login" {
-            if login(
-                k3_c,
-                k3_s,
-                &mut stream,
-                &mut aead_nonce,
-                &ad,
-                &mut database,
-                g,
-                &mut username,
-                &mut content
-            ) {
-                eprintln!("Google: Login error");
+            Err(RequestError::Transport(e)) => {
+                // A client that sends malformed or stalled frames is dropped
+                if matches!(e, TransportError::Decode(_) | TransportError::Timeout) {
+                    warn!("Closing connection: {e}");
+                }
                 return;
             }
-        } else {
-            eprintln!("Google: Invalid action: {action_text}");
-            return;
         }
-
     }
 }
 
-pub(crate) fn login(
-    k3_c: [u8; 32],
-    k3_s: [u8; 32],
-    mut stream: &mut TcpStream,
-    aead_nonce: &mut [u8; 12],
-    ad: &&[u8; 13],
-    database: &mut HashMap<Vec<u8>, DatabaseContent>,
-    g: ProjectivePoint,
-    username: &[u8],
-    content: &[u8]
-) -> bool {
-    // ----------- OPRF stage -----------
-    // println!("Google: OPRF stage");
+/// Sends what `handshake` has queued and waits for its next event. Errors are alerted
+/// to Alice.
+async fn next_event(handshake: &mut ServerHandshake, stream: &mut impl Transport) -> Result<Event, RequestError> {
+    match User::drive(stream, handshake).await? {
+        Ok(event) => Ok(event),
+        Err(e) => {
+            warn!("Handshake error: {e}");
+            Err(RequestError::Failed)
+        }
+    }
+}
 
-    let h_pw_a = ProjectivePoint::from_bytes(content.into()).unwrap();
+/// Reports `e` as `Google: <context> error` and alerts Alice about it.
+async fn fail(handshake: &mut ServerHandshake, stream: &mut impl Transport, context: &str, e: HandshakeError) -> RequestError {
+    warn!("{context} error: {e}");
+    match User::fail(stream, handshake, e).await {
+        Ok(_) => RequestError::Failed,
+        Err(e) => e.into(),
+    }
+}
 
-    // Load saved data from database
-    // println!("Google: Loading saved data for user: {}", String::from_utf8_lossy(username));
-    let saved_data = match database.get(username) {
-        Some(data) => data,
-        None => {
-            eprintln!("Google: Username not found");
-            return true;
-        }
-    };
+/// Reports `what` and ends the session with `description`.
+async fn abort(handshake: &mut ServerHandshake, stream: &mut impl Transport, what: &str, description: AlertDescription) -> RequestError {
+    warn!("{what}");
+    match User::send_alert(stream, handshake, description).await {
+        Ok(()) => RequestError::Failed,
+        Err(e) => e.into(),
+    }
+}
 
-    // Send AEAD(k3_s, {{h_pw^as, enc_client_keys}}) message from Google to Alice
-    // println!("Google: Sending AEAD(k3_s, {{h_pw^as, enc_client_keys}}) message to Alice");
-    let mut msg = Vec::new();
-    msg.extend_from_slice((h_pw_a * saved_data.salt).to_bytes().as_bytes());
-    msg.extend_from_slice(saved_data.enc_client_keys.as_slice());
-    msg.extend_from_slice(saved_data.aead_nonce.as_bytes());
-    OsRng.fill_bytes(aead_nonce);
-    let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k3_s, &aead_nonce, msg.as_bytes(), &ad.to_vec()) {
+/// Serves one request from Alice: establishes pq_tls, then dispatches on the action
+/// (Register, Login, Resume, ChangePassword, DeleteAccount).
+pub(crate) async fn handle_request(
+    ca: &mut CA,
+    oprf_seed: &[u8; 32],
+    ksf: &KeyStretching,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
+    state: &Mutex<ServerState>,
+) -> Result<(), RequestError> {
+    // Establish TLS connection
+    debug!("Establishing TLS connection");
+    let identity = lock(state).identity.clone();
+    let mut handshake = pq_tls(stream, ca, &identity, ad).await?;
+    debug!("TLS connection established.");
+
+    // Receive message from Alice
+    debug!("Waiting for message from Alice");
+    let decrypted_msg: Vec<u8> = match User::recv_sealed(stream, &mut handshake).await? {
         Ok(c) => c,
         Err(e) => {
-            eprintln!("Google: Encrypt error: {e}");
-            return true;
+            warn!("Decrypt error: {e}");
+            return Err(RequestError::Failed);
         }
     };
-    let msg = Message::AeadCiphertext {
-        nonce: *aead_nonce,
-        aead_payload: cypher_text,
-    };
-    User::send_bytes(&mut stream, &msg);
-
-    // Parse enc_client_keys
-    let lsk_s: Scalar = saved_data.lsk_s;
-    let lpk_c: ProjectivePoint = saved_data.lpk_c;
-    let _lpk_s: ProjectivePoint = saved_data.lpk_s;
-
-    // ----------- AKE stage: 3DH -----------
-    // println!("Google: AKE stage");
-
-    let y = Scalar::random(&mut OsRng);
-
-    // Receive ephemeral_pk key from Alice
-    // println!("Google: Waiting for ephemeral_pk from Alice");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce, aead_payload) = match msg {
-        Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-        _ => {
-            match msg {
-                Message::Reset {} => (),
-                _ => {
-                    eprintln!("Google: Unexpected message");
-                    return true;
-                }
+    let mut parts = decrypted_msg.splitn(3, |&b| b == b';');
+    let action = parts.next().unwrap_or(&[]);
+    let action_text = String::from_utf8_lossy(action);
+    let username = parts.next().unwrap_or(&[]);
+    let content = parts.next().unwrap_or(&[]);
+
+    if action == b"Register" || action == b"RegisterTotp" {
+        let username_taken = lock(state).database.contains_key(username);
+
+        // Enroll a TOTP secret if Alice asked for a second factor
+        let totp_secret = (action == b"RegisterTotp").then(totp::generate_secret);
+        let failed = register(
+            oprf_seed,
+            ksf,
+            state,
+            totp_secret.clone(),
+            username,
+            content
+        ).await;
+
+        let event = if failed { AuditEvent::RegistrationRejected } else { AuditEvent::Registered };
+        audit(state, stream, event, Some(username));
+
+        // Tell Alice whether the registration was accepted, along with the TOTP secret
+        let status = if !failed {
+            match totp_secret {
+                Some(secret) => [STATUS_OK, b";", &secret[..]].concat(),
+                None => STATUS_OK.to_vec(),
             }
-            RECEIVED_RESET.store(true, Ordering::Relaxed);
-            panic!("Google: Unexpected message")
-        },
-    };
-    let decrypted_msg: Vec<u8> = match crypto::aead::decrypt(&k3_c, &nonce, &aead_payload, &ad.as_ref()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Google: Decrypt error: {e}");
-            return true;
+        } else if username_taken {
+            STATUS_USER_EXISTS.to_vec()
+        } else {
+            STATUS_FAILED.to_vec()
+        };
+        if let Err(e) = send_status(&mut handshake, stream, &status).await {
+            warn!("Register error");
+            return Err(e);
         }
-    };
-    let large_x: ProjectivePoint = ProjectivePoint::from_bytes(decrypted_msg.as_slice().try_into().unwrap()).unwrap();
-
-    // Send ephemeral_pk key to Alice
-    // println!("Google: Sending ephemeral_pk key to Alice");
-    let mut msg = Vec::new();
-    msg.extend_from_slice((g * y).to_bytes().as_bytes());
-    OsRng.fill_bytes(aead_nonce);
-    let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k3_s, &aead_nonce, msg.as_bytes(), &ad.to_vec()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Google: Encrypt error: {e}");
-            return true;
+    } else if action == b"ChangePassword" {
+        if let Err(e) = change_password(
+            oprf_seed,
+            ksf,
+            &mut handshake,
+            &mut Connection::new(stream, state, ad),
+            username,
+            content
+        ).await {
+            warn!("ChangePassword error");
+            return Err(e);
         }
+    } else if action == b"DeleteAccount" {
+        if let Err(e) = delete_account(
+            oprf_seed,
+            &mut handshake,
+            &mut Connection::new(stream, state, ad),
+            username,
+            content
+        ).await {
+            warn!("DeleteAccount error");
+            return Err(e);
+        }
+    } else if action == b"Login" {
+        if let Err(e) = login(
+            oprf_seed,
+            &mut handshake,
+            &mut Connection::new(stream, state, ad),
+            username,
+            content
+        ).await {
+            warn!("Login error");
+            return Err(e);
+        }
+    } else if action == b"Resume" {
+        if let Err(e) = resume(
+            oprf_seed,
+            &mut handshake,
+            stream,
+            state,
+            username,
+            content
+        ).await {
+            warn!("Resume error");
+            return Err(e);
+        }
+    } else {
+        return Err(abort(&mut handshake, stream, &format!("Invalid action: {action_text}"), AlertDescription::UnexpectedMessage).await);
+    }
+
+    Ok(())
+}
+
+/// Server side of the OPRF stage and the 3DH AKE with key confirmation. Drops the
+/// connection after `AUTH_TIMEOUT`, so a stalled client cannot hold on to a connection
+/// task forever. An attempt counted by the rate limiter is audited as failed unless it
+/// reaches key confirmation, whether it ends with an error, an alert or the timeout. The
+/// attempt stays counted until the caller accepts it, e.g. after the second factor.
+pub(crate) async fn authenticate(
+    oprf_seed: &[u8; 32],
+    handshake: &mut ServerHandshake,
+    conn: &mut Connection<'_, impl Transport>,
+    username: &[u8],
+    content: &[u8]
+) -> Result<(), RequestError> {
+    let timer = METRICS.login.start();
+    let mut failure = None;
+    let result = time::timeout(
+        AUTH_TIMEOUT,
+        authenticate_inner(oprf_seed, handshake, conn, username, content, &mut failure),
+    )
+    .await;
+    if let Some(event) = failure {
+        conn.audit(event, Some(username));
+    }
+    match result {
+        Ok(Ok(())) => {
+            timer.succeed();
+            Ok(())
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(TransportError::Timeout.into()),
+    }
+}
+
+async fn authenticate_inner(
+    oprf_seed: &[u8; 32],
+    handshake: &mut ServerHandshake,
+    conn: &mut Connection<'_, impl Transport>,
+    username: &[u8],
+    content: &[u8],
+    failure: &mut Option<AuditEvent>
+) -> Result<(), RequestError> {
+    // ----------- OPRF stage -----------
+    debug!("OPRF stage");
+
+    // Every OPRF evaluation is a password guess, so it counts as failed until key confirmation
+    let now = SystemTime::now();
+    let (attempt, locked) = {
+        let ServerState { database, limiter, .. } = &mut *lock(conn.state);
+        let attempt = limiter.begin_attempt(database, username, conn.peer, now);
+        (attempt, limiter.is_user_locked(database, username, now))
     };
-    let msg = Message::AeadCiphertext {
-        nonce: *aead_nonce,
-        aead_payload: cypher_text
-    };
-    User::send_bytes(&mut stream, &msg);
-
-    // 3DH-KServer (𝑏, 𝑦, 𝐴, 𝑋)
-    // println!("Google: Calculating SK");
-    let mut key_input = Vec::new();
-    key_input.extend_from_slice((large_x * lsk_s).to_bytes().as_bytes());
-    key_input.extend_from_slice((large_x * y).to_bytes().as_bytes());
-    key_input.extend_from_slice((lpk_c * y).to_bytes().as_bytes());
-    let (sk, _) = crypto::key_schedule::extract(None, key_input.as_bytes());
-
-    // ----------- Key Confirmation -----------
-    // println!("Google: Key Confirmation stage");
-
-    // Calculate mac_s
-    // println!("Google: Calculating mac_s");
-    let (_, hk) = crypto::key_schedule::extract(None, sk.as_bytes());
-    let combined_key = crypto::key_schedule::expand::<64>(&hk, b"Key Confirmation").unwrap();
-    let (kc, ks) = combined_key.split_at(32);
-
-    let mac_s = compute_hmac(ks.as_bytes(), b"Server KC");
-    let expected_mac_c = compute_hmac(kc.as_bytes(), b"Client KC");
-
-    // Receive mac_c from Alice
-    // println!("Google: Waiting for mac_c from Alice");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce, aead_payload) = match msg {
-        Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-        _ => {
-            match msg {
-                Message::Reset {} => (),
-                _ => {
-                    eprintln!("Google: Unexpected message");
-                    return true;
-                }
-            }
-            RECEIVED_RESET.store(true, Ordering::Relaxed);
-            panic!("Google: Unexpected message")
-        },
+    if let Err(limited) = attempt {
+        debug!("Refusing attempt for user: {}", String::from_utf8_lossy(username));
+        conn.audit(AuditEvent::LoginRefused, Some(username));
+        let (status, wait) = match limited {
+            Limited::Backoff(wait) => (STATUS_RATE_LIMITED, wait),
+            Limited::Locked(wait) => (STATUS_LOCKED, wait),
+        };
+        let status = [status, b";", wait.as_secs_f64().ceil().to_string().as_bytes()].concat();
+        send_status(handshake, conn.stream, &status).await?;
+        return Err(RequestError::Failed);
+    }
+    if locked {
+        conn.audit(AuditEvent::Lockout, Some(username));
+    }
+    *failure = Some(AuditEvent::LoginFailed);
+
+    // Load saved data from database, answering unknown usernames with a fake record
+    debug!("Loading saved data for user: {}", String::from_utf8_lossy(username));
+    let (saved_data, server_key) = {
+        let mut state = lock(conn.state);
+        let saved_data = match state.database.get(username) {
+            Some(record) => record.clone(),
+            None => fake_record(oprf_seed, &mut state, username),
+        };
+        let current = state.ake_keys.current();
+
+        // An envelope for a retired key cannot be recovered, as with a wrong password
+        let server_key = state.ake_keys.get(saved_data.key_id, now).unwrap_or_else(|| {
+            warn!("AKE key {} of the record retired, the user has to register again", saved_data.key_id);
+            current
+        });
+        (saved_data, server_key.clone())
     };
-    let mac_c: Vec<u8> = match crypto::aead::decrypt(&k3_c, &nonce, &aead_payload, &ad.as_ref()) {
-        Ok(c) => c,
+
+    // Derive the user's OPRF key from the server seed
+    let oprf_key = match voprf::derive_key(oprf_seed, username) {
+        Ok(k) => k,
         Err(e) => {
-            eprintln!("Google: Decrypt error: {e}");
-            return true;
+            let what = format!("OPRF key derivation error: {e:?}");
+            return Err(abort(handshake, conn.stream, &what, AlertDescription::InternalError).await);
         }
     };
 
-    // Send mac_s to Alice
-    // println!("Google: Sending mac_s to Alice");
-    OsRng.fill_bytes(aead_nonce);
-    let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k3_s, &aead_nonce, mac_s.as_bytes(), &ad.to_vec()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Google: Encrypt error: {e}");
-            return true;
-        }
+    // Evaluate the blinded element, then run 3DH and key confirmation
+    if let Err(e) = handshake.start_login(content, username, &saved_data, &oprf_key, &server_key) {
+        return Err(fail(handshake, conn.stream, "Login", e).await);
+    }
+    match next_event(handshake, conn.stream).await? {
+        Event::LoginSucceeded => (),
+        _ => return Err(abort(handshake, conn.stream, "Unexpected message", AlertDescription::UnexpectedMessage).await),
+    }
+    debug!("Valid MACs received.");
+    *failure = None;
+    conn.audit(AuditEvent::LoginSucceeded, Some(username));
+
+    Ok(())
+}
+
+/// Second factor stage of a request after `authenticate`: tells Alice whether a TOTP code
+/// is required and checks the code, which arrives as the first ratchet message. Returns
+/// whether the request may go on; after a wrong code the attempt stays counted as failed.
+async fn second_factor(
+    handshake: &mut ServerHandshake,
+    conn: &mut Connection<'_, impl Transport>,
+    username: &[u8]
+) -> Result<bool, RequestError> {
+    debug!("Second factor stage");
+
+    // Tell Alice whether a TOTP code is required before access is granted
+    let totp_secret = lock(conn.state).database.get(username).and_then(|record| record.totp_secret.clone());
+    let status = if totp_secret.is_some() { STATUS_TOTP_REQUIRED } else { STATUS_OK };
+    send_status(handshake, conn.stream, status).await?;
+    let Some(secret) = totp_secret else {
+        return Ok(true);
     };
-    let msg = Message::AeadCiphertext {
-        nonce: *aead_nonce,
-        aead_payload: cypher_text
+
+    // Receive the code and answer with the verdict
+    debug!("Waiting for TOTP code from Alice");
+    let code = match next_event(handshake, conn.stream).await? {
+        Event::AppData(code) => code,
+        _ => return Err(abort(handshake, conn.stream, "Unexpected message", AlertDescription::UnexpectedMessage).await),
     };
-    User::send_bytes(&mut stream, &msg);
-
-    // Verify mac_c
-    // println!("Google: Verifying mac_c");
-    assert!(verify_hmac(
-        kc.as_bytes(),
-        b"Client KC",
-        mac_c.as_bytes()
-    ));
-    assert_eq!(mac_c.as_bytes(), expected_mac_c.as_bytes());
-    // println!("Google: Valid MACs received.");
+    let last_step = lock(conn.state).database.get(username).and_then(|record| record.totp_last_step);
+    let accepted_step = totp::verify(secret.as_slice(), &code, unix_time(), last_step);
+    let verdict = if accepted_step.is_some() { STATUS_OK } else { STATUS_INVALID_TOTP };
+    answer(handshake, conn.stream, &String::from_utf8_lossy(verdict)).await?;
+
+    match accepted_step {
+        Some(step) => {
+            if let Some(record) = lock(conn.state).database.get_mut(username) {
+                record.totp_last_step = Some(step);
+            }
+            Ok(true)
+        }
+        None => {
+            warn!("Invalid TOTP code");
+            let now = SystemTime::now();
+            let ServerState { database, limiter, audit, .. } = &mut *lock(conn.state);
+            audit.record(AuditEvent::SecondFactorFailed, Some(username), conn.peer);
+            if limiter.is_user_locked(database, username, now) {
+                audit.record(AuditEvent::Lockout, Some(username), conn.peer);
+            }
+            Ok(false)
+        }
+    }
+}
+
+pub(crate) async fn login(
+    oprf_seed: &[u8; 32],
+    handshake: &mut ServerHandshake,
+    conn: &mut Connection<'_, impl Transport>,
+    username: &[u8],
+    content: &[u8]
+) -> Result<(), RequestError> {
+    authenticate(oprf_seed, handshake, conn, username, content).await?;
 
     // End of login -----------------------------------------------------------------------------------------------------------
     // Start communication -----------------------------------------------------------------------------------------------------------
 
+    rewrap_envelope(handshake, conn.stream, conn.state, username).await?;
+    if !second_factor(handshake, conn, username).await? {
+        return Ok(());
+    }
+    conn.accept_attempt(username);
+
     // ----------- Double Ratchet -----------
-    // println!("Google: Double Ratchet stage");
+    debug!("Double Ratchet stage");
 
-    let mut rk_i = sk;
-    let mut _large_x_i = large_x;
-    let mut y_i = y;
+    // Resumptions of this login end with the ticket issued now
+    let expires_at = unix_time() + lock(conn.state).sessions.ticket_lifetime.as_secs();
+    run_session(oprf_seed, handshake, conn.stream, conn.state, username, expires_at).await
+}
 
-    #[cfg(not(test))]
+/// Resumes a session with a ticket from an earlier login, without the password. The
+/// content is Alice's ephemeral key followed by the ticket.
+pub(crate) async fn resume(
+    oprf_seed: &[u8; 32],
+    handshake: &mut ServerHandshake,
+    stream: &mut impl Transport,
+    state: &Mutex<ServerState>,
+    username: &[u8],
+    content: &[u8]
+) -> Result<(), RequestError> {
+    // Refuse tickets that expired or belong to another user, and accounts that were
+    // locked, deleted or given a new password since the login
+    let (large_x, ticket) = content.split_at(content.len().min(dh_group::ELEMENT_LEN));
+    let ticket = Ticket::open(oprf_seed, ticket, unix_time()).filter(|ticket| {
+        ticket.username == username
+            && lock(state).database.get(username).is_some_and(|record| {
+                record.generation == ticket.generation
+                    && record.attempts.locked_until.is_none_or(|until| until <= SystemTime::now())
+            })
+    });
+    let Some(ticket) = ticket else {
+        warn!("Resumption ticket refused");
+        audit(state, stream, AuditEvent::ResumeRefused, Some(username));
+        return send_status(handshake, stream, STATUS_RESUME_REFUSED).await;
+    };
+
+    // Fresh ephemeral keys and key confirmation under the resumption secret
+    if let Err(e) = handshake.start_resume(large_x, &ticket.resumption_secret) {
+        return Err(fail(handshake, stream, "Resume", e).await);
+    }
+    let confirmed = match time::timeout(AUTH_TIMEOUT, next_event(handshake, stream)).await {
+        Ok(confirmed) => confirmed?,
+        Err(_) => return Err(TransportError::Timeout.into()),
+    };
+    match confirmed {
+        Event::LoginSucceeded => (),
+        _ => return Err(abort(handshake, stream, "Unexpected message", AlertDescription::UnexpectedMessage).await),
+    }
+    audit(state, stream, AuditEvent::SessionResumed, Some(username));
+
+    run_session(oprf_seed, handshake, stream, state, username, ticket.expires_at).await
+}
+
+/// Issues a ticket for resuming the logged-in session until `expires_at`, then serves it.
+async fn run_session(
+    oprf_seed: &[u8; 32],
+    handshake: &mut ServerHandshake,
+    stream: &mut impl Transport,
+    state: &Mutex<ServerState>,
+    username: &[u8],
+    expires_at: u64
+) -> Result<(), RequestError> {
+    let Some(sk) = handshake.session_key() else {
+        return Err(abort(handshake, stream, "Session without key", AlertDescription::InternalError).await);
+    };
+    let Some(generation) = lock(state).database.get(username).map(|record| record.generation) else {
+        return Err(abort(handshake, stream, "Account deleted during login", AlertDescription::UnknownUser).await);
+    };
+    let ticket = Ticket {
+        username: username.to_vec(),
+        generation,
+        resumption_secret: crypto::key_schedule::resumption_secret(sk.as_slice()),
+        expires_at,
+    };
+    let Some(ticket) = ticket.seal(oprf_seed) else {
+        return Err(abort(handshake, stream, "Ticket encryption error", AlertDescription::InternalError).await);
+    };
+    send_status(handshake, stream, &[STATUS_TICKET, &ticket].concat()).await?;
+
+    let policy = lock(state).sessions;
+    serve_session(handshake, stream, &policy).await
+}
+
+/// Answers Alice's ratchet messages and heartbeats until she closes the session. Drops
+/// the connection once she stays silent for the idle timeout, and ends the session
+/// with close_notify at the first message after its lifetime, so she resumes it.
+async fn serve_session(handshake: &mut ServerHandshake, stream: &mut impl Transport, policy: &SessionPolicy) -> Result<(), RequestError> {
+    let expires_at = Instant::now() + policy.lifetime;
     loop {
-        let (large_x_plus_one, y_i_plus_1, rk_i_plus_2, _) = match inner_double_ratchet(&k3_c, &k3_s, &mut stream, aead_nonce, &ad, g, rk_i, y_i) {
-            Ok(value) => value,
-            Err(value) => return value,
+        let event = match time::timeout(policy.idle_timeout, User::drive(stream, handshake)).await {
+            Ok(event) => event?,
+            Err(_) => return Err(TransportError::Timeout.into()),
         };
+        match event {
+            Ok(Event::AppData(_) | Event::Heartbeat) if Instant::now() >= expires_at => {
+                debug!("Session expired");
+                return match time::timeout(policy.idle_timeout, User::close(stream, handshake)).await {
+                    Ok(closed) => Ok(closed?),
+                    Err(_) => Err(TransportError::Timeout.into()),
+                };
+            }
+            Ok(Event::AppData(message)) => answer(handshake, stream, &format!("Echo => {}", message)).await?,
+            Ok(Event::Heartbeat) => User::flush(stream, handshake).await?,
+            Ok(_) => return Err(abort(handshake, stream, "Unexpected message", AlertDescription::UnexpectedMessage).await),
+            Err(HandshakeError::Alert(AlertDescription::CloseNotify)) => return Ok(()),
+            Err(e) => {
+                warn!("Ratchet error: {e}");
+                return Err(RequestError::Failed);
+            }
+        }
+    }
+}
+
+/// Answers Alice's last ratchet message with `reply`.
+async fn answer(handshake: &mut ServerHandshake, stream: &mut impl Transport, reply: &str) -> Result<(), RequestError> {
+    if let Err(e) = handshake.send_app_data(reply) {
+        return Err(fail(handshake, stream, "Encrypt", e).await);
+    }
+    Ok(User::flush(stream, handshake).await?)
+}
+
+pub(crate) async fn register(
+    oprf_seed: &[u8; 32],
+    ksf: &KeyStretching,
+    state: &Mutex<ServerState>,
+    totp_secret: Option<Secret<[u8; totp::SECRET_LEN]>>,
+    username: &[u8],
+    password: &[u8]
+) -> bool {
+    if lock(state).database.contains_key(username) {
+        warn!("Username already registered");
+        return true;
+    }
 
-        rk_i = rk_i_plus_2.into();
-        _large_x_i = large_x_plus_one;
-        y_i = y_i_plus_1;
-    }
-
-    #[cfg(test)]
-    {
-        return false;
-    }
-}
-
-pub(crate) fn inner_double_ratchet(
-    k3_c: &[u8; 32],
-    k3_s: &[u8; 32],
-    mut stream: &mut &mut TcpStream,
-    aead_nonce: &mut [u8; 12],
-    ad: &&&[u8; 13],
-    g: ProjectivePoint, 
-    mut rk_i: Output<Sha256>,
-    mut y_i: Scalar
-) -> Result<(ProjectivePoint, Scalar, [u8; 32], String), bool> {
-    // Receive large_x_i_plus_one and c1 from Alice
-    // println!("Google: Waiting for X_i+1 and c1 from Alice");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce, aead_payload) = match msg {
-        Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-        _ => {
-            match msg {
-                Message::Reset {} => (),
-                _ => {
-                    eprintln!("Google: Unexpected message");
-                    return Err(true);
+    // The record is computed without holding the lock, so check again before inserting
+    let server_key = lock(state).ake_keys.current().clone();
+    match spawn_record(oprf_seed, ksf, &server_key, username, password).await {
+        Some(mut record) => {
+            record.totp_secret = totp_secret;
+            match lock(state).database.entry(username.to_vec()) {
+                Entry::Occupied(_) => {
+                    warn!("Username already registered");
+                    true
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(record);
+                    debug!("Registration record saved.");
+                    false
                 }
             }
-            RECEIVED_RESET.store(true, Ordering::Relaxed);
-            panic!("Google: Unexpected message")
-        },
-    };
-    let decrypted_msg: Vec<u8> = match crypto::aead::decrypt(&k3_c, &nonce, &aead_payload, &ad.as_ref()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Google: Decrypt error: {e}");
-            return Err(true);
         }
-    };
-    if decrypted_msg.len() < 45 {
-        eprintln!("Google: Decrypt error: received malformed ratchet payload (len={})", decrypted_msg.len());
-        return Err(true);
+        None => true,
     }
-    let (nonce_and_large_x_plus_one_as_bytes, c1) = decrypted_msg.split_at(45);
-    let (nonce, large_x_plus_one_as_bytes) = nonce_and_large_x_plus_one_as_bytes.split_at(12);
-    let large_x_plus_one = ProjectivePoint::from_bytes(large_x_plus_one_as_bytes.into()).unwrap();
+}
 
-    // Recover the chains
-    // println!("Google: Recovering chains");
-    let (rk_i_plus_1, ck_0) = kdf_rk(rk_i.as_bytes(), (large_x_plus_one * y_i).to_bytes().as_bytes());
-    let (ck_1, mk_1) = kdf_ck(ck_0.as_bytes());
+/// Runs `create_record` on the blocking pool, so that the key stretching does not hold
+/// up a runtime worker and the connections it serves.
+async fn spawn_record(
+    oprf_seed: &[u8; 32],
+    ksf: &KeyStretching,
+    server_key: &AkeKey,
+    username: &[u8],
+    password: &[u8]
+) -> Option<DatabaseContent> {
+    let (oprf_seed, ksf, server_key) = (*oprf_seed, *ksf, server_key.clone());
+    let (username, password) = (username.to_vec(), Secret::new(password.to_vec()));
+    let record = task::spawn_blocking(move || create_record(&oprf_seed, &ksf, &server_key, &username, &password));
+    record.await.unwrap_or_else(|e| {
+        error!("Registration task failed: {e}");
+        None
+    })
+}
 
-    let message_from_user: Vec<u8> = match crypto::aead::decrypt(&mk_1.try_into().unwrap(), &nonce.try_into().unwrap(), &c1, &ad.as_ref()) {
-        Ok(c) => c,
+/// Computes the OPAQUE registration record for `username` and `password`, with the
+/// envelope created for `server_key`.
+pub(crate) fn create_record(
+    oprf_seed: &[u8; 32],
+    ksf: &KeyStretching,
+    server_key: &AkeKey,
+    username: &[u8],
+    password: &[u8]
+) -> Option<DatabaseContent> {
+    // Calculate the envelope and the registration record
+    debug!("Registering user: {}", String::from_utf8_lossy(username));
+    let s = match voprf::derive_key(oprf_seed, username) {
+        Ok(k) => k,
         Err(e) => {
-            eprintln!("Google: Decrypt error: {e}");
-            return Err(true);
+            error!("OPRF key derivation error: {e:?}");
+            return None;
         }
     };
+    let h_pw: ProjectivePoint =
+        hash2curve_demo::<k256::Secp256k1, ExpandMsgXmd<Sha3_256>>(password)
+            .expect("hash2curve_demo (k256 + SHA3-256) failed");
 
-    // Echo message_from_user
-    let message_text = String::from_utf8_lossy(&message_from_user);
-    let message_from_server = format!("Echo => {}", message_text);
-
-    // Can be used for multiple messages
-    let (_ck_2, _mk_2) = kdf_ck(ck_1.as_bytes());
-
-
-    // Encrypt message_from_server with DH Ratchet and Sym Ratchet
-    // println!("Google: Encrypting message_from_server with DH Ratchet and Sym Ratchet");
-    let y_i_plus_1 = Scalar::random(&mut OsRng);
-    let (rk_i_plus_2, ck_0) = kdf_rk(rk_i_plus_1.as_bytes(), (large_x_plus_one * y_i_plus_1).to_bytes().as_bytes());
-    let (ck_1, mk_1) = kdf_ck(ck_0.as_bytes());
-    OsRng.fill_bytes(aead_nonce);
-    let c1: Vec<u8> = match crypto::aead::encrypt(&mk_1.try_into().unwrap(), &aead_nonce, message_from_server.as_bytes(), &ad.to_vec()) {
-        Ok(c) => c,
+    let rw = Secret::new(<[u8; 32]>::from(Sha3_256::digest(Secret::new([password.as_bytes(), (h_pw * *s).to_bytes().as_bytes()].concat()).as_slice())));
+    let stretched_rw = match ksf.stretch(rw.as_slice()) {
+        Ok(v) => v,
         Err(e) => {
-            eprintln!("Google: Encrypt error: {e}");
-            return Err(true);
+            error!("Key stretching error: {e:?}");
+            return None;
         }
     };
-
-    // Send large_y_i_plus_one and c1 to Alice
-    // println!("Google: Sending Y_i+1 and c1 to Alice");
-    let mut msg = Vec::new();
-    msg.extend_from_slice(aead_nonce.as_bytes());
-    msg.extend_from_slice((g * y_i_plus_1).to_bytes().as_bytes());
-    msg.extend_from_slice(c1.as_bytes());
-    OsRng.fill_bytes(aead_nonce);
-    let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k3_s, &aead_nonce, msg.as_bytes(), &ad.to_vec()) {
-        Ok(c) => c,
+    let (randomized_pw, _) = crypto::key_schedule::extract(None, Secret::new([rw.as_slice(), stretched_rw.as_slice()].concat()).as_slice());
+    let lpk_s: DhElement = server_key.public_key();
+
+    // The client key pair is derived from randomized_pw, only the envelope is stored
+    let mut envelope_nonce = [0u8; envelope::NONCE_LEN];
+    OsRng.fill_bytes(&mut envelope_nonce);
+    let stored = match envelope::store(randomized_pw.as_slice(), envelope_nonce, &lpk_s, SERVER_IDENTITY, username) {
+        Ok(e) => e,
         Err(e) => {
-            eprintln!("Google: Encrypt error: {e}");
-            return Err(true);
+            error!("Envelope error: {e:?}");
+            return None;
         }
     };
-    let msg = Message::AeadCiphertext {
-        nonce: *aead_nonce,
-        aead_payload: cypher_text
-    };
-    User::send_bytes(&mut stream, &msg);
 
-    // Can be used for multiple messages
-    let (_ck_2, _mk_2) = kdf_ck(ck_1.as_bytes());
-    Ok((large_x_plus_one, y_i_plus_1, rk_i_plus_2, format!("{}", message_text)))
+    Some(DatabaseContent {
+        lpk_c: stored.client_public_key,
+        key_id: server_key.id,
+        masking_key: stored.masking_key,
+        envelope: stored.envelope,
+        ksf: *ksf,
+        attempts: AttemptCounter::default(),
+        totp_secret: None,
+        totp_last_step: None,
+        generation: OsRng.next_u64(),
+    })
 }
 
-pub(crate) fn register(
-    aead_nonce:
-    &mut [u8; 12],
-    ad: &&[u8; 13],
-    database: &mut HashMap<Vec<u8>, DatabaseContent>,
-    g: ProjectivePoint,
-    username: &[u8],
-    password: &[u8]
-) -> bool {
-    {
-
-        // Calculate client_key_info and save in database
-        // println!("Google: Registering user: {}", String::from_utf8_lossy(username));
-        let s = Scalar::random(&mut OsRng);
-        let h_pw: ProjectivePoint =
-            hash2curve_demo::<k256::Secp256k1, ExpandMsgXmd<Sha3_256>>(password)
-                .expect("hash2curve_demo (k256 + SHA3-256) failed");
-
-        let rw = Sha3_256::digest([password.as_bytes(), (h_pw * s).to_bytes().as_bytes()].concat());
-        let (rw_key, _) = crypto::key_schedule::extract(None, rw.as_bytes());
-        let lsk_s = Scalar::random(&mut OsRng);
-        let lpk_s: ProjectivePoint = g * lsk_s;
-
-        let lsk_c = Scalar::random(&mut OsRng);
-        let lpk_c: ProjectivePoint = g * lsk_c;
-
-        let mut client_key_info = Vec::new();
-        client_key_info.extend_from_slice(&lpk_c.to_bytes());
-        client_key_info.extend_from_slice(&lsk_c.to_bytes());
-        client_key_info.extend_from_slice(&lpk_s.to_bytes());
-
-        OsRng.fill_bytes(aead_nonce);
-        let enc_client_keys = crypto::aead::encrypt(
-            rw_key.as_ref(),
-            &aead_nonce,
-            client_key_info.as_bytes(),
-            ad.as_ref(),
-        ).unwrap();
-
-        database.insert(username.to_vec(), DatabaseContent {
-            salt: s,
-            lpk_c,
-            lpk_s,
-            lsk_s,
-            aead_nonce: *aead_nonce,
-            enc_client_keys,
-        });
-        // println!("Google: Client keys saved.");
+/// Asks Alice to re-wrap her envelope if it was created for an older AKE key than the
+/// current one, and stores the new envelope and client public key.
+async fn rewrap_envelope(
+    handshake: &mut ServerHandshake,
+    stream: &mut impl Transport,
+    state: &Mutex<ServerState>,
+    username: &[u8]
+) -> Result<(), RequestError> {
+    let (key_id, current) = {
+        let state = lock(state);
+        let key_id = state.database.get(username).map(|record| record.key_id);
+        (key_id, state.ake_keys.current().clone())
     };
-    false
-}
-
-fn kdf_ck(ck_i: &[u8]) -> (Vec<u8>, Vec<u8>) {
-    let ck_i_plus_1 = compute_hmac(ck_i.as_bytes(), b"ChainKey");
-    let mk_i = compute_hmac(ck_i.as_bytes(), b"MessageKey");
-
-    (ck_i_plus_1, mk_i)
-}
+    if key_id.is_none_or(|key_id| key_id == current.id) {
+        return Ok(());
+    }
+    debug!("Re-wrapping the envelope for AKE key {}", current.id);
 
-fn kdf_rk(rk_i: &[u8], dh: &[u8]) -> ([u8; 32], [u8; 32]) {
-    let (_, hk) = crypto::key_schedule::extract(Some(rk_i), dh);
-    let rk_i_plus_1 = crypto::key_schedule::expand::<32>(&hk, b"RootKey").unwrap();
-    let ck_i = crypto::key_schedule::expand::<32>(&hk, b"ChainKey").unwrap();
+    // {{key_id, lpk_s}} of the current key, answered with {{envelope, lpk_c}}
+    let request = [STATUS_REWRAP, &current.id.to_be_bytes(), &Group::encode(&current.public_key())].concat();
+    send_status(handshake, stream, &request).await?;
+    let reply = match User::recv_sealed(stream, handshake).await? {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!("Decrypt error: {e}");
+            return Err(RequestError::Failed);
+        }
+    };
+    let (envelope_bytes, lpk_c) = reply.split_at(reply.len().min(envelope::ENVELOPE_LEN));
+    let (Ok(new_envelope), Some(lpk_c)) = (Envelope::from_bytes(envelope_bytes), Group::decode(lpk_c)) else {
+        return Err(abort(handshake, stream, "Malformed re-wrapped envelope", AlertDescription::UnexpectedMessage).await);
+    };
 
-    (rk_i_plus_1, ck_i)
+    // The account may have been deleted over another connection since the login
+    if let Some(record) = lock(state).database.get_mut(username) {
+        record.envelope = new_envelope;
+        record.lpk_c = lpk_c;
+        record.key_id = current.id;
+    }
+    audit(state, stream, AuditEvent::EnvelopeRewrapped, Some(username));
+    Ok(())
 }
 
-pub(crate) fn pq_tls(
-    mut stream: &mut TcpStream,
-    ca: &mut CA,
-    ad: &[u8; 13]
-) -> ([u8; 32], [u8; 32], [u8; 32], [u8; 32], [u8; 32], [u8; 32]) {
-
-    let mut nonce_s: [u8; 8] = [0u8; 8];
-    OsRng.fill_bytes(&mut nonce_s);
-    let mut aead_nonce: [u8; 12] = [0u8; 12];
-
-    // Receive PqtlsClientHello from Alive
-    // println!("Google: Waiting for PqtlsClientHello from Alice");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce_c, ek_bytes) = match msg {
-        Message::PqtlsClientHello { nonce_c, ek } => (nonce_c, ek),
-        _ => {
-            match msg {
-                Message::Reset {} => (),
-                _ => {
-                    eprintln!("Google: Unexpected message");
-                    return ([0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32]);
+/// Replaces the registration record of `username` after the client proved the old password
+/// and passed the second factor.
+pub(crate) async fn change_password(
+    oprf_seed: &[u8; 32],
+    ksf: &KeyStretching,
+    handshake: &mut ServerHandshake,
+    conn: &mut Connection<'_, impl Transport>,
+    username: &[u8],
+    content: &[u8]
+) -> Result<(), RequestError> {
+    authenticate(oprf_seed, handshake, conn, username, content).await?;
+    if !second_factor(handshake, conn, username).await? {
+        return Ok(());
+    }
+    conn.accept_attempt(username);
+
+    // Receive the new password and run a full re-registration
+    debug!("Waiting for new password from Alice");
+    let new_password = recv_account_request(handshake, conn.stream, conn.ad).await?;
+    let server_key = lock(conn.state).ake_keys.current().clone();
+    let status = match spawn_record(oprf_seed, ksf, &server_key, username, &new_password).await {
+        Some(mut record) => {
+            // The second factor is independent of the password. The account may have
+            // been deleted over another connection since the login.
+            let replaced = match lock(conn.state).database.get_mut(username) {
+                Some(old_record) => {
+                    record.totp_secret = old_record.totp_secret.clone();
+                    record.totp_last_step = old_record.totp_last_step;
+                    *old_record = record;
+                    true
                 }
+                None => false,
+            };
+            if !replaced {
+                return Err(abort(handshake, conn.stream, "Account deleted during password change", AlertDescription::UnknownUser).await);
             }
-            RECEIVED_RESET.store(true, Ordering::Relaxed);
-            panic!("Google: Unexpected message")
-        },
+            conn.audit(AuditEvent::PasswordChanged, Some(username));
+            STATUS_OK
+        }
+        None => STATUS_FAILED,
     };
-    const EK768_LEN: usize = 1184;
-    let ek_arr: [u8; EK768_LEN] = ek_bytes.as_slice().try_into()
-        .expect("ungültige EncapsulationKey-Bytes (falsche Länge)");
-    let ek = EncapsulationKey::<MlKem768Params>::from_bytes((&ek_arr).as_ref());
-
-    // Generate key pair and calculate shared key and ciphertext
-    // println!("Google: Generating key pair and calculating shared key and ciphertext");
-    let key_pair = MlDsa65::from_seed(&Seed::default());
-    let (ct, shared_key) = ek.encapsulate(&mut OsRng).unwrap();
-
-    // Calculate K1_c, K1_s, K2_c, K2_s
-    // println!("Google: Calculating K1_c, K1_s, K2_c, K2_s");
-    let (k1_c, k1_s) = key_schedule_1(shared_key.as_bytes());
-    let (k2_c, k2_s) = key_schedule_2(
-        nonce_c.clone().as_bytes(),
-        ek.as_bytes().as_bytes(),
-        nonce_s.clone().as_bytes(),
-        key_pair.verifying_key().encode().as_bytes(),
-        shared_key.as_bytes(),
-    );
-
-    // Get certificate for google's public key
-    // println!("Google: Getting certificate for google's public key");
-    let cert = ca.generate_certificate(key_pair.verifying_key().encode().as_bytes());
-
-    // Calculate google's signature
-    // println!("Google: Calculating google's signature");
-    let mut sign_digest_input = Vec::new();
-    sign_digest_input.extend_from_slice(nonce_c.clone().as_bytes());
-    sign_digest_input.extend_from_slice(ek.as_bytes().as_bytes());
-    sign_digest_input.extend_from_slice(nonce_s.as_bytes());
-    sign_digest_input.extend_from_slice(key_pair.verifying_key().encode().as_bytes());
-    sign_digest_input.extend_from_slice(cert.encode().as_bytes());
-
-    let google_sign = key_pair.signing_key().sign(&Sha256::digest(&sign_digest_input));
-
-    // Calculate google's MAC tag
-    // println!("Google: Calculating google's MAC tag");
-    let mut mac_s_input = Vec::new();
-    mac_s_input.extend_from_slice(nonce_c.clone().as_bytes());
-    mac_s_input.extend_from_slice(ek.as_bytes().as_bytes());
-    mac_s_input.extend_from_slice(nonce_s.as_bytes());
-    mac_s_input.extend_from_slice(key_pair.verifying_key().encode().as_bytes());
-    mac_s_input.extend_from_slice(google_sign.encode().as_bytes());
-    mac_s_input.extend_from_slice(cert.encode().as_bytes());
-    mac_s_input.extend_from_slice(b"ServerMAC");
-
-    let mac_s = compute_hmac(&k2_s, &Sha256::digest(&mac_s_input));
-
-    // Calculate K3_c, K3_s
-    // println!("Google: Calculating K3_c, K3_s");
-    let (k3_c, k3_s) = key_schedule_3(
-        nonce_c.clone().as_bytes(),
-        ek.as_bytes().as_bytes(),
-        nonce_s.as_bytes(),
-        key_pair.verifying_key().encode().as_bytes(),
-        shared_key.as_bytes(),
-        google_sign.encode().as_bytes(),
-        cert.encode().as_bytes(),
-        mac_s.as_bytes(),
-    );
-
-    // Send nonce_s, ct, verifying_key from Google to Alice
-    // println!("Google: Sending nonce_s, ct, verifying_key from Google to Alice");
-    let msg = Message::PqtlsServerHello {
-        nonce_s: nonce_s.to_vec(),
-        ct: ct.as_bytes().to_vec(),
-        verifying_key: key_pair.verifying_key().encode().as_bytes().to_vec(),
+    send_status(handshake, conn.stream, status).await
+}
+
+/// Removes the registration record of `username` after the client proved its password
+/// and passed the second factor.
+pub(crate) async fn delete_account(
+    oprf_seed: &[u8; 32],
+    handshake: &mut ServerHandshake,
+    conn: &mut Connection<'_, impl Transport>,
+    username: &[u8],
+    content: &[u8]
+) -> Result<(), RequestError> {
+    authenticate(oprf_seed, handshake, conn, username, content).await?;
+    if !second_factor(handshake, conn, username).await? {
+        return Ok(());
+    }
+    conn.accept_attempt(username);
+
+    // Receive the deletion confirmation, bound to the username
+    debug!("Waiting for deletion confirmation from Alice");
+    let confirmation = recv_account_request(handshake, conn.stream, conn.ad).await?;
+    let status = if confirmation == [b"DeleteAccount;".as_slice(), username].concat() {
+        if lock(conn.state).database.remove(username).is_none() {
+            return Err(abort(handshake, conn.stream, "Account already deleted", AlertDescription::UnknownUser).await);
+        }
+        conn.audit(AuditEvent::AccountDeleted, Some(username));
+        STATUS_OK
+    } else {
+        warn!("Invalid deletion confirmation");
+        STATUS_FAILED
     };
-    User::send_bytes(&mut stream, &msg);
-
-    // Send AEAD(k1_s, {{cert , google_sign, mac_s}}) message from Google to Alice
-    // println!("Google: Sending AEAD(k1_s, {{cert , google_sign, mac_s}}) message from Google to Alice");
-    let mut msg = Vec::new();
-    msg.extend_from_slice(cert.encode().as_bytes());
-    msg.extend_from_slice(google_sign.encode().as_bytes());
-    msg.extend_from_slice(mac_s.as_bytes());
-    OsRng.fill_bytes(&mut aead_nonce);
-    let cypher_text: Vec<u8> = match crypto::aead::encrypt(&k1_s, &aead_nonce, msg.as_bytes(), &ad.as_ref()) {
+    send_status(handshake, conn.stream, status).await
+}
+
+/// Receives AEAD(k3_c, {{nonce, AEAD(account_key, request)}}) and returns the request.
+async fn recv_account_request(
+    handshake: &mut ServerHandshake,
+    stream: &mut impl Transport,
+    ad: &[u8; 13],
+) -> Result<Vec<u8>, RequestError> {
+    let decrypted_msg: Vec<u8> = match User::recv_sealed(stream, handshake).await? {
         Ok(c) => c,
         Err(e) => {
-            eprintln!("Google: Encrypt error: {e}");
-            return ([0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32]);
+            warn!("Decrypt error: {e}");
+            return Err(RequestError::Failed);
         }
     };
+    if decrypted_msg.len() < 12 {
+        let what = format!("Decrypt error: received malformed account request (len={})", decrypted_msg.len());
+        return Err(abort(handshake, stream, &what, AlertDescription::UnexpectedMessage).await);
+    }
+    let Some(sk) = handshake.session_key() else {
+        return Err(abort(handshake, stream, "Account request before login", AlertDescription::UnexpectedMessage).await);
+    };
+    let (inner_nonce, c1) = decrypted_msg.split_at(12);
+    let account_key = crypto::key_schedule::account_key(sk.as_slice());
+    match crypto::aead::decrypt(&account_key, inner_nonce.try_into().unwrap(), c1, ad) {
+        Ok(c) => Ok(c),
+        Err(e) => Err(abort(handshake, stream, &format!("Decrypt error: {e}"), AlertDescription::DecryptError).await),
+    }
+}
 
-    let msg = Message::AeadCiphertext {
-        nonce: aead_nonce,
-        aead_payload: cypher_text,
+/// Sends AEAD(k3_s, status) to Alice.
+pub(crate) async fn send_status(
+    handshake: &mut ServerHandshake,
+    stream: &mut impl Transport,
+    status: &[u8]
+) -> Result<(), RequestError> {
+    let msg = match handshake.seal(status) {
+        Ok(msg) => msg,
+        Err(e) => return Err(fail(handshake, stream, "Encrypt", e).await),
     };
-    User::send_bytes(&mut stream, &msg);
-
-
-    // Receive and decrypt the AEAD message
-    // println!("Google: Receiving and decrypting the AEAD message");
-    let msg = User::recv_bytes(&mut stream);
-    let (nonce, aead_payload) = match msg {
-        Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-        _ => {
-            match msg {
-                Message::Reset {} => (),
-                _ => {
-                    eprintln!("Google: Unexpected message");
-                    return ([0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32]);
-                }
-            }
-            RECEIVED_RESET.store(true, Ordering::Relaxed);
-            panic!("Google: Unexpected message")
-        },
+    Ok(User::send_bytes(stream, handshake, &msg).await?)
+}
+
+/// Returns a stand-in record for an unknown `username`, so the login response looks the
+/// same for registered and unregistered users. The record is derived from the OPRF seed,
+/// so repeated logins for the same name see the same keys, and cached in `state` so the
+/// records it borrows its parameters from are only ranked once.
+pub(crate) fn fake_record(oprf_seed: &[u8; 32], state: &mut ServerState, username: &[u8]) -> DatabaseContent {
+    if let Some(record) = state.fake_records.get(username) {
+        return record.clone();
+    }
+    let (_, hk) = crypto::key_schedule::extract(Some(oprf_seed), username);
+
+    // The AKE key and key stretching of a real record, picked by the name so the choice
+    // stays the same as other users register; the defaults while there are none
+    let rank = |name: &[u8]| {
+        let info = [b"FakeRecord;".as_slice(), name].concat();
+        u64::from_be_bytes(*crypto::key_schedule::expand::<8>(&hk, &info).unwrap())
     };
-    let decrypted_msg: Vec<u8> = match crypto::aead::decrypt(&k1_c, &nonce, &aead_payload, &ad.as_ref()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Google: Decrypt error: {e}");
-            return ([0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32], [0u8; 32]);
-        }
+    let (key_id, ksf) = state.database.iter()
+        .min_by_key(|(name, _)| rank(name))
+        .map(|(_, record)| (record.key_id, record.ksf))
+        .unwrap_or((state.ake_keys.current().id, KeyStretching::default()));
+
+    let masking_key = crypto::key_schedule::expand::<32>(&hk, b"FakeMaskingKey").unwrap();
+    let lsk_c = crypto::key_schedule::expand::<32>(&hk, b"FakeClientKey").unwrap();
+    let lsk_c = Secret::new(Group::derive_scalar(lsk_c.as_slice(), b"FakeClientKey").unwrap_or_else(Group::random_scalar));
+
+    let record = DatabaseContent {
+        lpk_c: Group::public_key(&lsk_c),
+        key_id,
+        masking_key,
+        envelope: Envelope { nonce: [0u8; envelope::NONCE_LEN], auth_tag: [0u8; envelope::MAC_LEN] },
+        ksf,
+        attempts: AttemptCounter::default(),
+        totp_secret: None,
+        totp_last_step: None,
+        generation: 0,
     };
+    if state.fake_records.len() >= MAX_FAKE_RECORDS {
+        // Any of them, it is built the same way when needed again
+        let evicted = state.fake_records.keys().next().cloned().unwrap();
+        state.fake_records.remove(&evicted);
+    }
+    state.fake_records.insert(username.to_vec(), record.clone());
+    record
+}
 
-    // Verify the MAC tag from Alice
-    // println!("Google: Verifying the MAC tag from Alice");
-    let mut expected_mac_c_input = Vec::new();
-    expected_mac_c_input.extend_from_slice(nonce_c.clone().as_bytes());
-    expected_mac_c_input.extend_from_slice(ek.as_bytes().as_bytes());
-    expected_mac_c_input.extend_from_slice(nonce_s.as_bytes());
-    expected_mac_c_input.extend_from_slice(key_pair.verifying_key().encode().as_bytes());
-    expected_mac_c_input.extend_from_slice(google_sign.encode().as_bytes());
-    expected_mac_c_input.extend_from_slice(cert.encode().as_bytes());
-    expected_mac_c_input.extend_from_slice(b"ClientMAC");
-
-    assert!(verify_hmac(&k2_c, &Sha256::digest(&expected_mac_c_input), decrypted_msg.as_bytes()));
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
 
-    (k1_c, k1_s, k2_c, k2_s, k3_c, k3_s)
+/// Runs pq_tls with a new client and returns the established handshake.
+pub(crate) async fn pq_tls(
+    stream: &mut impl Transport,
+    ca: &CA,
+    identity: &ServerIdentity,
+    ad: &[u8; 13]
+) -> Result<ServerHandshake, RequestError> {
+    let mut handshake = ServerHandshake::with_identity(ca, identity, ad);
+    match next_event(&mut handshake, stream).await? {
+        Event::Established => Ok(handshake),
+        _ => Err(abort(&mut handshake, stream, "Unexpected message", AlertDescription::UnexpectedMessage).await),
+    }
 }
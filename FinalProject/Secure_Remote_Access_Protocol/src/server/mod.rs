@@ -1 +1,7 @@
-pub mod google;
\ No newline at end of file
+pub mod ake_keys;
+pub mod audit;
+pub mod blocking;
+pub mod google;
+pub mod rate_limit;
+pub mod session;
+pub mod store;
\ No newline at end of file
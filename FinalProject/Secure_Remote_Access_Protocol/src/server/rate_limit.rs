@@ -0,0 +1,332 @@
+//! Online brute-force protection for the OPRF stage.
+//!
+//! Every OPRF evaluation lets the client test one password guess offline, whether or not
+//! it finishes the AKE afterwards. An attempt is therefore counted as failed as soon as
+//! the server evaluates the OPRF, and only forgiven once the request is accepted: after
+//! key confirmation, or after the second factor for accounts that have one.
+//!
+//! Counters are kept per username (in the user's `DatabaseContent`, or in the limiter
+//! for unknown usernames so both look the same) and per peer address. Each failure
+//! doubles the wait before the next attempt; after `max_*_failures` the counter is
+//! locked for `lockout`, until it expires or an administrator unlocks it.
+//!
+//! The limiter's own maps are bounded: a counter that is not locked and whose last
+//! failure is older than `lockout` is forgotten when a new entry needs the room, and
+//! once `max_tracked` counters are in use the one with the oldest failure is evicted.
+
+use crate::crypto::participant::DatabaseContent;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitPolicy {
+    /// Failed attempts per username before the account is locked.
+    pub max_user_failures: u32,
+    /// Failed attempts per peer address before the address is locked.
+    pub max_peer_failures: u32,
+    /// Wait after the first failure, doubled for every further failure.
+    pub base_backoff: Duration,
+    /// Upper bound for the backoff.
+    pub max_backoff: Duration,
+    /// Duration of a lockout.
+    pub lockout: Duration,
+    /// Counters kept per peer address, and per unknown username, by the limiter.
+    pub max_tracked: usize,
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        RateLimitPolicy {
+            max_user_failures: 5,
+            max_peer_failures: 20,
+            base_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            lockout: Duration::from_secs(15 * 60),
+            max_tracked: 100_000,
+        }
+    }
+}
+
+/// Why an attempt was refused, and how long until the next one is allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limited {
+    Backoff(Duration),
+    Locked(Duration),
+}
+
+/// Failed attempts of one username or peer address.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttemptCounter {
+    pub failures: u32,
+    pub last_failure: Option<SystemTime>,
+    pub locked_until: Option<SystemTime>,
+}
+
+impl AttemptCounter {
+    /// Returns whether a new attempt is allowed at `now`.
+    pub fn check(&self, policy: &RateLimitPolicy, now: SystemTime) -> Result<(), Limited> {
+        if let Some(locked_until) = self.locked_until.filter(|&t| t > now) {
+            return Err(Limited::Locked(locked_until.duration_since(now).unwrap()));
+        }
+        if let Some(next_attempt) = self.last_failure.map(|t| t + self.backoff(policy)).filter(|&t| t > now) {
+            return Err(Limited::Backoff(next_attempt.duration_since(now).unwrap()));
+        }
+        Ok(())
+    }
+
+    /// `base_backoff * 2^(failures - 1)`, capped at `max_backoff`.
+    fn backoff(&self, policy: &RateLimitPolicy) -> Duration {
+        if self.failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(self.failures - 1).unwrap_or(u32::MAX);
+        policy.base_backoff.saturating_mul(factor).min(policy.max_backoff)
+    }
+
+    fn record_failure(&mut self, max_failures: u32, policy: &RateLimitPolicy, now: SystemTime) {
+        self.failures = self.failures.saturating_add(1);
+        self.last_failure = Some(now);
+        if self.failures >= max_failures {
+            self.locked_until = Some(now + policy.lockout);
+        }
+    }
+
+    /// Takes back the failure counted by the last `record_failure`.
+    fn forgive_one(&mut self) {
+        self.failures = self.failures.saturating_sub(1);
+        if self.failures == 0 {
+            *self = AttemptCounter::default();
+        }
+    }
+
+    pub fn is_locked(&self, now: SystemTime) -> bool {
+        self.locked_until.is_some_and(|locked_until| locked_until > now)
+    }
+
+    /// Whether the counter no longer limits anything at `now` and can be forgotten: it is
+    /// not locked and nothing failed for at least `lockout`.
+    fn is_stale(&self, policy: &RateLimitPolicy, now: SystemTime) -> bool {
+        !self.is_locked(now) && self.last_failure.is_none_or(|t| t + policy.lockout <= now)
+    }
+}
+
+/// Returns the counter of `key`. Before a new counter is inserted into a full map, the
+/// stale counters are pruned and, if the map is still full, the one with the oldest
+/// failure is evicted.
+fn tracked<'a, K: Eq + Hash + Clone>(
+    map: &'a mut HashMap<K, AttemptCounter>,
+    key: &K,
+    policy: &RateLimitPolicy,
+    now: SystemTime,
+) -> &'a mut AttemptCounter {
+    if !map.contains_key(key) && map.len() >= policy.max_tracked {
+        map.retain(|_, counter| !counter.is_stale(policy, now));
+        if map.len() >= policy.max_tracked {
+            let oldest = map.iter().min_by_key(|(_, counter)| counter.last_failure).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                map.remove(&oldest);
+            }
+        }
+    }
+    map.entry(key.clone()).or_default()
+}
+
+pub struct RateLimiter {
+    pub policy: RateLimitPolicy,
+    peers: HashMap<IpAddr, AttemptCounter>,
+    unknown_users: HashMap<Vec<u8>, AttemptCounter>,
+}
+
+impl RateLimiter {
+    pub fn new(policy: RateLimitPolicy) -> Self {
+        RateLimiter { policy, peers: HashMap::new(), unknown_users: HashMap::new() }
+    }
+
+    fn user_counter<'a>(
+        &'a mut self,
+        database: &'a mut HashMap<Vec<u8>, DatabaseContent>,
+        username: &[u8],
+        now: SystemTime,
+    ) -> &'a mut AttemptCounter {
+        match database.get_mut(username) {
+            Some(record) => &mut record.attempts,
+            None => tracked(&mut self.unknown_users, &username.to_vec(), &self.policy, now),
+        }
+    }
+
+    /// Checks `username` and `peer`. If the attempt may proceed, it is counted as failed
+    /// for both until `record_success` is called.
+    pub fn begin_attempt(
+        &mut self,
+        database: &mut HashMap<Vec<u8>, DatabaseContent>,
+        username: &[u8],
+        peer: IpAddr,
+        now: SystemTime,
+    ) -> Result<(), Limited> {
+        let policy = self.policy;
+        tracked(&mut self.peers, &peer, &policy, now).check(&policy, now)?;
+        self.user_counter(database, username, now).check(&policy, now)?;
+
+        self.peers.get_mut(&peer).unwrap().record_failure(policy.max_peer_failures, &policy, now);
+        self.user_counter(database, username, now).record_failure(policy.max_user_failures, &policy, now);
+        Ok(())
+    }
+
+    /// The attempt was accepted: clears the username's counter and takes back the
+    /// failure counted for `peer`. The peer counter is not cleared, so logging in to one
+    /// account does not reset the guesses made against others.
+    pub fn record_success(
+        &mut self,
+        database: &mut HashMap<Vec<u8>, DatabaseContent>,
+        username: &[u8],
+        peer: IpAddr,
+    ) {
+        match database.get_mut(username) {
+            Some(record) => record.attempts = AttemptCounter::default(),
+            None => {
+                self.unknown_users.remove(username);
+            }
+        }
+        if let Some(counter) = self.peers.get_mut(&peer) {
+            counter.forgive_one();
+            if counter.failures == 0 {
+                self.peers.remove(&peer);
+            }
+        }
+    }
+
+    /// Whether the counter of `username` is locked at `now`.
+    pub fn is_user_locked(&self, database: &HashMap<Vec<u8>, DatabaseContent>, username: &[u8], now: SystemTime) -> bool {
+        match database.get(username) {
+            Some(record) => record.attempts.is_locked(now),
+            None => self.unknown_users.get(username).is_some_and(|counter| counter.is_locked(now)),
+        }
+    }
+
+    /// Administrator unlock: clears the counter of `username`.
+    pub fn unlock_user(&mut self, database: &mut HashMap<Vec<u8>, DatabaseContent>, username: &[u8]) {
+        if let Some(record) = database.get_mut(username) {
+            record.attempts = AttemptCounter::default();
+        }
+        self.unknown_users.remove(username);
+    }
+
+    /// Administrator unlock: clears the counter of `peer`.
+    pub fn unlock_peer(&mut self, peer: IpAddr) {
+        self.peers.remove(&peer);
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(RateLimitPolicy::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ksf::KeyStretching;
+    use crate::server::google::{self, ServerState};
+    use crate::transport::blocking;
+    use std::net::Ipv4Addr;
+    use std::sync::Mutex;
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    const OTHER_PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    fn setup() -> (RateLimiter, HashMap<Vec<u8>, DatabaseContent>) {
+        let state = Mutex::new(ServerState::default());
+        let registered = blocking::runtime().block_on(google::register(&[7u8; 32], &KeyStretching::Identity, &state, None, b"alice", b"12345"));
+        assert!(!registered);
+        (RateLimiter::default(), state.into_inner().unwrap().database)
+    }
+
+    #[test]
+    fn backoff_doubles_until_lockout() {
+        let (mut limiter, mut database) = setup();
+        let policy = limiter.policy;
+        let mut now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        for failures in 1..policy.max_user_failures {
+            assert!(limiter.begin_attempt(&mut database, b"alice", PEER, now).is_ok());
+            let backoff = policy.base_backoff * 2u32.pow(failures - 1);
+            assert_eq!(limiter.begin_attempt(&mut database, b"alice", PEER, now), Err(Limited::Backoff(backoff)));
+            now += backoff;
+        }
+
+        assert!(limiter.begin_attempt(&mut database, b"alice", PEER, now).is_ok());
+        assert_eq!(database[b"alice".as_slice()].attempts.failures, policy.max_user_failures);
+        assert!(database[b"alice".as_slice()].attempts.is_locked(now));
+        assert_eq!(
+            limiter.begin_attempt(&mut database, b"alice", OTHER_PEER, now + policy.max_backoff),
+            Err(Limited::Locked(policy.lockout - policy.max_backoff))
+        );
+        assert!(limiter.begin_attempt(&mut database, b"alice", OTHER_PEER, now + policy.lockout).is_ok());
+    }
+
+    #[test]
+    fn success_and_unlock_clear_the_user_counter() {
+        let (mut limiter, mut database) = setup();
+        let policy = limiter.policy;
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        assert!(limiter.begin_attempt(&mut database, b"alice", PEER, now).is_ok());
+        limiter.record_success(&mut database, b"alice", PEER);
+        assert_eq!(database[b"alice".as_slice()].attempts, AttemptCounter::default());
+        assert!(limiter.begin_attempt(&mut database, b"alice", PEER, now).is_ok());
+
+        database.get_mut(b"alice".as_slice()).unwrap().attempts.locked_until = Some(now + policy.lockout);
+        assert!(matches!(limiter.begin_attempt(&mut database, b"alice", OTHER_PEER, now), Err(Limited::Locked(_))));
+        limiter.unlock_user(&mut database, b"alice");
+        assert!(limiter.begin_attempt(&mut database, b"alice", OTHER_PEER, now).is_ok());
+    }
+
+    #[test]
+    fn unknown_users_and_peers_are_limited() {
+        let (mut limiter, mut database) = setup();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        assert!(limiter.begin_attempt(&mut database, b"mallory", PEER, now).is_ok());
+        assert!(matches!(limiter.begin_attempt(&mut database, b"mallory", OTHER_PEER, now), Err(Limited::Backoff(_))));
+
+        // The peer is still backing off after its attempt, even for another username
+        assert!(matches!(limiter.begin_attempt(&mut database, b"alice", PEER, now), Err(Limited::Backoff(_))));
+        limiter.unlock_peer(PEER);
+        assert!(limiter.begin_attempt(&mut database, b"alice", PEER, now).is_ok());
+    }
+
+    #[test]
+    fn stale_counters_are_pruned_and_the_maps_are_bounded() {
+        let (_, mut database) = setup();
+        let mut limiter = RateLimiter::new(RateLimitPolicy { max_tracked: 3, ..RateLimitPolicy::default() });
+        let policy = limiter.policy;
+        let mut now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        // A username sprayed from many addresses never tracks more than `max_tracked` of either
+        for i in 0..10u8 {
+            let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 1, i));
+            let _ = limiter.begin_attempt(&mut database, &[b'u', i], peer, now);
+            assert!(limiter.peers.len() <= policy.max_tracked);
+            assert!(limiter.unknown_users.len() <= policy.max_tracked);
+            now += Duration::from_secs(1);
+        }
+        // The oldest were evicted
+        assert!(!limiter.peers.contains_key(&IpAddr::V4(Ipv4Addr::new(10, 0, 1, 0))));
+        assert!(limiter.unknown_users.contains_key([b'u', 9].as_slice()));
+
+        // After `lockout` without failures, every old counter is pruned for a new one
+        now += policy.lockout;
+        assert!(limiter.begin_attempt(&mut database, b"mallory", PEER, now).is_ok());
+        assert_eq!(limiter.peers.len(), 1);
+        assert_eq!(limiter.unknown_users.len(), 1);
+
+        // Locked counters are not stale
+        let locked = AttemptCounter { failures: 20, last_failure: Some(now), locked_until: Some(now + policy.lockout) };
+        assert!(!locked.is_stale(&policy, now + policy.lockout - Duration::from_secs(1)));
+        assert!(locked.is_stale(&policy, now + policy.lockout));
+    }
+}
@@ -0,0 +1,134 @@
+//! Lifetime of logged-in sessions and the tickets that resume them.
+//!
+//! Google drops a connection whose session stays silent for `idle_timeout`, and ends a
+//! session with close_notify once it is older than `lifetime`. After each login Google
+//! issues a ticket, with which Alice resumes an ended session without her password
+//! until `ticket_lifetime` after the password login.
+//!
+//! Tickets are stateless: Google seals the username, the generation of the user's
+//! record, the resumption secret of the session and the expiry under a key derived from
+//! the OPRF seed, and keeps nothing. A password change or a new registration under the
+//! same name draws a new generation, which ends every earlier ticket.
+
+use crate::crypto;
+use crate::crypto::secret::{Secret, SecretKey};
+use aes_gcm::aead::OsRng;
+use rand_core::RngCore;
+use std::time::Duration;
+
+/// Associated data of a sealed ticket.
+const TICKET_AD: &[u8] = b"SRAP resumption ticket";
+
+#[derive(Clone, Copy, Debug)]
+pub struct SessionPolicy {
+    /// Longest silence, without messages or heartbeats, before the connection is dropped.
+    pub idle_timeout: Duration,
+    /// Age at which a session is ended and has to be resumed.
+    pub lifetime: Duration,
+    /// How long after the password login a session can be resumed.
+    pub ticket_lifetime: Duration,
+}
+
+impl Default for SessionPolicy {
+    fn default() -> Self {
+        SessionPolicy {
+            idle_timeout: Duration::from_secs(60),
+            lifetime: Duration::from_secs(60 * 60),
+            ticket_lifetime: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// What Google needs to resume a session.
+pub struct Ticket {
+    pub username: Vec<u8>,
+    /// `DatabaseContent::generation` of the record the ticket was issued for.
+    pub generation: u64,
+    pub resumption_secret: SecretKey,
+    /// Unix time after which the ticket is refused.
+    pub expires_at: u64,
+}
+
+impl Ticket {
+    /// nonce || AEAD(ticket_key, {{expires_at, generation, resumption_secret, username}})
+    pub fn seal(&self, oprf_seed: &[u8; 32]) -> Option<Vec<u8>> {
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        let plaintext = Secret::new(
+            [
+                self.expires_at.to_be_bytes().as_slice(),
+                &self.generation.to_be_bytes(),
+                self.resumption_secret.as_slice(),
+                &self.username,
+            ]
+            .concat(),
+        );
+        let sealed = crypto::aead::encrypt(&ticket_key(oprf_seed), &nonce, plaintext.as_slice(), TICKET_AD).ok()?;
+        Some([nonce.as_slice(), &sealed].concat())
+    }
+
+    /// Opens a ticket sealed by `seal`, `None` if it was not issued with this seed or
+    /// expired before `now`.
+    pub fn open(oprf_seed: &[u8; 32], ticket: &[u8], now: u64) -> Option<Ticket> {
+        if ticket.len() < 12 {
+            return None;
+        }
+        let (nonce, sealed) = ticket.split_at(12);
+        let plaintext = crypto::aead::decrypt(&ticket_key(oprf_seed), nonce.try_into().unwrap(), sealed, TICKET_AD).ok()?;
+        let plaintext = Secret::new(plaintext);
+        if plaintext.len() < 8 + 8 + 32 {
+            return None;
+        }
+
+        let (expires_at, rest) = plaintext.split_at(8);
+        let (generation, rest) = rest.split_at(8);
+        let (resumption_secret, username) = rest.split_at(32);
+        let expires_at = u64::from_be_bytes(expires_at.try_into().unwrap());
+        let generation = u64::from_be_bytes(generation.try_into().unwrap());
+        if now > expires_at {
+            return None;
+        }
+        let mut key = SecretKey::default();
+        key.copy_from_slice(resumption_secret);
+        Some(Ticket { username: username.to_vec(), generation, resumption_secret: key, expires_at })
+    }
+}
+
+fn ticket_key(oprf_seed: &[u8; 32]) -> SecretKey {
+    let (_, hk) = crypto::key_schedule::extract(Some(oprf_seed), b"Ticket");
+    crypto::key_schedule::expand::<32>(&hk, b"TicketKey").unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticket() -> Ticket {
+        let mut resumption_secret = SecretKey::default();
+        OsRng.fill_bytes(resumption_secret.as_mut_slice());
+        Ticket { username: b"alice".to_vec(), generation: 42, resumption_secret, expires_at: 1000 }
+    }
+
+    #[test]
+    fn tickets_open_with_the_same_seed_until_they_expire() {
+        let seed = [7u8; 32];
+        let issued = ticket();
+        let sealed = issued.seal(&seed).unwrap();
+
+        let opened = Ticket::open(&seed, &sealed, 1000).unwrap();
+        assert_eq!(opened.username, b"alice");
+        assert_eq!(opened.generation, 42);
+        assert!(opened.resumption_secret == issued.resumption_secret);
+        assert!(Ticket::open(&seed, &sealed, 1001).is_none());
+        assert!(Ticket::open(&[8u8; 32], &sealed, 0).is_none());
+    }
+
+    #[test]
+    fn tampered_tickets_are_refused() {
+        let seed = [7u8; 32];
+        let mut sealed = ticket().seal(&seed).unwrap();
+        sealed[20] ^= 1;
+        assert!(Ticket::open(&seed, &sealed, 0).is_none());
+        assert!(Ticket::open(&seed, &sealed[..8], 0).is_none());
+    }
+}
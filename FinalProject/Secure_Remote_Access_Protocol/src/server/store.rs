@@ -0,0 +1,419 @@
+//! Google's data between runs, kept as files in one directory:
+//!
+//! - `users.db`: the registration records, with their attempt counters
+//! - `oprf.seed`: the seed every per-user OPRF key is derived from
+//! - `ake.keys`: Google's 3DH private keys, the current one and those in their overlap
+//! - `ca.seed`: the ML-DSA seed of the CA
+//! - `server.seed`, `server.cert`: Google's ML-DSA seed and the CA's certificate for it
+//! - `audit.seed`: the ML-DSA seed of the key signing the audit checkpoints, kept when
+//!   the server certificate is reissued so older checkpoints still verify
+//! - `revoked`: hex fingerprints of revoked server keys, one per line
+//!
+//! Seeds, AKE keys and the server certificate are created on first use. `srap-admin` works on the
+//! same files while Google is stopped, since Google rewrites `users.db` after every request.
+
+use crate::crypto::dh_group::{DhGroup, Group};
+use crate::crypto::envelope::Envelope;
+use crate::crypto::ksf::KeyStretching;
+use crate::crypto::participant::{DatabaseContent, CA};
+use crate::crypto::secret::{Secret, SecretKey};
+use crate::crypto::totp;
+use crate::handshake::server::ServerIdentity;
+use crate::server::ake_keys::{AkeKey, AkeKeys};
+use crate::server::rate_limit::AttemptCounter;
+use aes_gcm::aead::OsRng;
+use ml_dsa::{KeyGen, KeyPair, MlDsa65, Seed, VerifyingKey};
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use zeroize::Zeroize;
+
+/// Directory Google keeps its data in.
+pub const DATA_DIR: &str = "google_data";
+
+/// Version of the `users.db` format. Version 1 kept a server key pair in every record,
+/// version 2 did not name the DH group, version 3 had no record generations, version 4
+/// kept a CA certificate for the OPRF key.
+const USERS_VERSION: u32 = 5;
+
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Store { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The stored records, none if nothing was stored yet.
+    pub fn load_users(&self) -> io::Result<HashMap<Vec<u8>, DatabaseContent>> {
+        match fs::read(self.dir.join("users.db")) {
+            Ok(bytes) => decode_users(&bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save_users(&self, database: &HashMap<Vec<u8>, DatabaseContent>) -> io::Result<()> {
+        self.write("users.db", &encode_users(database))
+    }
+
+    /// Google's AKE keys, a single fresh one if none were stored yet.
+    pub fn ake_keys(&self) -> io::Result<AkeKeys> {
+        match fs::read(self.dir.join("ake.keys")) {
+            Ok(bytes) => decode_ake_keys(&Secret::new(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let keys = AkeKeys::default();
+                self.save_ake_keys(&keys)?;
+                Ok(keys)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save_ake_keys(&self, keys: &AkeKeys) -> io::Result<()> {
+        let keys = keys
+            .keys()
+            .iter()
+            .map(|key| StoredAkeKey { id: key.id, private_key: Group::scalar_to_bytes(&key.private_key).to_vec(), retires_at: key.retires_at })
+            .collect();
+        let stored = StoredAkeKeys { group: Group::NAME.to_string(), keys };
+        let encoded = Secret::new(bincode::serialize(&stored).map_err(|_| invalid_data("unencodable AKE keys"))?);
+        self.write("ake.keys", &encoded)
+    }
+
+    pub fn oprf_seed(&self) -> io::Result<[u8; 32]> {
+        self.seed("oprf.seed")
+    }
+
+    /// The CA, refusing the revoked server keys.
+    pub fn ca(&self) -> io::Result<CA> {
+        Ok(CA::from_seed(&self.seed("ca.seed")?).with_revoked(self.revoked()?))
+    }
+
+    /// Google's identity, issued by `ca` if there is none yet.
+    pub fn identity(&self, ca: &CA) -> io::Result<ServerIdentity> {
+        match (self.read_seed("server.seed")?, fs::read(self.dir.join("server.cert"))) {
+            (Some(seed), Ok(certificate)) => Ok(ServerIdentity::new(seed, certificate)),
+            (_, Err(e)) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => self.issue_identity(ca),
+        }
+    }
+
+    /// Replaces Google's identity with a new key pair, certified by `ca`.
+    pub fn issue_identity(&self, ca: &CA) -> io::Result<ServerIdentity> {
+        let mut seed = Secret::new([0u8; 32]);
+        OsRng.fill_bytes(seed.as_mut_slice());
+        let verifying_key = ServerIdentity::new(*seed, Vec::new()).key_pair().verifying_key().encode();
+        let certificate = ca.generate_certificate(verifying_key.as_slice()).encode().to_vec();
+        self.write("server.seed", seed.as_slice())?;
+        self.write("server.cert", &certificate)?;
+        Ok(ServerIdentity::new(*seed, certificate))
+    }
+
+    /// The key pair signing the audit checkpoints.
+    pub fn audit_key_pair(&self) -> io::Result<KeyPair<MlDsa65>> {
+        Ok(MlDsa65::from_seed(&Seed::from(self.seed("audit.seed")?)))
+    }
+
+    /// The key the audit checkpoints verify with. Unlike `audit_key_pair`, never creates one.
+    pub fn audit_verifying_key(&self) -> io::Result<VerifyingKey<MlDsa65>> {
+        let seed = self.read_seed("audit.seed")?.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no audit.seed"))?;
+        Ok(MlDsa65::from_seed(&Seed::from(seed)).verifying_key().clone())
+    }
+
+    /// Fingerprints of the revoked server keys.
+    pub fn revoked(&self) -> io::Result<Vec<[u8; 32]>> {
+        let list = match fs::read_to_string(self.dir.join("revoked")) {
+            Ok(list) => list,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        list.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| parse_fingerprint(line.trim()).ok_or_else(|| invalid_data("malformed fingerprint in revoked")))
+            .collect()
+    }
+
+    pub fn revoke(&self, fingerprint: [u8; 32]) -> io::Result<()> {
+        let mut revoked = self.revoked()?;
+        if !revoked.contains(&fingerprint) {
+            revoked.push(fingerprint);
+        }
+        let list: String = revoked.iter().map(|fingerprint| format!("{}\n", to_hex(fingerprint))).collect();
+        self.write("revoked", list.as_bytes())
+    }
+
+    fn seed(&self, name: &str) -> io::Result<[u8; 32]> {
+        if let Some(seed) = self.read_seed(name)? {
+            return Ok(seed);
+        }
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        self.write(name, &seed)?;
+        Ok(seed)
+    }
+
+    fn read_seed(&self, name: &str) -> io::Result<Option<[u8; 32]>> {
+        match fs::read(self.dir.join(name)) {
+            Ok(bytes) => {
+                let bytes = Secret::new(bytes);
+                let seed = <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| invalid_data("malformed seed"))?;
+                Ok(Some(seed))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write(&self, name: &str, contents: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        write_private(&self.dir.join(name), contents)
+    }
+}
+
+/// Replaces the file at `path` in one step, readable by the owner only.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(temporary, path)
+}
+
+/// A `DatabaseContent` in the `users.db` format.
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    username: Vec<u8>,
+    lpk_c: Vec<u8>,
+    key_id: u32,
+    masking_key: Vec<u8>,
+    envelope: Vec<u8>,
+    ksf: Vec<u8>,
+    attempts: AttemptCounter,
+    totp_secret: Option<Vec<u8>>,
+    totp_last_step: Option<u64>,
+    generation: u64,
+}
+
+impl Drop for StoredRecord {
+    fn drop(&mut self) {
+        self.masking_key.zeroize();
+        self.totp_secret.zeroize();
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredUsers {
+    version: u32,
+    /// `DhGroup::NAME` of the group the client public keys belong to.
+    group: String,
+    users: Vec<StoredRecord>,
+}
+
+/// The records in the `users.db` format, also used to export them.
+pub fn encode_users(database: &HashMap<Vec<u8>, DatabaseContent>) -> Vec<u8> {
+    let users = database
+        .iter()
+        .map(|(username, record)| StoredRecord {
+            username: username.clone(),
+            lpk_c: Group::encode(&record.lpk_c),
+            key_id: record.key_id,
+            masking_key: record.masking_key.to_vec(),
+            envelope: record.envelope.to_bytes().to_vec(),
+            ksf: record.ksf.to_bytes().to_vec(),
+            attempts: record.attempts.clone(),
+            totp_secret: record.totp_secret.as_ref().map(|secret| secret.to_vec()),
+            totp_last_step: record.totp_last_step,
+            generation: record.generation,
+        })
+        .collect();
+    bincode::serialize(&StoredUsers { version: USERS_VERSION, group: Group::NAME.to_string(), users }).unwrap_or_default()
+}
+
+/// Parses records in the `users.db` format.
+pub fn decode_users(bytes: &[u8]) -> io::Result<HashMap<Vec<u8>, DatabaseContent>> {
+    let stored: StoredUsers = bincode::deserialize(bytes).map_err(|_| invalid_data("malformed user database"))?;
+    if stored.version != USERS_VERSION {
+        return Err(invalid_data("unsupported user database version"));
+    }
+    if stored.group != Group::NAME {
+        return Err(invalid_data(&format!("user database for the {} group, built for {}", stored.group, Group::NAME)));
+    }
+    stored
+        .users
+        .iter()
+        .map(|stored| Ok((stored.username.clone(), decode_record(stored).ok_or_else(|| invalid_data("malformed user record"))?)))
+        .collect()
+}
+
+fn decode_record(stored: &StoredRecord) -> Option<DatabaseContent> {
+    let mut masking_key = SecretKey::default();
+    if stored.masking_key.len() != masking_key.len() {
+        return None;
+    }
+    masking_key.copy_from_slice(&stored.masking_key);
+    let totp_secret = match &stored.totp_secret {
+        Some(secret) => Some(Secret::new(<[u8; totp::SECRET_LEN]>::try_from(secret.as_slice()).ok()?)),
+        None => None,
+    };
+
+    Some(DatabaseContent {
+        lpk_c: Group::decode(&stored.lpk_c)?,
+        key_id: stored.key_id,
+        masking_key,
+        envelope: Envelope::from_bytes(&stored.envelope).ok()?,
+        ksf: KeyStretching::from_bytes(&stored.ksf).ok()?,
+        attempts: stored.attempts.clone(),
+        totp_secret,
+        totp_last_step: stored.totp_last_step,
+        generation: stored.generation,
+    })
+}
+
+/// An `AkeKey` in the `ake.keys` format.
+#[derive(Serialize, Deserialize)]
+struct StoredAkeKey {
+    id: u32,
+    private_key: Vec<u8>,
+    retires_at: Option<SystemTime>,
+}
+
+impl Drop for StoredAkeKey {
+    fn drop(&mut self) {
+        self.private_key.zeroize();
+    }
+}
+
+/// The `ake.keys` format.
+#[derive(Serialize, Deserialize)]
+struct StoredAkeKeys {
+    /// `DhGroup::NAME` of the group the keys belong to.
+    group: String,
+    keys: Vec<StoredAkeKey>,
+}
+
+fn decode_ake_keys(bytes: &[u8]) -> io::Result<AkeKeys> {
+    let stored: StoredAkeKeys = bincode::deserialize(bytes).map_err(|_| invalid_data("malformed AKE keys"))?;
+    if stored.group != Group::NAME {
+        return Err(invalid_data(&format!("AKE keys for the {} group, built for {}", stored.group, Group::NAME)));
+    }
+    let keys = stored
+        .keys
+        .iter()
+        .map(|stored| {
+            let private_key = Secret::new(<[u8; 32]>::try_from(stored.private_key.as_slice()).ok()?);
+            let private_key = Group::scalar_from_bytes(&private_key)?;
+            Some(AkeKey { id: stored.id, private_key: Secret::new(private_key), retires_at: stored.retires_at })
+        })
+        .collect::<Option<Vec<AkeKey>>>()
+        .and_then(AkeKeys::from_keys);
+    keys.ok_or_else(|| invalid_data("malformed AKE keys"))
+}
+
+/// Lowercase hex of a server key fingerprint.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Parses a fingerprint written by `to_hex`.
+pub fn parse_fingerprint(hex: &str) -> Option<[u8; 32]> {
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect();
+    bytes?.try_into().ok()
+}
+
+fn invalid_data(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::google::{self, ServerState};
+    use std::sync::Mutex;
+
+    fn temp_store() -> Store {
+        Store::new(std::env::temp_dir().join(format!("srap_store_{}", OsRng.next_u64())))
+    }
+
+    #[tokio::test]
+    async fn records_survive_a_round_trip() {
+        let state = Mutex::new(ServerState::default());
+        let mut oprf_seed = [0u8; 32];
+        OsRng.fill_bytes(&mut oprf_seed);
+        assert!(!google::register(&oprf_seed, &KeyStretching::Identity, &state, Some(totp::generate_secret()), b"alice", b"12345").await);
+        state.lock().unwrap().database.get_mut(b"alice".as_slice()).unwrap().attempts.failures = 2;
+
+        let store = temp_store();
+        let database = &state.lock().unwrap().database;
+        store.save_users(database).unwrap();
+        let loaded = store.load_users().unwrap();
+        fs::remove_dir_all(store.dir()).unwrap();
+
+        let (record, loaded) = (&database[b"alice".as_slice()], &loaded[b"alice".as_slice()]);
+        assert_eq!(loaded.lpk_c, record.lpk_c);
+        assert_eq!(loaded.key_id, record.key_id);
+        assert!(loaded.masking_key == record.masking_key);
+        assert_eq!(loaded.envelope, record.envelope);
+        assert_eq!(loaded.ksf, record.ksf);
+        assert_eq!(loaded.attempts, record.attempts);
+        assert!(loaded.totp_secret == record.totp_secret);
+    }
+
+    #[test]
+    fn seeds_keys_identity_and_revocations_persist() {
+        let store = temp_store();
+        assert!(store.load_users().unwrap().is_empty());
+        assert_eq!(store.oprf_seed().unwrap(), store.oprf_seed().unwrap());
+
+        let mut ake_keys = store.ake_keys().unwrap();
+        ake_keys.rotate(std::time::Duration::from_secs(60), SystemTime::now());
+        store.save_ake_keys(&ake_keys).unwrap();
+        let loaded = store.ake_keys().unwrap();
+        assert_eq!(loaded.keys().len(), 2);
+        assert_eq!(loaded.current().id, ake_keys.current().id);
+        assert_eq!(loaded.current().public_key(), ake_keys.current().public_key());
+        assert_eq!(loaded.keys()[0].retires_at, ake_keys.keys()[0].retires_at);
+
+        let ca = store.ca().unwrap();
+        let identity = store.identity(&ca).unwrap();
+        let verifying_key = identity.key_pair().verifying_key().encode();
+        assert_eq!(store.identity(&ca).unwrap().key_pair().verifying_key().encode(), verifying_key);
+        assert!(!store.ca().unwrap().is_revoked(verifying_key.as_slice()));
+
+        store.revoke(CA::fingerprint(verifying_key.as_slice())).unwrap();
+        assert!(store.ca().unwrap().is_revoked(verifying_key.as_slice()));
+        let reissued = store.issue_identity(&ca).unwrap().key_pair().verifying_key().encode();
+        assert!(!store.ca().unwrap().is_revoked(reissued.as_slice()));
+        fs::remove_dir_all(store.dir()).unwrap();
+    }
+
+    #[test]
+    fn the_audit_key_outlives_reissued_certificates() {
+        let store = temp_store();
+        assert_eq!(store.audit_verifying_key().unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(!store.dir().join("audit.seed").exists());
+
+        let verifying_key = store.audit_key_pair().unwrap().verifying_key().clone();
+        store.issue_identity(&store.ca().unwrap()).unwrap();
+        assert_eq!(store.audit_verifying_key().unwrap(), verifying_key);
+        assert_eq!(*store.audit_key_pair().unwrap().verifying_key(), verifying_key);
+        fs::remove_dir_all(store.dir()).unwrap();
+    }
+}
@@ -1,63 +1,72 @@
 mod tests {
     use crate::client::alice;
     use crate::server::google;
-    use crate::crypto::participant::{DatabaseContent, Message, User, CA};
-    use crate::{crypto};
-    use elliptic_curve::{Field, Group};
-    use hmac::digest::Output;
-    use image::EncodableLayout;
-    use k256::{ProjectivePoint, Scalar};
+    use crate::server::google::ServerState;
+    use crate::server::audit::{self, AuditLog};
+    use crate::handshake::server::{server_key_pair, ServerIdentity};
+    use crate::server::rate_limit::{RateLimitPolicy, RateLimiter};
+    use crate::server::session::SessionPolicy;
+    use crate::crypto::ksf::KeyStretching;
+    use crate::crypto::totp;
+    use crate::crypto::secret::{Secret, SecretKey};
+    use crate::crypto::participant::{RequestError, User, CA, STATUS_OK, STATUS_TOTP_REQUIRED};
+    use crate::crypto;
+    use crate::handshake::ratchet::{ClientRatchet, ServerRatchet};
+    use crate::handshake::AlertDescription;
+    use crate::metrics::{http, METRICS};
+    use crate::transport::{Transport, TransportError};
+    use crate::crypto::dh_group::{DhGroup, DhScalar, Group};
+        use image::EncodableLayout;
     use rand_core::OsRng;
     use rand_core::RngCore;
-    use sha2::Sha256;
-    use std::collections::HashMap;
-    use std::net::{TcpListener, TcpStream};
+        use std::collections::HashMap;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use std::time::{Duration, SystemTime};
+    use std::net::SocketAddr;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time;
+
+    /// Alice and Google talk over an in-memory pipe, so the tests need no ports.
+    const PIPE_CAPACITY: usize = 64 * 1024;
+
+    /// Builds the future returned by `make` on the heap. Alice's and Google's futures
+    /// run side by side on the test thread, and kept on its stack they overflow it in
+    /// debug builds.
+    fn boxed<F: Future>(make: impl FnOnce() -> F) -> Pin<Box<F>> {
+        Box::pin(make())
+    }
 
-    #[test]
-    fn test_register_and_login() {
-        let mut ca = CA::new();
+    #[tokio::test]
+    async fn test_register_and_login() {
+        let ca = CA::new();
         let mut ca_clone = ca.clone();
-        let mut g: ProjectivePoint = ProjectivePoint::random(&mut OsRng);
-
-        let handle = std::thread::spawn(move || {
-            sim_google(&mut ca_clone, &mut g);
-        });
-
-        std::thread::sleep(std::time::Duration::from_millis(500));
-
-        let mut stream = TcpStream::connect("127.0.0.1:9001").unwrap();
-        let mut aead_nonce: [u8; 12] = [0u8; 12];
-        
-        let ad = b"Alice,Google,";
-        let username = "alice";
-        let pw = "12345";
-
-        assert!(!alice::register(&mut ca, &mut stream, &mut aead_nonce, &ad, &username, &pw));
-        assert!(!alice::login(&mut ca, &mut stream, &mut aead_nonce, &ad, g, &username, &pw));
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
 
-        drop(stream);
+        let google = boxed(|| sim_google(&mut ca_clone, &mut google_stream));
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let username = "alice";
+            let pw = "12345";
 
-        handle.join().unwrap();
+            assert!(alice::register(&ca, &mut stream, ad, username, pw).await.is_ok());
+            assert!(alice::login(&ca, &mut stream, ad, username, pw, None).await.is_ok());
+        });
+        tokio::join!(google, alice);
 
         println!("Test register_and_login finished.\n\n");
     }
 
-    fn sim_google(ca: &mut CA, g: &mut ProjectivePoint) {
-        let listener = TcpListener::bind("127.0.0.1:9001").unwrap();
-        let (mut stream, _) = listener.accept().unwrap();
-
-        let mut aead_nonce: [u8; 12] = [0u8; 12];
+    async fn sim_google(ca: &mut CA, stream: &mut DuplexStream) {
         let ad = b"Alice,Google,";
-        let mut database: HashMap<Vec<u8>, DatabaseContent> = HashMap::new();
+        let state = Mutex::new(ServerState::default());
+        let mut oprf_seed = [0u8; 32];
+        OsRng.fill_bytes(&mut oprf_seed);
 
-        let (_k1_c, _k1_s, _k2_c, _k2_s, k3_c, _k3_s) = google::pq_tls(&mut stream, ca, ad);
+        let mut handshake = google::pq_tls(stream, ca, &ServerIdentity::default(), ad).await.unwrap();
 
-        let msg = User::recv_bytes(&mut stream);
-        let (nonce, aead_payload) = match msg {
-            Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-            _ => panic!("Google: Unexpected message"),
-        };
-        let decrypted_msg: Vec<u8> = match crypto::aead::decrypt(&k3_c, &nonce, &aead_payload, &ad.as_ref()) {
+        let decrypted_msg: Vec<u8> = match User::recv_sealed(stream, &mut handshake).await.unwrap() {
             Ok(c) => c,
             Err(e) => {
                 eprintln!("Google: Decrypt error: {e}");
@@ -66,26 +75,22 @@ mod tests {
         };
         let mut parts = decrypted_msg.splitn(3, |&b| b == b';');
         let _action = parts.next().unwrap_or(&[]);
-        let mut username = parts.next().unwrap_or(&[]);
-        let mut content = parts.next().unwrap_or(&[]);
+        let username = parts.next().unwrap_or(&[]);
+        let content = parts.next().unwrap_or(&[]);
 
         assert!(!google::register(
-            &mut aead_nonce,
-            &ad,
-            &mut database,
-            *g,
-            &mut username,
-            &mut content
-        ));
-
-        let (_k1_c, _k1_s, _k2_c, _k2_s, k3_c, k3_s) = google::pq_tls(&mut stream, ca, ad);
-
-        let msg = User::recv_bytes(&mut stream);
-        let (nonce, aead_payload) = match msg {
-            Message::AeadCiphertext { nonce, aead_payload } => (nonce, aead_payload),
-            _ => panic!("Google: Unexpected message"),
-        };
-        let decrypted_msg: Vec<u8> = match crypto::aead::decrypt(&k3_c, &nonce, &aead_payload, &ad.as_ref()) {
+            &oprf_seed,
+            &KeyStretching::default(),
+            &state,
+            None,
+            username,
+            content
+        ).await);
+        assert!(google::send_status(&mut handshake, stream, STATUS_OK).await.is_ok());
+
+        let mut handshake = google::pq_tls(stream, ca, &ServerIdentity::default(), ad).await.unwrap();
+
+        let decrypted_msg: Vec<u8> = match User::recv_sealed(stream, &mut handshake).await.unwrap() {
             Ok(c) => c,
             Err(e) => {
                 eprintln!("Google: Decrypt error: {e}");
@@ -94,152 +99,687 @@ mod tests {
         };
         let mut parts = decrypted_msg.splitn(3, |&b| b == b';');
         let _action = parts.next().unwrap_or(&[]);
-        let mut username = parts.next().unwrap_or(&[]);
-        let mut content = parts.next().unwrap_or(&[]);
-
-        assert!(!google::login(
-            k3_c,
-            k3_s,
-            &mut stream,
-            &mut aead_nonce,
-            &ad,
-            &mut database,
-            *g,
-            &mut username,
-            &mut content
-        ));
-
-        drop(stream);
-        drop(listener);
+        let username = parts.next().unwrap_or(&[]);
+        let content = parts.next().unwrap_or(&[]);
+
+        assert!(google::login(
+            &oprf_seed,
+            &mut handshake,
+            &mut google::Connection::new(stream, &state, ad),
+            username,
+            content
+        ).await.is_ok());
     }
     
-    #[test]
-    fn test_double_ratchet() {
-        let ad = b"Alice,Google,";
-        let mut k3_c = [0u8; 32];
-        OsRng.fill_bytes(&mut k3_c);
-        let mut k3_s = [0u8; 32];
-        OsRng.fill_bytes(&mut k3_s);
-        let mut g: ProjectivePoint = ProjectivePoint::random(&mut OsRng);
-        
-        let len = 16;
-        let mut random_bytes = vec![0u8; len];
-        OsRng.fill_bytes(&mut random_bytes);
-        
-        let (mut sk, _) = crypto::key_schedule::extract(None, random_bytes.as_bytes());
-        let mut x_i = Scalar::random(&mut OsRng);
-        let mut y_i = Scalar::random(&mut OsRng);
-        let message_1_from_user = "Hello, world!";
-        let message_2_from_user = "How are you?";
-        
-        let handle = std::thread::spawn(move || {
-            sim_google_ratchet(&mut g, &mut sk, &mut x_i, &mut y_i, &k3_c.clone(), &k3_s.clone(), message_1_from_user, message_2_from_user);
+    #[tokio::test]
+    async fn test_change_password_and_delete_account() {
+        let ca = CA::new();
+        let mut ca_clone = ca.clone();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+
+        let google = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let state = Mutex::new(ServerState::default());
+            let mut oprf_seed = [0u8; 32];
+            OsRng.fill_bytes(&mut oprf_seed);
+
+            // Register, duplicate Register, ChangePassword, Login, DeleteAccount
+            for _ in 0..5 {
+                assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            }
+            assert!(state.lock().unwrap().database.is_empty());
         });
 
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
 
-        let mut stream = TcpStream::connect("127.0.0.1:9002").unwrap();
-        let mut rk_i = sk;
-        let mut large_y_i = g * y_i;
-        let aead_nonce = &mut [0u8; 12];
-        OsRng.fill_bytes(aead_nonce);
+            assert!(alice::register(&ca, &mut stream, ad, "alice", "12345").await.is_ok());
+            assert!(alice::register(&ca, &mut stream, ad, "alice", "54321").await.is_err());
+            assert!(alice::change_password(&ca, &mut stream, ad, "alice", "12345", "67890", None).await.is_ok());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "67890", None).await.is_ok());
+            assert!(alice::delete_account(&ca, &mut stream, ad, "alice", "67890", None).await.is_ok());
+        });
+        tokio::join!(google, alice);
 
+        println!("Test change_password_and_delete_account finished.\n\n");
+    }
 
-        let (x_i_plus_1, large_y_plus_one, rk_i_plus_2, output) =
-            match alice::inner_double_ratchet(&mut&mut stream, aead_nonce, &&ad, g, &k3_c, &k3_s, rk_i, large_y_i, message_1_from_user) {
-            Ok(value) => value,
-            Err(value) => panic!("Alice: Error in inner_double_ratchet: {value}"),
-        };
+    #[tokio::test]
+    async fn test_login_lockout_and_unlock() {
+        let ca = CA::new();
+        let mut ca_clone = ca.clone();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
 
-        assert_eq!(output, format!("Echo => {}", message_1_from_user));
+        let google = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let state = Mutex::new(ServerState::default());
+            let mut oprf_seed = [0u8; 32];
+            OsRng.fill_bytes(&mut oprf_seed);
 
-        rk_i = rk_i_plus_2.into();
-        large_y_i = large_y_plus_one;
-        x_i = x_i_plus_1;
+            // Register
+            assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
 
-        let (_, _, _, output_2) =
-        match alice::inner_double_ratchet(&mut&mut stream, aead_nonce, &&ad, g, &k3_c, &k3_s, rk_i, large_y_i, message_2_from_user) {
-            Ok(value) => value,
-            Err(value) => panic!("Alice: Error in inner_double_ratchet: {value}"),
-        };
+            // Lock the account as if too many key confirmations had failed, then refuse a Login
+            {
+                let ServerState { database, limiter, .. } = &mut *state.lock().unwrap();
+                database.get_mut(b"alice".as_slice()).unwrap().attempts.locked_until = Some(SystemTime::now() + limiter.policy.lockout);
+            }
+            assert!(matches!(
+                google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await,
+                Err(RequestError::Failed)
+            ));
+
+            // Administrator unlock, then Login
+            {
+                let ServerState { database, limiter, .. } = &mut *state.lock().unwrap();
+                limiter.unlock_user(database, b"alice");
+            }
+            assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            assert_eq!(state.lock().unwrap().database[b"alice".as_slice()].attempts.failures, 0);
+        });
+
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
 
-        assert_eq!(output_2, format!("Echo => {}", message_2_from_user));
+            assert!(alice::register(&ca, &mut stream, ad, "alice", "12345").await.is_ok());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", None).await.is_err());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", None).await.is_ok());
+        });
+        tokio::join!(google, alice);
+
+        println!("Test login_lockout_and_unlock finished.\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_login_with_totp() {
+        let ca = CA::new();
+        let mut ca_clone = ca.clone();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+
+        let google = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let state = Mutex::new(ServerState {
+                database: HashMap::new(),
+                limiter: RateLimiter::new(RateLimitPolicy {
+                    max_user_failures: 2,
+                    base_backoff: Duration::ZERO,
+                    ..RateLimitPolicy::default()
+                }),
+                ..ServerState::default()
+            });
+            let mut oprf_seed = [0u8; 32];
+            OsRng.fill_bytes(&mut oprf_seed);
+
+            // RegisterTotp, two Logins with a wrong code, which lock the account even though
+            // the password is right, and a refused Login with the right code
+            for _ in 0..3 {
+                assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            }
+            assert!(matches!(
+                google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await,
+                Err(RequestError::Failed)
+            ));
+            {
+                let ServerState { database, limiter, .. } = &mut *state.lock().unwrap();
+                assert!(database[b"alice".as_slice()].totp_last_step.is_none());
+                assert!(limiter.is_user_locked(database, b"alice", SystemTime::now()));
+                limiter.unlock_user(database, b"alice");
+            }
 
-        drop(stream);
+            // Login with the right code after the unlock
+            assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            let state = state.lock().unwrap();
+            let record = &state.database[b"alice".as_slice()];
+            assert!(record.totp_last_step.is_some());
+            assert_eq!(record.attempts.failures, 0);
+        });
 
-        handle.join().unwrap();
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
 
-        println!("Test double_ratchet finished.\n\n");
+            let secret = alice::register_with_totp(&ca, &mut stream, ad, "alice", "12345").await.unwrap();
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", Some("abcdef")).await.is_err());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", Some("abcdef")).await.is_err());
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let code = totp::totp(&secret, now);
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", Some(&code)).await.is_err());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", Some(&code)).await.is_ok());
+        });
+        tokio::join!(google, alice);
+
+        println!("Test login_with_totp finished.\n\n");
     }
 
-    fn sim_google_ratchet(g: &mut ProjectivePoint, sk: &mut Output<Sha256>, x_i: &mut Scalar, y_i: &mut Scalar, k3_c: &[u8; 32], k3_s: &[u8; 32], message_1_from_user: &str, message_2_from_user: &str) {
-        let listener = TcpListener::bind("127.0.0.1:9002").unwrap();
-        let (mut stream, _) = listener.accept().unwrap();
-        let aead_nonce = &mut [0u8; 12];
-        OsRng.fill_bytes(aead_nonce);
-        let ad = b"Alice,Google,";
+    #[tokio::test]
+    async fn test_account_changes_need_the_second_factor() {
+        let ca = CA::new();
+        let mut ca_clone = ca.clone();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+
+        let google = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let state = Mutex::new(ServerState {
+                limiter: RateLimiter::new(RateLimitPolicy { base_backoff: Duration::ZERO, ..RateLimitPolicy::default() }),
+                ..ServerState::default()
+            });
+            let mut oprf_seed = [0u8; 32];
+            OsRng.fill_bytes(&mut oprf_seed);
+
+            // RegisterTotp, then ChangePassword and DeleteAccount with a wrong and without a code
+            assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            let registered = state.lock().unwrap().database[b"alice".as_slice()].lpk_c;
+            for _ in 0..2 {
+                assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+                assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_err());
+            }
+            {
+                let state = state.lock().unwrap();
+                let record = &state.database[b"alice".as_slice()];
+                assert_eq!(record.lpk_c, registered);
+                assert_eq!(record.attempts.failures, 4);
+            }
 
-        let mut rk_i = sk;
-        let mut _large_x_i = g.clone() * x_i.clone();
-        let mut y_i = y_i;
+            // ChangePassword and DeleteAccount with the right codes
+            for _ in 0..2 {
+                assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            }
+            assert!(state.lock().unwrap().database.is_empty());
+        });
 
-        let (large_x_plus_one, y_i_plus_1, mut rk_i_plus_2, output) = match google::inner_double_ratchet(&k3_c, &k3_s, &mut &mut stream, aead_nonce, &&ad, *g, *rk_i, *y_i) {
-            Ok(value) => value,
-            Err(value) => panic!("Google: Error in inner_double_ratchet: {value}"),
-        };
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
 
-        assert_eq!(output, message_1_from_user);
+            // A client that sends the account request right after the login, skipping the code
+            async fn without_code(ca: &CA, stream: &mut DuplexStream, ad: &[u8; 13], action: &[u8], request: &[u8]) {
+                let mut handshake = alice::authenticate(ca, stream, ad, b"alice", b"12345", action).await.unwrap();
+                assert_eq!(User::recv_sealed(stream, &mut handshake).await.unwrap().unwrap(), STATUS_TOTP_REQUIRED);
+                assert!(alice::send_account_request(&mut handshake, stream, ad, request).await.is_ok());
+                assert!(User::recv_sealed(stream, &mut handshake).await.is_err());
+            }
 
-        rk_i = (&mut rk_i_plus_2).into();
-        _large_x_i = large_x_plus_one;
-        *y_i = y_i_plus_1;
+            let secret = alice::register_with_totp(&ca, &mut stream, ad, "alice", "12345").await.unwrap();
+            assert!(alice::change_password(&ca, &mut stream, ad, "alice", "12345", "67890", Some("abcdef")).await.is_err());
+            without_code(&ca, &mut stream, ad, b"ChangePassword", b"67890").await;
+            assert!(alice::delete_account(&ca, &mut stream, ad, "alice", "12345", Some("abcdef")).await.is_err());
+            without_code(&ca, &mut stream, ad, b"DeleteAccount", b"DeleteAccount;alice").await;
+
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let code = totp::totp(&secret, now);
+            assert!(alice::change_password(&ca, &mut stream, ad, "alice", "12345", "67890", Some(&code)).await.is_ok());
+            let code = totp::totp(&secret, now + totp::TIME_STEP);
+            assert!(alice::delete_account(&ca, &mut stream, ad, "alice", "67890", Some(&code)).await.is_ok());
+        });
+        tokio::join!(google, alice);
 
-        let (_, _, _, output_2) = match google::inner_double_ratchet(&k3_c, &k3_s, &mut &mut stream, aead_nonce, &&ad, *g, *rk_i, *y_i) {
-            Ok(value) => value,
-            Err(value) => panic!("Google: Error in inner_double_ratchet: {value}"),
-        };
+        println!("Test account_changes_need_the_second_factor finished.\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_wrong_password_is_alerted() {
+        let ca = CA::new();
+        let mut ca_clone = ca.clone();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+
+        let google = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let state = Mutex::new(ServerState::default());
+            let mut oprf_seed = [0u8; 32];
+            OsRng.fill_bytes(&mut oprf_seed);
+
+            // Register, then a Login that Alice aborts with an encrypted decrypt_error
+            assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            let result = google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await;
+            assert!(matches!(result, Err(RequestError::Transport(TransportError::Alert(AlertDescription::DecryptError)))));
+        });
 
-        assert_eq!(output_2, message_2_from_user);
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
 
-        drop(stream);
-        drop(listener);
+            assert!(alice::register(&ca, &mut stream, ad, "alice", "12345").await.is_ok());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "54321", None).await.is_err());
+        });
+        tokio::join!(google, alice);
+
+        println!("Test wrong_password_is_alerted finished.\n\n");
     }
 
-    #[test]
-    fn test_pqtls() {
+    #[tokio::test]
+    async fn test_envelope_is_rewrapped_after_key_rotation() {
+        let ca = CA::new();
+        let mut ca_clone = ca.clone();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+
+        let google = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let state = Mutex::new(ServerState::default());
+            let mut oprf_seed = [0u8; 32];
+            OsRng.fill_bytes(&mut oprf_seed);
+            assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            let old_lpk_c = state.lock().unwrap().database[b"alice".as_slice()].lpk_c;
+
+            // The first login after the rotation uses the old key and re-wraps the envelope
+            state.lock().unwrap().ake_keys.rotate(Duration::from_secs(60), SystemTime::now());
+            assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            {
+                let state = state.lock().unwrap();
+                let record = &state.database[b"alice".as_slice()];
+                assert_eq!(record.key_id, state.ake_keys.current().id);
+                assert_ne!(record.lpk_c, old_lpk_c);
+            }
+            assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+
+            // Without an overlap the envelope cannot be recovered any more
+            state.lock().unwrap().ake_keys.rotate(Duration::ZERO, SystemTime::now());
+            let result = google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await;
+            assert!(matches!(result, Err(RequestError::Transport(TransportError::Alert(AlertDescription::DecryptError)))));
+        });
+
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
+
+            assert!(alice::register(&ca, &mut stream, ad, "alice", "12345").await.is_ok());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", None).await.is_ok());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", None).await.is_ok());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", None).await.is_err());
+        });
+        tokio::join!(google, alice);
+
+        println!("Test envelope_is_rewrapped_after_key_rotation finished.\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_expired_session_is_resumed() {
+        let ca = CA::new();
+        let mut ca_clone = ca.clone();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+        let lifetime = Duration::from_millis(500);
+
+        let google = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let state = Mutex::new(ServerState {
+                sessions: SessionPolicy { lifetime, ..SessionPolicy::default() },
+                ..ServerState::default()
+            });
+            let mut oprf_seed = [0u8; 32];
+            OsRng.fill_bytes(&mut oprf_seed);
+
+            // Register, Login that expires, Resume
+            for _ in 0..3 {
+                assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            }
+        });
+
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
+
+            assert!(alice::register(&ca, &mut stream, ad, "alice", "12345").await.is_ok());
+            let mut session = alice::open_session(&ca, &mut stream, ad, "alice", "12345", None).await.unwrap();
+            time::sleep(lifetime + Duration::from_millis(200)).await;
+
+            // Google ends the session, Alice resumes it and sends the message again
+            assert_eq!(session.send(&mut stream, "Hello").await.unwrap(), "Echo => Hello");
+            assert!(session.close(&mut stream).await.is_ok());
+        });
+        tokio::join!(google, alice);
+
+        println!("Test expired_session_is_resumed finished.\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_session_is_resumed_on_a_new_connection() {
+        let ca = CA::new();
+        let (mut stream_1, mut google_stream_1) = duplex(PIPE_CAPACITY);
+        let (mut stream_2, mut google_stream_2) = duplex(PIPE_CAPACITY);
+        let ad = b"Alice,Google,";
+        let state = Mutex::new(ServerState::default());
+        let mut oprf_seed = [0u8; 32];
+        OsRng.fill_bytes(&mut oprf_seed);
+
+        // Register and a Login whose connection is lost; Resume
+        let google_1 = boxed(|| async {
+            assert!(google::handle_request(&mut ca.clone(), &oprf_seed, &KeyStretching::Identity, &mut google_stream_1, ad, &state).await.is_ok());
+            let result = google::handle_request(&mut ca.clone(), &oprf_seed, &KeyStretching::Identity, &mut google_stream_1, ad, &state).await;
+            assert!(matches!(result, Err(RequestError::Transport(TransportError::Io(_)))));
+        });
+        let google_2 = boxed(|| async {
+            assert!(google::handle_request(&mut ca.clone(), &oprf_seed, &KeyStretching::Identity, &mut google_stream_2, ad, &state).await.is_ok());
+        });
+
+        let alice = boxed(|| async {
+            assert!(alice::register(&ca, &mut stream_1, ad, "alice", "12345").await.is_ok());
+            let mut session = alice::open_session(&ca, &mut stream_1, ad, "alice", "12345", None).await.unwrap();
+            drop(stream_1);
+
+            assert!(session.resume(&mut stream_2).await.is_ok());
+            assert_eq!(session.send(&mut stream_2, "Hello").await.unwrap(), "Echo => Hello");
+            assert!(session.close(&mut stream_2).await.is_ok());
+        });
+        tokio::join!(google_1, google_2, alice);
+
+        println!("Test session_is_resumed_on_a_new_connection finished.\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_tickets_end_with_the_record() {
+        let ca = CA::new();
+        let (mut stream_1, mut google_stream_1) = duplex(PIPE_CAPACITY);
+        let (mut stream_2, mut google_stream_2) = duplex(PIPE_CAPACITY);
+        let (mut stream_3, mut google_stream_3) = duplex(PIPE_CAPACITY);
         let ad = b"Alice,Google,";
-        let mut ca = CA::new();
+        let state = Mutex::new(ServerState::default());
+        let mut oprf_seed = [0u8; 32];
+        OsRng.fill_bytes(&mut oprf_seed);
+
+        // Register and Login; ChangePassword, refused Resume, DeleteAccount, Register and
+        // refused Resume; Login
+        let google_1 = boxed(|| async {
+            for _ in 0..2 {
+                assert!(google::handle_request(&mut ca.clone(), &oprf_seed, &KeyStretching::Identity, &mut google_stream_1, ad, &state).await.is_ok());
+            }
+        });
+        let google_2 = boxed(|| async {
+            for _ in 0..5 {
+                assert!(google::handle_request(&mut ca.clone(), &oprf_seed, &KeyStretching::Identity, &mut google_stream_2, ad, &state).await.is_ok());
+            }
+        });
+        let google_3 = boxed(|| async {
+            assert!(google::handle_request(&mut ca.clone(), &oprf_seed, &KeyStretching::Identity, &mut google_stream_3, ad, &state).await.is_ok());
+        });
+
+        let alice = boxed(|| async {
+            // A ticket from before a password change
+            assert!(alice::register(&ca, &mut stream_1, ad, "alice", "12345").await.is_ok());
+            let mut session = alice::open_session(&ca, &mut stream_1, ad, "alice", "12345", None).await.unwrap();
+            assert!(alice::change_password(&ca, &mut stream_2, ad, "alice", "12345", "67890", None).await.is_ok());
+            assert!(matches!(session.resume(&mut stream_2).await, Err(RequestError::Failed)));
+            assert!(session.close(&mut stream_1).await.is_ok());
+
+            // A ticket of a deleted account, after someone else registered the name
+            let mut session = alice::open_session(&ca, &mut stream_3, ad, "alice", "67890", None).await.unwrap();
+            assert!(alice::delete_account(&ca, &mut stream_2, ad, "alice", "67890", None).await.is_ok());
+            assert!(alice::register(&ca, &mut stream_2, ad, "alice", "abcdef").await.is_ok());
+            assert!(matches!(session.resume(&mut stream_2).await, Err(RequestError::Failed)));
+            assert!(session.close(&mut stream_3).await.is_ok());
+        });
+        tokio::join!(google_1, google_2, google_3, alice);
+
+        println!("Test tickets_end_with_the_record finished.\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_is_dropped() {
+        let ca = CA::new();
         let mut ca_clone = ca.clone();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+        let idle_timeout = Duration::from_millis(300);
+
+        let google = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let state = Mutex::new(ServerState {
+                sessions: SessionPolicy { idle_timeout, ..SessionPolicy::default() },
+                ..ServerState::default()
+            });
+            let mut oprf_seed = [0u8; 32];
+            OsRng.fill_bytes(&mut oprf_seed);
+
+            // Register, then a Login whose session Alice leaves idle after a few heartbeats
+            assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            let result = google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await;
+            assert!(matches!(result, Err(RequestError::Transport(TransportError::Timeout))));
+        });
 
-        let handle = std::thread::spawn(move || {
-            let listener = TcpListener::bind("127.0.0.1:9003").unwrap();
-            let (mut stream, _) = listener.accept().unwrap();
-            let (k1_c, k1_s, k2_c, k2_s, k3_c, k3_s) = google::pq_tls(&mut stream, &mut ca_clone, ad);
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
 
-            drop(stream);
-            drop(listener);
+            assert!(alice::register(&ca, &mut stream, ad, "alice", "12345").await.is_ok());
+            let mut session = alice::open_session(&ca, &mut stream, ad, "alice", "12345", None).await.unwrap();
 
-            (k1_c, k1_s, k2_c, k2_s, k3_c, k3_s)
+            // Heartbeats keep the session alive past the idle timeout
+            for _ in 0..3 {
+                time::sleep(idle_timeout / 2).await;
+                session.heartbeat(&mut stream).await.unwrap();
+            }
+            assert_eq!(session.send(&mut stream, "Hello").await.unwrap(), "Echo => Hello");
+            time::sleep(idle_timeout * 2).await;
         });
+        tokio::join!(google, alice);
 
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        println!("Test idle_connection_is_dropped finished.\n\n");
+    }
 
-        let mut stream = TcpStream::connect("127.0.0.1:9003").unwrap();
+    #[tokio::test]
+    async fn test_security_events_are_audited() {
+        let ca = CA::new();
+        let mut ca_clone = ca.clone();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+        let path = std::env::temp_dir().join(format!("srap_audit_{}.log", OsRng.next_u64()));
+
+        let google = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let state = Mutex::new(ServerState {
+                limiter: RateLimiter::new(RateLimitPolicy { base_backoff: Duration::ZERO, max_user_failures: 2, ..RateLimitPolicy::default() }),
+                audit: AuditLog::open(&path, server_key_pair()).unwrap(),
+                ..ServerState::default()
+            });
+            let mut oprf_seed = [0u8; 32];
+            OsRng.fill_bytes(&mut oprf_seed);
+
+            // Register, Login, two wrong passwords that lock the account, refused Login
+            for wrong_password in [false, false, true, true, false] {
+                let result = google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await;
+                assert_eq!(matches!(result, Err(RequestError::Transport(TransportError::Alert(_)))), wrong_password);
+            }
+        });
 
-        let (alice_k1_c, alice_k1_s, alice_k2_c, alice_k2_s, alice_k3_c, alice_k3_s) = alice::pq_tls(&mut stream, &mut ca, ad);
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
 
-        let result = handle.join().unwrap();
-        let (google_k1_c, google_k1_s, google_k2_c, google_k2_s, google_k3_c, google_k3_s) = result;
+            assert!(alice::register(&ca, &mut stream, ad, "alice", "12345").await.is_ok());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", None).await.is_ok());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "54321", None).await.is_err());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "54321", None).await.is_err());
+            assert!(alice::login(&ca, &mut stream, ad, "alice", "12345", None).await.is_err());
+        });
+        tokio::join!(google, alice);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let events: Vec<&str> = log
+            .lines()
+            .map(|line| line.split(' ').nth(1).unwrap())
+            .collect();
+        assert_eq!(events, [
+            "event=registered",
+            "event=login_succeeded",
+            "event=login_failed",
+            "event=lockout",
+            "event=login_failed",
+            "event=login_refused",
+        ]);
+        assert!(log.lines().all(|line| line.starts_with("time=") && line.contains(" user=\"alice\" peer=")));
+        assert!(!log.contains("12345") && !log.contains("54321"));
+        assert!(audit::verify(&log, server_key_pair().verifying_key()).is_ok());
+
+        println!("Test security_events_are_audited finished.\n\n");
+    }
 
-        assert_eq!(alice_k1_c, google_k1_c);
-        assert_eq!(alice_k1_s, google_k1_s);
-        assert_eq!(alice_k2_c, google_k2_c);
-        assert_eq!(alice_k2_s, google_k2_s);
-        assert_eq!(alice_k3_c, google_k3_c);
-        assert_eq!(alice_k3_s, google_k3_s);
+    /// Sends `GET path` to the metrics endpoint at `addr` and returns the whole response.
+    async fn scrape(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_metrics_are_scraped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(http::serve(listener, &METRICS));
+
+        let ca = CA::new();
+        let mut ca_clone = ca.clone();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
 
-        drop(stream);
+        let google = boxed(|| async {
+            let ad = b"Alice,Google,";
+            let state = Mutex::new(ServerState::default());
+            let mut oprf_seed = [0u8; 32];
+            OsRng.fill_bytes(&mut oprf_seed);
+
+            // Register, then Login with one ratchet message
+            for _ in 0..2 {
+                assert!(google::handle_request(&mut ca_clone, &oprf_seed, &KeyStretching::Identity, &mut google_stream, ad, &state).await.is_ok());
+            }
+        });
+
+        let alice = boxed(|| async {
+            let ad = b"Alice,Google,";
+
+            assert!(alice::register(&ca, &mut stream, ad, "alice", "12345").await.is_ok());
+            let mut session = alice::open_session(&ca, &mut stream, ad, "alice", "12345", None).await.unwrap();
+            assert_eq!(session.send(&mut stream, "Hello").await.unwrap(), "Echo => Hello");
+            assert!(session.close(&mut stream).await.is_ok());
+        });
+        tokio::join!(google, alice);
+
+        // Other tests share the counters, so only check that these runs were counted
+        let response = scrape(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+        for stage in ["pq_tls", "oprf", "ake", "ratchet", "login"] {
+            let counter = format!("srap_{stage}_total{{result=\"ok\"}} ");
+            let line = response.lines().find(|line| line.starts_with(&counter)).unwrap();
+            assert!(line[counter.len()..].parse::<u64>().unwrap() >= 1);
+            assert!(response.contains(&format!("# TYPE srap_{stage}_seconds histogram\n")));
+        }
+        assert!(scrape(addr, "/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        println!("Test metrics_are_scraped finished.\n\n");
+    }
+
+    #[tokio::test]
+    async fn test_fake_record_for_unknown_user() {
+        let mut oprf_seed = [0u8; 32];
+        OsRng.fill_bytes(&mut oprf_seed);
+        let state = Mutex::new(ServerState::default());
+
+        assert!(!google::register(&oprf_seed, &KeyStretching::Identity, &state, None, b"alice", b"12345").await);
+        assert!(google::register(&oprf_seed, &KeyStretching::Identity, &state, None, b"alice", b"54321").await);
+        let mut state = state.lock().unwrap();
+        let real = state.database.get(b"alice".as_slice()).unwrap().clone();
+
+        let fake_1 = google::fake_record(&oprf_seed, &mut state, b"mallory");
+        assert!(state.fake_records.contains_key(b"mallory".as_slice()));
+        let cached = google::fake_record(&oprf_seed, &mut state, b"mallory");
+        state.fake_records.clear();
+        let fake_2 = google::fake_record(&oprf_seed, &mut state, b"mallory");
+
+        // Same shape as a real record, with the key id and key stretching real records
+        // hold, and stable across cached lookups and rebuilds
+        assert_eq!((fake_1.key_id, fake_1.ksf), (real.key_id, real.ksf));
+        assert_ne!(fake_1.ksf, KeyStretching::default());
+        assert!(fake_1.masking_key == fake_2.masking_key);
+        assert_eq!(fake_1.lpk_c, fake_2.lpk_c);
+        assert!(cached.masking_key == fake_1.masking_key);
+    }
+
+    #[tokio::test]
+    async fn test_double_ratchet() {
+        let ad = b"Alice,Google,";
+        let mut k3_c = SecretKey::default();
+        OsRng.fill_bytes(k3_c.as_mut_slice());
+        let mut k3_s = SecretKey::default();
+        OsRng.fill_bytes(k3_s.as_mut_slice());
+        
+        let len = 16;
+        let mut random_bytes = vec![0u8; len];
+        OsRng.fill_bytes(&mut random_bytes);
+        
+        let (sk, _) = crypto::key_schedule::extract(None, random_bytes.as_bytes());
+        let y_i = Secret::new(Group::random_scalar());
+        let large_y_i = Group::public_key(&y_i);
+        let message_1_from_user = "Hello, world!";
+        let message_2_from_user = "How are you?";
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+
+        let google = boxed(|| sim_google_ratchet(sk.clone(), y_i, &k3_c, &k3_s, message_1_from_user, message_2_from_user, &mut google_stream));
+
+        let alice = boxed(|| async {
+            let mut ratchet = ClientRatchet::new(sk.clone(), large_y_i);
+
+            for message in [message_1_from_user, message_2_from_user] {
+                let msg = ratchet.seal(&k3_c, ad, message).unwrap();
+                stream.send(&msg).await.unwrap();
+                let output = ratchet.open(&k3_s, ad, stream.recv().await.unwrap()).unwrap();
+
+                assert_eq!(output, format!("Echo => {}", message));
+            }
+        });
+        tokio::join!(google, alice);
+
+        println!("Test double_ratchet finished.\n\n");
+    }
+
+    async fn sim_google_ratchet(sk: SecretKey, y_i: Secret<DhScalar>, k3_c: &SecretKey, k3_s: &SecretKey, message_1_from_user: &str, message_2_from_user: &str, stream: &mut impl Transport) {
+        let ad = b"Alice,Google,";
+        let mut ratchet = ServerRatchet::new(sk, y_i);
+
+        for expected in [message_1_from_user, message_2_from_user] {
+            let output = ratchet.open(k3_c, ad, stream.recv().await.unwrap()).unwrap();
+            assert_eq!(output, expected);
+
+            let msg = ratchet.seal(k3_s, ad, &format!("Echo => {}", output)).unwrap();
+            stream.send(&msg).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pqtls() {
+        let ad = b"Alice,Google,";
+        let ca = CA::new();
+        let ca_clone = ca.clone();
+        let identity = ServerIdentity::default();
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+
+        let (alice_handshake, google_handshake) = tokio::join!(
+            boxed(|| alice::pq_tls(&mut stream, &ca, ad)),
+            boxed(|| google::pq_tls(&mut google_stream, &ca_clone, &identity, ad)),
+        );
+        let (alice_handshake, google_handshake) = (alice_handshake.unwrap(), google_handshake.unwrap());
+        let alice_keys = alice_handshake.keys().unwrap();
+        let google_keys = google_handshake.keys().unwrap();
+
+        assert!(alice_keys.k1_c == google_keys.k1_c);
+        assert!(alice_keys.k1_s == google_keys.k1_s);
+        assert!(alice_keys.k2_c == google_keys.k2_c);
+        assert!(alice_keys.k2_s == google_keys.k2_s);
+        assert!(alice_keys.k3_c == google_keys.k3_c);
+        assert!(alice_keys.k3_s == google_keys.k3_s);
 
         println!("Test pqtls finished.\n\n");
     }
+    #[tokio::test]
+    async fn test_revoked_certificate_is_refused() {
+        let ad = b"Alice,Google,";
+        let ca = CA::new();
+        let identity = ServerIdentity::default();
+        let verifying_key = identity.key_pair().verifying_key().encode();
+        let revoked = ca.clone().with_revoked(vec![CA::fingerprint(verifying_key.as_slice())]);
+        let (mut stream, mut google_stream) = duplex(PIPE_CAPACITY);
+
+        // Alice refuses the revoked key with bad_certificate
+        let (alice_handshake, google_handshake) = tokio::join!(
+            boxed(|| alice::pq_tls(&mut stream, &revoked, ad)),
+            boxed(|| google::pq_tls(&mut google_stream, &ca, &identity, ad)),
+        );
+        assert!(alice_handshake.is_err());
+        assert!(matches!(
+            google_handshake,
+            Err(RequestError::Transport(TransportError::Alert(AlertDescription::BadCertificate)))
+        ));
+
+        println!("Test revoked_certificate_is_refused finished.\n\n");
+    }
 }
\ No newline at end of file
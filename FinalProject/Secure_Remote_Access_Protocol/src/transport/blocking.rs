@@ -0,0 +1,11 @@
+//! Runs the async protocol code from synchronous callers.
+
+use tokio::runtime::{Builder, Runtime};
+
+/// Single-threaded runtime for a client or a blocking call.
+pub fn runtime() -> Runtime {
+    Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Error starting the tokio runtime")
+}
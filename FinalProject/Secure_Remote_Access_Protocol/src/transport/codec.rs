@@ -0,0 +1,189 @@
+//! Length-prefixed framing with limits, so a hostile peer can neither make the reader
+//! allocate an arbitrary amount of memory nor hold a connection with a half-sent frame.
+//!
+//! A frame is `len || magic || version || type || body`: the big-endian length of the
+//! rest, `MAGIC`, the big-endian protocol version the body is encoded for, the
+//! `Message::message_type` and the bincode encoding of the message. The header is
+//! checked before the body is decoded, so a peer speaking another version is told
+//! apart from a corrupted stream.
+//!
+//! The hellos and the frames before them carry `HELLO_VERSION`, which every build
+//! accepts. Every later frame carries the version the hellos negotiated.
+
+use super::TransportError;
+use crate::crypto::participant::{Message, HELLO_VERSION};
+use bincode::Options;
+use std::fmt;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+/// Length of the big-endian length prefix of a frame.
+const LEN_PREFIX: usize = 4;
+
+/// First bytes of every frame after the length prefix.
+pub const MAGIC: [u8; 4] = *b"SRAP";
+
+/// Magic, protocol version and message type.
+const HEADER_LEN: usize = MAGIC.len() + 2 + 1;
+
+/// Limits applied to every frame sent or received over a connection.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameCodec {
+    /// Largest frame accepted or sent, without the length prefix.
+    pub max_frame_size: usize,
+    /// Longest time the rest of a frame may take once its first byte arrived. Waiting
+    /// for the next frame is not limited, an idle connection stays open.
+    pub read_timeout: Option<Duration>,
+    /// Longest time writing a whole frame may take.
+    pub write_timeout: Option<Duration>,
+    /// Version negotiated on the connection, `None` until the hellos were exchanged.
+    pub version: Option<u16>,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec {
+            // The largest message, Google's sealed finished message with the ML-DSA-65
+            // certificate and signature (3309 bytes each) and a MAC, is about 6.6 KiB
+            max_frame_size: 64 * 1024,
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            version: None,
+        }
+    }
+}
+
+/// Why a received frame was rejected.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The length prefix announces more than `max_frame_size` bytes.
+    FrameTooLarge { len: usize, max: usize },
+    /// The frame is too short for its header or does not start with `MAGIC`.
+    BadMagic,
+    /// The peer encoded the frame for another protocol version than the connection's.
+    UnsupportedVersion(u16),
+    /// The header names a message type this version does not know.
+    UnknownMessageType(u8),
+    /// The body holds another message than the header announced.
+    TypeMismatch { header: u8, body: u8 },
+    /// The body is not exactly one bincode-encoded `Message`.
+    Malformed(bincode::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::FrameTooLarge { len, max } => write!(f, "frame of {len} bytes exceeds the limit of {max} bytes"),
+            DecodeError::BadMagic => write!(f, "not a protocol frame"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {version}"),
+            DecodeError::UnknownMessageType(message_type) => write!(f, "unknown message type {message_type}"),
+            DecodeError::TypeMismatch { header, body } => write!(f, "header announces message type {header}, body holds {body}"),
+            DecodeError::Malformed(e) => write!(f, "malformed message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl FrameCodec {
+    /// bincode settings of the wire format (fixed-width integers, as `bincode::serialize`),
+    /// bounded by `max_frame_size`.
+    fn options(&self) -> impl Options {
+        bincode::options()
+            .with_fixint_encoding()
+            .with_limit(self.max_frame_size as u64)
+    }
+
+    /// Version in the header of a frame holding a message of `message_type`.
+    fn frame_version(&self, message_type: u8) -> u16 {
+        match self.version {
+            Some(version) if !Message::is_hello_type(message_type) => version,
+            _ => HELLO_VERSION,
+        }
+    }
+
+    /// Encodes `msg` as one frame: length prefix, header, then the bincode encoding.
+    pub fn encode(&self, msg: &Message) -> Result<Vec<u8>, TransportError> {
+        let body = self.options().serialize(msg).map_err(TransportError::Encode)?;
+        let len = HEADER_LEN + body.len();
+        if len > self.max_frame_size {
+            return Err(TransportError::Encode(Box::new(bincode::ErrorKind::SizeLimit)));
+        }
+
+        let mut frame = Vec::with_capacity(LEN_PREFIX + len);
+        frame.extend_from_slice(&(len as u32).to_be_bytes());
+        frame.extend_from_slice(&MAGIC);
+        frame.extend_from_slice(&self.frame_version(msg.message_type()).to_be_bytes());
+        frame.push(msg.message_type());
+        frame.extend_from_slice(&body);
+        Ok(frame)
+    }
+
+    /// Decodes a frame without its length prefix. The body has to hold exactly the
+    /// message the header announces.
+    pub fn decode(&self, frame: &[u8]) -> Result<Message, DecodeError> {
+        if frame.len() > self.max_frame_size {
+            return Err(DecodeError::FrameTooLarge { len: frame.len(), max: self.max_frame_size });
+        }
+        if frame.len() < HEADER_LEN || frame[..MAGIC.len()] != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let (header, body) = frame.split_at(HEADER_LEN);
+        let version = u16::from_be_bytes([header[4], header[5]]);
+        let message_type = header[6];
+        // A peer that failed the negotiation may send its alert before it learned the version
+        let early_alert = Message::is_alert_type(message_type) && version == HELLO_VERSION;
+        if version != self.frame_version(message_type) && !early_alert {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        if !Message::is_known_type(message_type) {
+            return Err(DecodeError::UnknownMessageType(message_type));
+        }
+
+        let msg: Message = self.options().deserialize(body).map_err(DecodeError::Malformed)?;
+        if msg.message_type() != message_type {
+            return Err(DecodeError::TypeMismatch { header: message_type, body: msg.message_type() });
+        }
+        Ok(msg)
+    }
+
+    pub async fn send<S: AsyncWrite + Unpin + ?Sized>(&self, stream: &mut S, msg: &Message) -> Result<(), TransportError> {
+        let frame = self.encode(msg)?;
+        with_timeout(self.write_timeout, stream.write_all(&frame)).await
+    }
+
+    pub async fn recv<S: AsyncRead + Unpin + ?Sized>(&self, stream: &mut S) -> Result<Message, TransportError> {
+        let mut len_buf = [0u8; LEN_PREFIX];
+        stream.read_exact(&mut len_buf[..1]).await?;
+
+        // The frame has started, the rest has to follow in time
+        let frame = with_timeout(self.read_timeout, async {
+            stream.read_exact(&mut len_buf[1..]).await?;
+            let len = u32::from_be_bytes(len_buf) as usize;
+            if len > self.max_frame_size {
+                return Err(DecodeError::FrameTooLarge { len, max: self.max_frame_size }.into());
+            }
+
+            let mut frame = vec![0u8; len];
+            stream.read_exact(&mut frame).await?;
+            Ok::<_, TransportError>(frame)
+        })
+        .await?;
+
+        Ok(self.decode(&frame)?)
+    }
+}
+
+async fn with_timeout<T, E: Into<TransportError>>(
+    limit: Option<Duration>,
+    operation: impl Future<Output = Result<T, E>>,
+) -> Result<T, TransportError> {
+    match limit {
+        Some(limit) => match time::timeout(limit, operation).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(TransportError::Timeout),
+        },
+        None => operation.await.map_err(Into::into),
+    }
+}
@@ -0,0 +1,300 @@
+//! Framing of `Message`s over async (tokio) byte streams.
+//!
+//! Every message is sent as a 4-byte big-endian length followed by a versioned header
+//! and its bincode encoding, within the limits of a `FrameCodec`. The protocol code in `client::alice` and `server::google` runs over any
+//! `Transport`: TCP in production, Unix domain sockets, or an in-memory duplex pipe in
+//! the tests. `blocking` provides the runtime for the synchronous entry points.
+
+pub mod blocking;
+pub mod codec;
+
+pub use codec::{DecodeError, FrameCodec};
+
+use crate::crypto::participant::Message;
+use crate::handshake::AlertDescription;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// The connection failed or was closed by the peer.
+    Io(io::Error),
+    /// The peer stalled in the middle of a frame, or a frame could not be written in time.
+    Timeout,
+    /// A message could not be encoded within the frame size limit.
+    Encode(bincode::Error),
+    /// A received frame was rejected.
+    Decode(DecodeError),
+    /// The peer ended the session with a fatal alert.
+    Alert(AlertDescription),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(e) => write!(f, "connection error: {e}"),
+            TransportError::Timeout => write!(f, "connection timed out"),
+            TransportError::Encode(e) => write!(f, "cannot encode message: {e}"),
+            TransportError::Decode(e) => write!(f, "{e}"),
+            TransportError::Alert(description) => write!(f, "peer ended the session: {description}"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+impl From<DecodeError> for TransportError {
+    fn from(e: DecodeError) -> Self {
+        TransportError::Decode(e)
+    }
+}
+
+/// A connection between Alice and Google that carries framed `Message`s.
+// Only implemented for the transports below, whose futures are all Send
+#[allow(async_fn_in_trait)]
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {
+    /// Address of the peer, used by Google to rate limit per client. Transports
+    /// without network addresses return `None`.
+    fn peer_ip(&self) -> Option<IpAddr>;
+
+    /// Limits for the frames on this connection.
+    fn codec(&self) -> FrameCodec {
+        FrameCodec::default()
+    }
+
+    async fn send(&mut self, msg: &Message) -> Result<(), TransportError> {
+        self.codec().send(self, msg).await
+    }
+
+    async fn recv(&mut self) -> Result<Message, TransportError> {
+        self.codec().recv(self).await
+    }
+
+    /// Sends `msg` as a frame of `version`, the version negotiated on the connection.
+    async fn send_versioned(&mut self, msg: &Message, version: Option<u16>) -> Result<(), TransportError> {
+        FrameCodec { version, ..self.codec() }.send(self, msg).await
+    }
+
+    /// Receives a frame of `version`, the version negotiated on the connection.
+    async fn recv_versioned(&mut self, version: Option<u16>) -> Result<Message, TransportError> {
+        FrameCodec { version, ..self.codec() }.recv(self).await
+    }
+}
+
+impl Transport for TcpStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl Transport for DuplexStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        (**self).peer_ip()
+    }
+
+    fn codec(&self) -> FrameCodec {
+        (**self).codec()
+    }
+}
+
+/// A transport with its own frame limits instead of the defaults.
+pub struct Framed<S> {
+    stream: S,
+    codec: FrameCodec,
+}
+
+impl<S> Framed<S> {
+    pub fn new(stream: S, codec: FrameCodec) -> Self {
+        Framed { stream, codec }
+    }
+}
+
+impl<S: Transport> Transport for Framed<S> {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.stream.peer_ip()
+    }
+
+    fn codec(&self) -> FrameCodec {
+        self.codec
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Framed<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Framed<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn messages_round_trip() {
+        let (mut alice, mut google) = tokio::io::duplex(1024);
+
+        let msg = Message::AeadCiphertext { nonce: [7u8; 12], aead_payload: b"payload".to_vec() };
+        alice.send(&msg).await.unwrap();
+        alice.send(&Message::Alert { description: 0 }).await.unwrap();
+
+        match google.recv().await.unwrap() {
+            Message::AeadCiphertext { nonce, aead_payload } => {
+                assert_eq!(nonce, [7u8; 12]);
+                assert_eq!(aead_payload, b"payload");
+            }
+            _ => panic!("unexpected message"),
+        }
+        assert!(matches!(google.recv().await.unwrap(), Message::Alert { description: 0 }));
+
+        drop(alice);
+        assert!(matches!(google.recv().await, Err(TransportError::Io(_))));
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_sockets_carry_messages() {
+        let (mut alice, mut google) = UnixStream::pair().unwrap();
+
+        alice.send(&Message::Alert { description: 0 }).await.unwrap();
+        assert!(matches!(google.recv().await.unwrap(), Message::Alert { description: 0 }));
+        assert_eq!(google.peer_ip(), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_and_malformed_frames_are_rejected() {
+        let codec = FrameCodec { max_frame_size: 64, ..FrameCodec::default() };
+        let (alice, google) = tokio::io::duplex(1024);
+        let (mut alice, mut google) = (Framed::new(alice, codec), Framed::new(google, codec));
+
+        // Announces 4 GiB, rejected before anything is allocated
+        alice.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        assert!(matches!(
+            google.recv().await,
+            Err(TransportError::Decode(DecodeError::FrameTooLarge { len, max: 64 })) if len == u32::MAX as usize
+        ));
+
+        // A variant index that does not exist
+        alice.write_all(&[0, 0, 0, 11, b'S', b'R', b'A', b'P', 0, 1, 4, 0xff, 0xff, 0xff, 0xff]).await.unwrap();
+        assert!(matches!(google.recv().await, Err(TransportError::Decode(DecodeError::Malformed(_)))));
+
+        // A payload length inside the frame beyond the limit
+        let mut frame = codec.encode(&Message::SimplePayload { payload: vec![1; 8] }).unwrap();
+        frame[15..23].copy_from_slice(&u64::MAX.to_le_bytes());
+        alice.write_all(&frame).await.unwrap();
+        assert!(matches!(google.recv().await, Err(TransportError::Decode(DecodeError::Malformed(_)))));
+
+        let big = Message::SimplePayload { payload: vec![0; 128] };
+        assert!(matches!(alice.send(&big).await, Err(TransportError::Encode(_))));
+    }
+
+    #[tokio::test]
+    async fn frame_headers_are_checked() {
+        let codec = FrameCodec::default();
+        let (alice, google) = tokio::io::duplex(1024);
+        let (mut alice, mut google) = (Framed::new(alice, codec), Framed::new(google, codec));
+        let frame = codec.encode(&Message::Alert { description: 0 }).unwrap();
+        assert_eq!(frame[4..11], [b'S', b'R', b'A', b'P', 0, 1, 5]);
+
+        let mut bad_magic = frame.clone();
+        bad_magic[4] = b'X';
+        alice.write_all(&bad_magic).await.unwrap();
+        assert!(matches!(google.recv().await, Err(TransportError::Decode(DecodeError::BadMagic))));
+
+        let mut future_version = frame.clone();
+        future_version[8..10].copy_from_slice(&2u16.to_be_bytes());
+        alice.write_all(&future_version).await.unwrap();
+        assert!(matches!(google.recv().await, Err(TransportError::Decode(DecodeError::UnsupportedVersion(2)))));
+
+        let mut unknown_type = frame.clone();
+        unknown_type[10] = 42;
+        alice.write_all(&unknown_type).await.unwrap();
+        assert!(matches!(google.recv().await, Err(TransportError::Decode(DecodeError::UnknownMessageType(42)))));
+
+        let mut mismatch = frame.clone();
+        mismatch[10] = 4;
+        alice.write_all(&mismatch).await.unwrap();
+        assert!(matches!(
+            google.recv().await,
+            Err(TransportError::Decode(DecodeError::TypeMismatch { header: 4, body: 5 }))
+        ));
+
+        // The stream is still usable afterwards
+        alice.send(&Message::Alert { description: 0 }).await.unwrap();
+        assert!(matches!(google.recv().await, Ok(Message::Alert { description: 0 })));
+    }
+
+    #[test]
+    fn frames_carry_the_negotiated_version() {
+        // A build that negotiated a version this one does not know
+        let newer = FrameCodec { version: Some(2), ..FrameCodec::default() };
+        let codec = FrameCodec::default();
+
+        // Its hellos and early alerts still carry HELLO_VERSION and are heard
+        let hello = Message::PqtlsServerHello { version: 2, nonce_s: vec![0; 8], ct: vec![], verifying_key: vec![] };
+        let frame = newer.encode(&hello).unwrap();
+        assert_eq!(frame[8..10], 1u16.to_be_bytes());
+        assert!(matches!(codec.decode(&frame[4..]), Ok(Message::PqtlsServerHello { version: 2, .. })));
+        let alert = Message::Alert { description: 0 };
+        assert!(newer.decode(&codec.encode(&alert).unwrap()[4..]).is_ok());
+
+        // Later frames carry the negotiated version and need it
+        let msg = Message::SimplePayload { payload: vec![1; 8] };
+        let frame = newer.encode(&msg).unwrap();
+        assert_eq!(frame[8..10], 2u16.to_be_bytes());
+        assert!(newer.decode(&frame[4..]).is_ok());
+        assert!(matches!(codec.decode(&frame[4..]), Err(DecodeError::UnsupportedVersion(2))));
+        assert!(matches!(newer.decode(&codec.encode(&msg).unwrap()[4..]), Err(DecodeError::UnsupportedVersion(1))));
+    }
+
+    #[tokio::test]
+    async fn stalled_frames_time_out() {
+        let codec = FrameCodec { read_timeout: Some(Duration::from_millis(50)), ..FrameCodec::default() };
+        let (mut alice, google) = tokio::io::duplex(1024);
+        let mut google = Framed::new(google, codec);
+
+        // Half a length prefix, then nothing
+        alice.write_all(&[0, 0]).await.unwrap();
+        assert!(matches!(google.recv().await, Err(TransportError::Timeout)));
+    }
+}